@@ -0,0 +1,4 @@
+-- Add migration script here
+ALTER TABLE subscriptions
+	ADD COLUMN delivery_frequency TEXT NOT NULL DEFAULT 'immediate'
+		CHECK (delivery_frequency IN ('immediate', 'weekly_digest'));
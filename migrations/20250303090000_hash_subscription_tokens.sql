@@ -0,0 +1,11 @@
+-- Add migration script here
+-- Tokens issued before this migration stay in plaintext in `subscription_token`
+-- until they expire; new tokens only store their keyed hash.
+ALTER TABLE subscription_tokens DROP CONSTRAINT subscription_tokens_pkey;
+ALTER TABLE subscription_tokens ALTER COLUMN subscription_token DROP NOT NULL;
+ALTER TABLE subscription_tokens ADD CONSTRAINT subscription_tokens_subscription_token_key
+	UNIQUE (subscription_token);
+ALTER TABLE subscription_tokens ADD COLUMN subscription_token_hash TEXT NULL
+	CONSTRAINT subscription_tokens_subscription_token_hash_key UNIQUE;
+ALTER TABLE subscription_tokens ADD CONSTRAINT subscription_tokens_token_or_hash
+	CHECK (subscription_token IS NOT NULL OR subscription_token_hash IS NOT NULL);
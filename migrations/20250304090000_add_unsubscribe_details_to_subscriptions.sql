@@ -0,0 +1,4 @@
+-- Add migration script here
+ALTER TABLE subscriptions
+	ADD COLUMN unsubscribed_at timestamptz NULL,
+	ADD COLUMN unsubscribe_reason TEXT NULL;
@@ -0,0 +1,14 @@
+-- Add migration script here
+ALTER TABLE subscription_tokens
+	ADD COLUMN purpose TEXT NOT NULL DEFAULT 'confirmation';
+ALTER TABLE subscriptions
+	ADD COLUMN language TEXT NOT NULL DEFAULT 'en',
+	ADD COLUMN topics TEXT[] NOT NULL DEFAULT '{}';
+CREATE TABLE preference_sessions(
+	session_hash TEXT NOT NULL,
+	PRIMARY KEY (session_hash),
+	subscriber_id uuid NOT NULL
+		REFERENCES subscriptions (id) ON DELETE CASCADE,
+	created_at timestamptz NOT NULL,
+	expires_at timestamptz NOT NULL
+);
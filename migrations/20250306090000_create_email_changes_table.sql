@@ -0,0 +1,12 @@
+-- Add migration script here
+CREATE TABLE email_changes(
+	id uuid NOT NULL,
+	PRIMARY KEY (id),
+	subscriber_id uuid NOT NULL
+		REFERENCES subscriptions (id) ON DELETE CASCADE,
+	old_email TEXT NOT NULL,
+	new_email TEXT NOT NULL,
+	requested_at timestamptz NOT NULL,
+	confirmed_at timestamptz NULL,
+	reverted_at timestamptz NULL
+);
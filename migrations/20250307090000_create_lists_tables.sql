@@ -0,0 +1,23 @@
+-- Add migration script here
+CREATE TABLE lists(
+	id uuid NOT NULL,
+	PRIMARY KEY (id),
+	slug TEXT NOT NULL UNIQUE,
+	name TEXT NOT NULL,
+	sender_email TEXT NOT NULL,
+	welcome_subject TEXT NOT NULL,
+	welcome_body TEXT NOT NULL
+);
+CREATE TABLE list_memberships(
+	subscriber_id uuid NOT NULL
+		REFERENCES subscriptions (id) ON DELETE CASCADE,
+	list_id uuid NOT NULL
+		REFERENCES lists (id) ON DELETE CASCADE,
+	PRIMARY KEY (subscriber_id, list_id),
+	status TEXT NOT NULL,
+	subscribed_at timestamptz NOT NULL,
+	confirmed_at timestamptz NULL
+);
+ALTER TABLE subscription_tokens
+	ADD COLUMN list_id uuid NULL
+		REFERENCES lists (id) ON DELETE CASCADE;
@@ -0,0 +1,7 @@
+-- Add migration script here
+CREATE TABLE rate_limit_buckets(
+	key TEXT NOT NULL,
+	PRIMARY KEY (key),
+	tokens DOUBLE PRECISION NOT NULL,
+	updated_at timestamptz NOT NULL
+);
@@ -0,0 +1,17 @@
+-- Add migration script here
+CREATE TABLE suppressions(
+	email_hash TEXT NOT NULL,
+	PRIMARY KEY (email_hash),
+	reason TEXT NOT NULL,
+	source TEXT NOT NULL,
+	created_at timestamptz NOT NULL
+);
+CREATE TABLE audit_log(
+	id uuid NOT NULL,
+	PRIMARY KEY (id),
+	occurred_at timestamptz NOT NULL,
+	actor TEXT NOT NULL,
+	action TEXT NOT NULL,
+	subscriber_id uuid NULL
+);
+CREATE INDEX audit_log_subscriber_id_idx ON audit_log (subscriber_id);
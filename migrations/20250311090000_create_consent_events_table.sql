@@ -0,0 +1,16 @@
+-- Add migration script here
+CREATE TABLE consent_events(
+	id uuid NOT NULL,
+	PRIMARY KEY (id),
+	subscriber_id uuid NOT NULL
+		REFERENCES subscriptions (id) ON DELETE CASCADE,
+	list_id uuid NULL
+		REFERENCES lists (id) ON DELETE SET NULL,
+	event TEXT NOT NULL,
+	ip TEXT NULL,
+	user_agent TEXT NULL,
+	source TEXT NULL,
+	consent_version TEXT NULL,
+	occurred_at timestamptz NOT NULL
+);
+CREATE INDEX consent_events_subscriber_id_idx ON consent_events (subscriber_id);
@@ -0,0 +1,10 @@
+-- Add migration script here
+ALTER TABLE subscriptions ADD COLUMN tags TEXT[] NOT NULL DEFAULT '{}';
+CREATE INDEX subscriptions_tags_idx ON subscriptions USING GIN (tags);
+CREATE TABLE segments(
+	name TEXT NOT NULL,
+	PRIMARY KEY (name),
+	filter JSONB NOT NULL,
+	created_at timestamptz NOT NULL,
+	updated_at timestamptz NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE consent_events ADD COLUMN attestation TEXT NULL;
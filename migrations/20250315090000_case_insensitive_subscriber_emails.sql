@@ -0,0 +1,48 @@
+-- Add migration script here
+ALTER TABLE subscriptions ADD COLUMN display_email TEXT NULL;
+UPDATE subscriptions SET display_email = email;
+ALTER TABLE subscriptions ALTER COLUMN display_email SET NOT NULL;
+
+-- Addresses differing only in case are the same person. Each group keeps
+-- its confirmed row if it has one, otherwise its oldest; the other rows hand
+-- over their list memberships and are anonymized as 'merged'.
+CREATE TEMPORARY TABLE merged_subscribers AS
+SELECT id, first_value(id) OVER (
+		PARTITION BY lower(email)
+		ORDER BY status = 'confirmed' DESC, subscribed_at, id
+	) AS kept_id
+FROM subscriptions;
+DELETE FROM merged_subscribers WHERE id = kept_id;
+
+-- A confirmed membership wins over a pending one, whichever row held it.
+INSERT INTO list_memberships (subscriber_id, list_id, status, subscribed_at, confirmed_at)
+SELECT DISTINCT ON (m.kept_id, l.list_id)
+	m.kept_id, l.list_id, l.status, l.subscribed_at, l.confirmed_at
+FROM list_memberships l JOIN merged_subscribers m ON m.id = l.subscriber_id
+ORDER BY m.kept_id, l.list_id, l.status = 'confirmed' DESC, l.subscribed_at
+ON CONFLICT (subscriber_id, list_id) DO UPDATE
+SET status = EXCLUDED.status, subscribed_at = EXCLUDED.subscribed_at,
+	confirmed_at = EXCLUDED.confirmed_at
+WHERE EXCLUDED.status = 'confirmed' AND list_memberships.status <> 'confirmed';
+DELETE FROM list_memberships WHERE subscriber_id IN (SELECT id FROM merged_subscribers);
+DELETE FROM subscription_tokens WHERE subscriber_id IN (SELECT id FROM merged_subscribers);
+DELETE FROM preference_sessions WHERE subscriber_id IN (SELECT id FROM merged_subscribers);
+DELETE FROM email_changes WHERE subscriber_id IN (SELECT id FROM merged_subscribers);
+UPDATE consent_events SET ip = NULL, user_agent = NULL
+WHERE subscriber_id IN (SELECT id FROM merged_subscribers);
+UPDATE subscriptions
+SET email = id || '@anonymized.invalid', display_email = id || '@anonymized.invalid',
+	name = 'anonymized', status = 'merged', unsubscribe_reason = NULL, topics = '{}',
+	attributes = '{}'
+WHERE id IN (SELECT id FROM merged_subscribers);
+DROP TABLE merged_subscribers;
+
+-- Stored addresses become canonical: domains are lowercased. Postgres cannot
+-- convert internationalized domains to punycode, so the application does
+-- that for stored addresses at startup.
+UPDATE subscriptions
+SET email = substring(email FROM '^(.*)@') || '@' || lower(substring(email FROM '@([^@]*)$'))
+WHERE email LIKE '%@%';
+
+ALTER TABLE subscriptions DROP CONSTRAINT subscriptions_email_key;
+CREATE UNIQUE INDEX subscriptions_email_lower_idx ON subscriptions (lower(email));
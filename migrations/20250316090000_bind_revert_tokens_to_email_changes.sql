@@ -0,0 +1,4 @@
+-- Add migration script here
+ALTER TABLE subscription_tokens
+	ADD COLUMN email_change_id uuid NULL
+		REFERENCES email_changes (id) ON DELETE CASCADE;
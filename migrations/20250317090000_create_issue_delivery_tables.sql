@@ -0,0 +1,24 @@
+-- Add migration script here
+CREATE TABLE newsletter_issues(
+	id uuid NOT NULL,
+	PRIMARY KEY (id),
+	segment TEXT NOT NULL,
+	subject TEXT NOT NULL,
+	html_content TEXT NOT NULL,
+	idempotency_key TEXT NULL UNIQUE,
+	created_at timestamptz NOT NULL
+);
+CREATE TABLE issue_deliveries(
+	issue_id uuid NOT NULL
+		REFERENCES newsletter_issues (id) ON DELETE CASCADE,
+	subscriber_id uuid NOT NULL
+		REFERENCES subscriptions (id) ON DELETE CASCADE,
+	PRIMARY KEY (issue_id, subscriber_id),
+	status TEXT NOT NULL,
+	attempts INTEGER NOT NULL DEFAULT 0,
+	queued_at timestamptz NOT NULL,
+	next_attempt_at timestamptz NOT NULL,
+	delivered_at timestamptz NULL
+);
+CREATE INDEX issue_deliveries_queued_idx ON issue_deliveries (next_attempt_at)
+	WHERE status = 'queued';
@@ -0,0 +1,4 @@
+-- Add migration script here
+-- Undoing an unsubscribe restores this status. Rows unsubscribed before it
+-- was recorded cannot be undone.
+ALTER TABLE subscriptions ADD COLUMN status_before_unsubscribe TEXT NULL;
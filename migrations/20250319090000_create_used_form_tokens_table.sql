@@ -0,0 +1,6 @@
+-- Add migration script here
+CREATE TABLE used_form_tokens(
+	form_token_hash TEXT NOT NULL,
+	PRIMARY KEY (form_token_hash),
+	expires_at timestamptz NOT NULL
+);
@@ -0,0 +1,24 @@
+-- Add migration script here
+-- An issue with a subject test first goes to a sample of its recipients,
+-- one variant each, and to everyone else once a winner is picked.
+ALTER TABLE newsletter_issues
+	ADD COLUMN winner_metric TEXT NULL,
+	ADD COLUMN test_ends_at timestamptz NULL,
+	ADD COLUMN winning_variant SMALLINT NULL;
+CREATE TABLE issue_subject_variants(
+	issue_id uuid NOT NULL
+		REFERENCES newsletter_issues (id) ON DELETE CASCADE,
+	variant SMALLINT NOT NULL,
+	PRIMARY KEY (issue_id, variant),
+	subject TEXT NOT NULL,
+	-- The results the winner was picked on, kept for auditing.
+	sent INTEGER NULL,
+	opens INTEGER NULL,
+	clicks INTEGER NULL
+);
+ALTER TABLE issue_deliveries
+	ADD COLUMN variant SMALLINT NULL,
+	ADD COLUMN opened_at timestamptz NULL,
+	ADD COLUMN clicked_at timestamptz NULL;
+CREATE INDEX newsletter_issues_running_tests_idx ON newsletter_issues (test_ends_at)
+	WHERE test_ends_at IS NOT NULL AND winning_variant IS NULL;
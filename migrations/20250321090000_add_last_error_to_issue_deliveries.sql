@@ -0,0 +1,8 @@
+-- Add migration script here
+-- Failed deliveries stay in `issue_deliveries` until an admin requeues or
+-- discards them.
+ALTER TABLE issue_deliveries
+	ADD COLUMN last_error TEXT NULL,
+	ADD COLUMN failed_at timestamptz NULL;
+CREATE INDEX issue_deliveries_failed_idx ON issue_deliveries (issue_id)
+	WHERE status = 'failed';
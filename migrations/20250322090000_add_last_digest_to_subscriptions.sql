@@ -0,0 +1,6 @@
+-- Add migration script here
+-- When the subscriber was last sent a weekly digest. Until the first one,
+-- the week is counted from when they subscribed.
+ALTER TABLE subscriptions ADD COLUMN last_digest_at timestamptz NULL;
+CREATE INDEX issue_deliveries_digest_idx ON issue_deliveries (subscriber_id)
+	WHERE status = 'digest';
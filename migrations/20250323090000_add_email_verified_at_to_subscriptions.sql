@@ -0,0 +1,12 @@
+-- Add migration script here
+-- When the subscriber proved they own the address by following any
+-- confirmation link. Confirming a list verifies the address without
+-- confirming the newsletter itself.
+ALTER TABLE subscriptions ADD COLUMN email_verified_at timestamptz NULL;
+UPDATE subscriptions s SET email_verified_at = s.subscribed_at
+WHERE s.status = 'confirmed' OR s.status_before_unsubscribe = 'confirmed'
+	OR EXISTS (
+		SELECT 1 FROM list_memberships m
+		WHERE m.subscriber_id = s.id AND m.status = 'confirmed'
+	);
+ALTER TABLE list_memberships ADD COLUMN left_at timestamptz NULL;
@@ -0,0 +1,31 @@
+use sqlx::{types::chrono::Utc, PgConnection};
+use uuid::Uuid;
+
+/// Audit entries only point at the subscriber row, never at the address, so
+/// they survive erasure without keeping personal data.
+#[tracing::instrument(name = "Record an audit event", skip(connection))]
+pub async fn record_audit_event(
+    connection: &mut PgConnection,
+    actor: &str,
+    action: &str,
+    subscriber_id: Option<Uuid>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    INSERT INTO audit_log (id, occurred_at, actor, action, subscriber_id)
+    VALUES ($1, $2, $3, $4, $5)
+    "#,
+        Uuid::new_v4(),
+        Utc::now(),
+        actor,
+        action,
+        subscriber_id,
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
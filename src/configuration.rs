@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::{net::IpAddr, time::Duration};
 
 use secrecy::ExposeSecret;
 use secrecy::SecretString;
@@ -6,13 +6,24 @@ use serde::Deserialize;
 use url::Url;
 use validator::ValidateEmail;
 
-use crate::domain::SubscriberEmail;
+use crate::{domain::SubscriberEmail, email_client::EmailClient};
 
 #[derive(Deserialize, Clone)]
 pub struct Settings {
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
     pub email_client: EmailClientSettings,
+    pub subscription_tokens: SubscriptionTokenSettings,
+    pub preferences: PreferenceSettings,
+    pub lists: Vec<ListSettings>,
+    #[serde(default)]
+    pub attributes: Vec<AttributeSettings>,
+    pub bot_protection: BotProtectionSettings,
+    pub rate_limits: RateLimitSettings,
+    pub retention: RetentionSettings,
+    pub consent: ConsentSettings,
+    pub subject_tests: SubjectTestSettings,
+    pub issue_delivery: IssueDeliverySettings,
 }
 
 #[derive(Deserialize, Clone)]
@@ -37,6 +48,14 @@ pub struct SmtpSettings {
 }
 
 impl EmailClientSettings {
+    pub fn client(&self) -> EmailClient {
+        let sender_email = self.sender().expect("Invalid sender email address.");
+        EmailClient::new(
+            self.connection_string().expose_secret(),
+            sender_email,
+            self.timeout(),
+        )
+    }
     pub fn timeout(&self) -> Duration {
         Duration::from_millis(self.timeout_milliseconds)
     }
@@ -73,11 +92,265 @@ impl EmailClientSettings {
     }
 }
 
+#[derive(Deserialize, Clone)]
+pub struct SubscriptionTokenSettings {
+    pub expiry_minutes: u64,
+    pub cleanup_interval_seconds: u64,
+    pub resend_cooldown_seconds: u64,
+}
+
+impl SubscriptionTokenSettings {
+    pub fn expiry(&self) -> Duration {
+        Duration::from_secs(self.expiry_minutes * 60)
+    }
+    pub fn cleanup_interval(&self) -> Duration {
+        Duration::from_secs(self.cleanup_interval_seconds)
+    }
+    pub fn resend_cooldown(&self) -> Duration {
+        Duration::from_secs(self.resend_cooldown_seconds)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct PreferenceSettings {
+    pub magic_link_expiry_minutes: u64,
+    pub session_expiry_minutes: u64,
+    pub topics: Vec<String>,
+    pub languages: Vec<String>,
+}
+
+impl PreferenceSettings {
+    pub fn magic_link_expiry(&self) -> Duration {
+        Duration::from_secs(self.magic_link_expiry_minutes * 60)
+    }
+    pub fn session_expiry(&self) -> Duration {
+        Duration::from_secs(self.session_expiry_minutes * 60)
+    }
+}
+
+/// Checks run on every subscribe request. A zero `min_submit_seconds` and
+/// `proof_of_work_difficulty`, the shipped default, turn the form token off
+/// entirely; raising either one needs every client to go through
+/// `/subscriptions/challenge`.
+#[derive(Deserialize, Clone)]
+pub struct BotProtectionSettings {
+    pub honeypot_field: Option<String>,
+    pub min_submit_seconds: u64,
+    pub max_form_age_minutes: u64,
+    pub proof_of_work_difficulty: u32,
+}
+
+impl BotProtectionSettings {
+    pub fn max_form_age(&self) -> Duration {
+        Duration::from_secs(self.max_form_age_minutes * 60)
+    }
+
+    /// Whether subscribe forms must carry a signed form token.
+    pub fn requires_form_token(&self) -> bool {
+        self.min_submit_seconds > 0 || self.proof_of_work_difficulty > 0
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct RateLimitSettings {
+    pub store: RateLimitStoreKind,
+    /// Proxies whose `X-Forwarded-For` header is believed.
+    pub trusted_proxies: Vec<IpAddr>,
+    pub per_ip: TokenBucketSettings,
+    pub per_email: TokenBucketSettings,
+}
+
+/// Buckets are kept in memory unless several replicas need to share them.
+#[derive(Deserialize, Clone)]
+pub enum RateLimitStoreKind {
+    #[serde(rename = "in_memory")]
+    InMemory,
+    #[serde(rename = "postgres")]
+    Postgres,
+}
+
+/// A bucket holds up to `capacity` requests and gets one back every
+/// `refill_interval_seconds`.
+#[derive(Deserialize, Clone)]
+pub struct TokenBucketSettings {
+    pub capacity: u32,
+    pub refill_interval_seconds: u64,
+}
+
+impl TokenBucketSettings {
+    pub fn refill_interval(&self) -> Duration {
+        Duration::from_secs(self.refill_interval_seconds)
+    }
+    /// How long an untouched bucket takes to fill up again.
+    pub fn refill_time(&self) -> Duration {
+        self.refill_interval() * self.capacity
+    }
+}
+
+/// What the retention worker does with subscribers that never confirmed.
+#[derive(Deserialize, Clone)]
+pub struct RetentionSettings {
+    pub pending_max_age_days: u64,
+    pub mode: RetentionMode,
+    /// Only report what would be purged.
+    pub dry_run: bool,
+    pub interval_seconds: u64,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub enum RetentionMode {
+    #[serde(rename = "delete")]
+    Delete,
+    #[serde(rename = "anonymize")]
+    Anonymize,
+}
+
+impl RetentionSettings {
+    pub fn pending_max_age(&self) -> Duration {
+        Duration::from_secs(self.pending_max_age_days * 24 * 60 * 60)
+    }
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_seconds)
+    }
+}
+
+/// How the delivery worker retries deliveries that failed. The wait doubles
+/// with every attempt, from `retry_base_seconds` up to `retry_max_seconds`.
+#[derive(Deserialize, Clone)]
+pub struct IssueDeliverySettings {
+    /// Deliveries that failed this many times are set aside as `failed`.
+    pub max_attempts: u32,
+    pub retry_base_seconds: u64,
+    pub retry_max_seconds: u64,
+}
+
+impl IssueDeliverySettings {
+    /// How long to wait before the next try, after `attempts` failures.
+    pub fn retry_delay(&self, attempts: u32) -> Duration {
+        let factor = 2u64.saturating_pow(attempts.saturating_sub(1));
+        Duration::from_secs(
+            self.retry_base_seconds
+                .saturating_mul(factor)
+                .min(self.retry_max_seconds),
+        )
+    }
+}
+
+/// How issues with several subject variants are tested before the winner
+/// goes out to everyone else.
+#[derive(Deserialize, Clone)]
+pub struct SubjectTestSettings {
+    /// The share of recipients, in percent, split between the variants.
+    pub sample_percent: u32,
+    pub wait_minutes: u64,
+}
+
+impl SubjectTestSettings {
+    pub fn wait(&self) -> Duration {
+        Duration::from_secs(self.wait_minutes * 60)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ConsentSettings {
+    /// Recorded for signups whose form does not send `consent_version`.
+    pub text_version: String,
+    /// Earlier versions forms may still show, e.g. from a cached page.
+    #[serde(default)]
+    pub accepted_versions: Vec<String>,
+}
+
+impl ConsentSettings {
+    /// The version to record for a signup. Only configured versions are
+    /// accepted, so the consent trail never holds arbitrary client input.
+    pub fn version(&self, submitted: Option<&str>) -> Result<String, String> {
+        match submitted {
+            None => Ok(self.text_version.clone()),
+            Some(version)
+                if version == self.text_version
+                    || self.accepted_versions.iter().any(|v| v == version) =>
+            {
+                Ok(version.to_string())
+            }
+            Some(version) => Err(format!("{} is not a known consent version.", version)),
+        }
+    }
+}
+
+/// A custom field collected at signup, e.g. `company`. It is sent next to
+/// `email` and `name` under its own name and stored in the `attributes`
+/// column of `subscriptions`.
+#[derive(Deserialize, Clone)]
+pub struct AttributeSettings {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: AttributeType,
+    #[serde(default)]
+    pub required: bool,
+    /// In graphemes, for text attributes.
+    pub max_length: Option<usize>,
+    /// Bounds for number attributes.
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// The accepted values of choice attributes.
+    #[serde(default)]
+    pub choices: Vec<String>,
+}
+
+impl AttributeSettings {
+    /// Rejects attributes that could never be submitted: ones named after a
+    /// field in `reserved_names`, and choice attributes without choices.
+    pub fn validate(&self, reserved_names: &[&str]) -> Result<(), String> {
+        if reserved_names.contains(&self.name.as_str()) {
+            return Err(format!(
+                "Attribute `{}` collides with a reserved form field.",
+                self.name
+            ));
+        }
+        if self.kind == AttributeType::Choice && self.choices.is_empty() {
+            return Err(format!("Choice attribute `{}` has no choices.", self.name));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttributeType {
+    #[serde(rename = "text")]
+    Text,
+    #[serde(rename = "number")]
+    Number,
+    #[serde(rename = "boolean")]
+    Boolean,
+    #[serde(rename = "choice")]
+    Choice,
+}
+
+/// A newsletter run from this deployment. Lists are kept in sync with the
+/// `lists` table on startup, keyed by `slug`.
+#[derive(Deserialize, Clone)]
+pub struct ListSettings {
+    pub slug: String,
+    pub name: String,
+    pub sender_email: String,
+    pub welcome_subject: String,
+    pub welcome_body: String,
+}
+
+impl ListSettings {
+    pub fn sender(&self) -> Result<SubscriberEmail, String> {
+        SubscriberEmail::parse(self.sender_email.clone())
+    }
+}
+
 #[derive(Deserialize, Clone)]
 pub struct ApplicationSettings {
     pub port: u16,
     pub host: String,
     pub base_url: String,
+    pub hmac_secret: SecretString,
+    /// Bearer token for the `/admin` endpoints, which are closed without it.
+    pub admin_token: Option<SecretString>,
 }
 
 #[derive(Deserialize, Clone)]
@@ -0,0 +1,176 @@
+use std::net::IpAddr;
+
+use axum::http::{header::USER_AGENT, HeaderMap};
+use serde::Serialize;
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    PgConnection,
+};
+use uuid::Uuid;
+
+/// Where a consent event came from.
+pub struct ConsentContext {
+    pub ip: IpAddr,
+    pub user_agent: Option<String>,
+}
+
+impl ConsentContext {
+    pub fn new(ip: IpAddr, headers: &HeaderMap) -> Self {
+        Self {
+            ip,
+            user_agent: headers
+                .get(USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.chars().take(512).collect()),
+        }
+    }
+}
+
+/// What a subscriber agreed to when signing up.
+pub struct SubscribeConsent {
+    pub context: ConsentContext,
+    /// The form or integration the signup came from.
+    pub source: Option<String>,
+    pub consent_version: String,
+}
+
+#[derive(Serialize)]
+pub struct ConsentEventRecord {
+    pub event: String,
+    pub list: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub source: Option<String>,
+    pub consent_version: Option<String>,
+    /// Why an imported subscriber counts as having consented.
+    pub attestation: Option<String>,
+    pub occurred_at: DateTime<Utc>,
+}
+
+#[tracing::instrument(
+    name = "Record a subscribe consent event",
+    skip(connection, subscriber_id, consent)
+)]
+pub async fn record_subscribe_consent(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    list_id: Option<Uuid>,
+    consent: &SubscribeConsent,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    INSERT INTO consent_events
+        (id, subscriber_id, list_id, event, ip, user_agent, source, consent_version, occurred_at)
+    VALUES ($1, $2, $3, 'subscribe', $4, $5, $6, $7, $8)
+    "#,
+        Uuid::new_v4(),
+        subscriber_id,
+        list_id,
+        consent.context.ip.to_string(),
+        consent.context.user_agent,
+        consent.source,
+        consent.consent_version,
+        Utc::now(),
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// A confirmation agrees to what was shown at signup, so it carries over the
+/// consent text version and source of the latest matching subscribe event.
+#[tracing::instrument(
+    name = "Record a confirm consent event",
+    skip(connection, subscriber_id, context)
+)]
+pub async fn record_confirm_consent(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    list_id: Option<Uuid>,
+    context: &ConsentContext,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    INSERT INTO consent_events
+        (id, subscriber_id, list_id, event, ip, user_agent, source, consent_version, occurred_at)
+    SELECT $1, $2, $3, 'confirm', $4, $5, signup.source, signup.consent_version, $6
+    FROM (SELECT 1) AS one
+    LEFT JOIN LATERAL (
+        SELECT source, consent_version FROM consent_events
+        WHERE subscriber_id = $2 AND list_id IS NOT DISTINCT FROM $3 AND event = 'subscribe'
+        ORDER BY occurred_at DESC LIMIT 1
+    ) AS signup ON TRUE
+    "#,
+        Uuid::new_v4(),
+        subscriber_id,
+        list_id,
+        context.ip.to_string(),
+        context.user_agent,
+        Utc::now(),
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// Imported subscribers signed up elsewhere, so instead of a request there
+/// is the import itself and, for rows imported as confirmed, the importer's
+/// statement of how consent was collected.
+#[tracing::instrument(
+    name = "Record an import consent event",
+    skip(connection, subscriber_id, attestation)
+)]
+pub async fn record_import_consent(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    attestation: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    INSERT INTO consent_events (id, subscriber_id, event, source, attestation, occurred_at)
+    VALUES ($1, $2, 'import', 'csv_import', $3, $4)
+    "#,
+        Uuid::new_v4(),
+        subscriber_id,
+        attestation,
+        Utc::now(),
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Get the consent events of a subscriber", skip(connection))]
+pub async fn get_consent_events(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+) -> Result<Vec<ConsentEventRecord>, sqlx::Error> {
+    sqlx::query_as!(
+        ConsentEventRecord,
+        r#"
+    SELECT c.event, l.slug AS "list?", c.ip, c.user_agent, c.source, c.consent_version,
+        c.attestation, c.occurred_at
+    FROM consent_events c LEFT JOIN lists l ON l.id = c.list_id
+    WHERE c.subscriber_id = $1 ORDER BY c.occurred_at
+    "#,
+        subscriber_id,
+    )
+    .fetch_all(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
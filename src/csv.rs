@@ -0,0 +1,189 @@
+use std::string::FromUtf8Error;
+
+use csv_core::{ReadRecordResult, Reader};
+
+/// Splits CSV into records as it arrives, so uploads never have to be held in
+/// memory as a whole.
+pub struct RecordReader {
+    reader: Reader,
+    output: Vec<u8>,
+    output_len: usize,
+    ends: Vec<usize>,
+    ends_len: usize,
+}
+
+pub type Record = Result<Vec<String>, FromUtf8Error>;
+
+impl Default for RecordReader {
+    fn default() -> Self {
+        Self {
+            reader: Reader::new(),
+            output: vec![0; 1024],
+            output_len: 0,
+            ends: vec![0; 16],
+            ends_len: 0,
+        }
+    }
+}
+
+impl RecordReader {
+    /// Returns the records completed by `chunk`. A record split across chunks
+    /// is returned with the chunk that ends it.
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<Record> {
+        let mut records = Vec::new();
+        let mut input = chunk;
+        loop {
+            let (result, nin, nout, nend) = self.reader.read_record(
+                input,
+                &mut self.output[self.output_len..],
+                &mut self.ends[self.ends_len..],
+            );
+            input = &input[nin..];
+            self.output_len += nout;
+            self.ends_len += nend;
+            match result {
+                ReadRecordResult::InputEmpty | ReadRecordResult::End => return records,
+                ReadRecordResult::OutputFull => self.output.resize(self.output.len() * 2, 0),
+                ReadRecordResult::OutputEndsFull => self.ends.resize(self.ends.len() * 2, 0),
+                ReadRecordResult::Record => {
+                    let record = self.take_record();
+                    // Blank lines carry no data.
+                    if !matches!(&record, Ok(fields) if fields.len() == 1 && fields[0].is_empty()) {
+                        records.push(record);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the last record if the input did not end with a newline.
+    pub fn finish(&mut self) -> Vec<Record> {
+        self.feed(&[])
+    }
+
+    fn take_record(&mut self) -> Record {
+        let mut start = 0;
+        let fields = self.ends[..self.ends_len]
+            .iter()
+            .map(|&end| {
+                let field = String::from_utf8(self.output[start..end].to_vec());
+                start = end;
+                field
+            })
+            .collect();
+        self.output_len = 0;
+        self.ends_len = 0;
+        fields
+    }
+}
+
+/// Encodes one record as a CSV line, quoting only the fields that need it.
+/// Spreadsheets run fields that look like formulas, so those are prefixed
+/// with `'` to be shown as text.
+pub fn write_record<S: AsRef<str>>(fields: &[S]) -> String {
+    let mut line = fields
+        .iter()
+        .map(|field| {
+            let field = field.as_ref();
+            let field = if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+                format!("'{}", field)
+            } else {
+                field.to_string()
+            };
+            if field.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    line.push('\n');
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(chunks: &[&[u8]]) -> Vec<Vec<String>> {
+        let mut reader = RecordReader::default();
+        let mut records = Vec::new();
+        for chunk in chunks {
+            records.extend(reader.feed(chunk));
+        }
+        records.extend(reader.finish());
+        records.into_iter().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn fields_that_look_like_formulas_are_written_as_text() {
+        let line = write_record(&["=HYPERLINK(\"x\")", "+1", "-1", "@SUM(A1)", "ursula"]);
+        assert_eq!(
+            line,
+            "\"'=HYPERLINK(\"\"x\"\")\",'+1,'-1,'@SUM(A1),ursula\n"
+        );
+    }
+
+    #[test]
+    fn records_are_split_into_fields() {
+        let records = read_all(&[b"email,name\nursula@example.com,Ursula\n"]);
+        assert_eq!(
+            records,
+            [vec!["email", "name"], vec!["ursula@example.com", "Ursula"]]
+        );
+    }
+
+    #[test]
+    fn records_split_across_chunks_are_reassembled() {
+        let records = read_all(&[b"email,na", b"me\nursula@exa", b"mple.com,\"Le ", b"Guin\""]);
+        assert_eq!(
+            records,
+            [vec!["email", "name"], vec!["ursula@example.com", "Le Guin"]]
+        );
+    }
+
+    #[test]
+    fn quoted_fields_may_contain_commas_and_newlines() {
+        let records = read_all(&[b"\"Le Guin, Ursula\",\"line\nbreak\"\n"]);
+        assert_eq!(records, [vec!["Le Guin, Ursula", "line\nbreak"]]);
+    }
+
+    #[test]
+    fn long_records_grow_the_buffers() {
+        let long_field = "a".repeat(5000);
+        let line = vec![long_field.as_str(); 40].join(",");
+        let records = read_all(&[line.as_bytes()]);
+        assert_eq!(records[0].len(), 40);
+        assert_eq!(records[0][39], long_field);
+    }
+
+    #[test]
+    fn blank_lines_are_skipped() {
+        let records = read_all(&[b"a,b\n\n\r\nc,d\n"]);
+        assert_eq!(records, [vec!["a", "b"], vec!["c", "d"]]);
+    }
+
+    #[test]
+    fn invalid_utf8_is_reported_per_record() {
+        let mut reader = RecordReader::default();
+        let mut records = reader.feed(b"a,\xff\nb,c\n");
+        records.extend(reader.finish());
+        assert!(records[0].is_err());
+        assert!(records[1].is_ok());
+    }
+
+    #[test]
+    fn fields_are_quoted_only_when_needed() {
+        let line = write_record(&["plain", "a,b", "say \"hi\"", "two\nlines"]);
+        assert_eq!(line, "plain,\"a,b\",\"say \"\"hi\"\"\",\"two\nlines\"\n");
+    }
+
+    #[test]
+    fn written_records_read_back_unchanged() {
+        let fields = ["Le Guin, Ursula", "\"quoted\"", "", "line\r\nbreak"];
+        let mut reader = RecordReader::default();
+        let records = reader.feed(write_record(&fields).as_bytes());
+        assert_eq!(records.into_iter().next().unwrap().unwrap(), fields);
+    }
+}
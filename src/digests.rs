@@ -0,0 +1,238 @@
+use std::time::Duration;
+
+use sqlx::{types::chrono::Utc, PgConnection, PgPool};
+use uuid::Uuid;
+
+use crate::{
+    domain::SubscriberEmail, email_client::EmailClient, hmac_secret::HmacSecret,
+    issues::DeliveryStatus, routes::unsubscribe_link,
+};
+
+/// Digest subscribers are sent at most one email this often.
+pub const DIGEST_INTERVAL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+struct DigestRecipient {
+    id: Uuid,
+    email: String,
+    reachable: bool,
+}
+
+struct DigestIssue {
+    id: Uuid,
+    subject: String,
+    html_content: String,
+}
+
+/// Sends every subscriber whose digest is due one email with all the issues
+/// held back for them since their last digest. A digest is due a week after
+/// the last one, or after subscribing, and straight away for subscribers
+/// who have switched back to immediate delivery. Returns how many digests
+/// were sent; failed ones are tried again on the next run.
+pub async fn send_due_digests(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    base_url: &str,
+    hmac_secret: &HmacSecret,
+) -> Result<u64, sqlx::Error> {
+    let due_before = Utc::now() - DIGEST_INTERVAL;
+    let subscriber_ids = sqlx::query_scalar!(
+        r#"
+    SELECT s.id FROM subscriptions s
+    WHERE (s.delivery_frequency = 'immediate' OR COALESCE(s.last_digest_at, s.subscribed_at) <= $1)
+        AND EXISTS (
+            SELECT 1 FROM issue_deliveries d WHERE d.subscriber_id = s.id AND d.status = 'digest'
+        )
+    "#,
+        due_before,
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    let mut sent = 0;
+    for subscriber_id in subscriber_ids {
+        let mut transaction = pool.begin().await?;
+        // Another worker may have sent it in the meantime.
+        let Some(recipient) = sqlx::query_as!(
+            DigestRecipient,
+            r#"
+    SELECT id, email,
+        (status = 'confirmed' OR (status = 'pending_confirmation'
+            AND email_verified_at IS NOT NULL)) AS "reachable!"
+    FROM subscriptions
+    WHERE id = $1
+        AND (delivery_frequency = 'immediate' OR COALESCE(last_digest_at, subscribed_at) <= $2)
+    FOR UPDATE SKIP LOCKED
+    "#,
+            subscriber_id,
+            due_before,
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?
+        else {
+            continue;
+        };
+        if send_digest(
+            &mut transaction,
+            email_client,
+            base_url,
+            hmac_secret,
+            &recipient,
+        )
+        .await?
+        {
+            sent += 1;
+        }
+        transaction.commit().await?;
+    }
+    Ok(sent)
+}
+
+/// Returns whether an email went out.
+#[tracing::instrument(
+    name = "Send a digest",
+    skip_all,
+    fields(subscriber_id = %recipient.id)
+)]
+async fn send_digest(
+    connection: &mut PgConnection,
+    email_client: &EmailClient,
+    base_url: &str,
+    hmac_secret: &HmacSecret,
+    recipient: &DigestRecipient,
+) -> Result<bool, sqlx::Error> {
+    let issues = sqlx::query_as!(
+        DigestIssue,
+        r#"
+    SELECT i.id, i.subject, i.html_content
+    FROM issue_deliveries d
+    JOIN newsletter_issues i ON i.id = d.issue_id
+    WHERE d.subscriber_id = $1 AND d.status = 'digest'
+    ORDER BY i.created_at
+    FOR UPDATE OF d
+    "#,
+        recipient.id,
+    )
+    .fetch_all(&mut *connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    let issue_ids: Vec<Uuid> = issues.iter().map(|issue| issue.id).collect();
+    // The subscriber may have left since the issues were held back.
+    let email = match SubscriberEmail::parse(recipient.email.clone()) {
+        Ok(email) if recipient.reachable => email,
+        _ => {
+            mark_digest_deliveries(
+                connection,
+                recipient.id,
+                &issue_ids,
+                DeliveryStatus::Skipped,
+            )
+            .await?;
+            return Ok(false);
+        }
+    };
+    let subject = match issues.len() {
+        1 => "Your weekly digest: 1 new issue".to_string(),
+        n => format!("Your weekly digest: {} new issues", n),
+    };
+    let body = digest_body(
+        &issues,
+        &unsubscribe_link(base_url, hmac_secret, recipient.id),
+    );
+    if let Err(e) = email_client.send_email(email, &subject, &body).await {
+        tracing::error!("Failed to send a digest: {}", e);
+        return Ok(false);
+    }
+    mark_digest_deliveries(connection, recipient.id, &issue_ids, DeliveryStatus::Sent).await?;
+    sqlx::query!(
+        r#"UPDATE subscriptions SET last_digest_at = $2 WHERE id = $1"#,
+        recipient.id,
+        Utc::now(),
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(true)
+}
+
+fn digest_body(issues: &[DigestIssue], unsubscribe_link: &str) -> String {
+    let mut body = String::from("<h1>Your weekly digest</h1>");
+    for issue in issues {
+        body.push_str(&format!(
+            "<h2>{}</h2>\n{}\n<hr />",
+            issue.subject, issue.html_content
+        ));
+    }
+    body.push_str(&format!(
+        "<br />
+    <a href=\"{}\">Unsubscribe</a>",
+        unsubscribe_link
+    ));
+    body
+}
+
+async fn mark_digest_deliveries(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    issue_ids: &[Uuid],
+    status: DeliveryStatus,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    UPDATE issue_deliveries SET status = $3, delivered_at = $4
+    WHERE subscriber_id = $1 AND issue_id = ANY($2)
+    "#,
+        subscriber_id,
+        issue_ids,
+        status.as_ref(),
+        Utc::now(),
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digest_lists_every_issue_in_order() {
+        let issues = [
+            DigestIssue {
+                id: Uuid::new_v4(),
+                subject: "Issue #1".to_string(),
+                html_content: "<p>First</p>".to_string(),
+            },
+            DigestIssue {
+                id: Uuid::new_v4(),
+                subject: "Issue #2".to_string(),
+                html_content: "<p>Second</p>".to_string(),
+            },
+        ];
+
+        let body = digest_body(&issues, "https://example.com/unsubscribe");
+
+        let first = body.find("Issue #1").unwrap();
+        let second = body.find("Issue #2").unwrap();
+        assert!(first < body.find("<p>First</p>").unwrap());
+        assert!(first < second);
+        assert!(body.contains("https://example.com/unsubscribe"));
+    }
+}
@@ -0,0 +1,53 @@
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeliveryFrequency {
+    #[default]
+    Immediate,
+    WeeklyDigest,
+}
+
+impl DeliveryFrequency {
+    pub fn parse(s: String) -> Result<Self, String> {
+        match s.as_str() {
+            "immediate" => Ok(Self::Immediate),
+            "weekly_digest" => Ok(Self::WeeklyDigest),
+            _ => Err(format!("{} is not a valid delivery frequency.", s)),
+        }
+    }
+}
+
+impl AsRef<str> for DeliveryFrequency {
+    fn as_ref(&self) -> &str {
+        match self {
+            Self::Immediate => "immediate",
+            Self::WeeklyDigest => "weekly_digest",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_frequencies_are_parsed_successfully() {
+        for frequency in [
+            DeliveryFrequency::Immediate,
+            DeliveryFrequency::WeeklyDigest,
+        ] {
+            let result = DeliveryFrequency::parse(frequency.as_ref().to_string());
+            assert_eq!(result, Ok(frequency));
+        }
+    }
+
+    #[test]
+    fn unknown_frequency_is_rejected() {
+        let result = DeliveryFrequency::parse("daily".to_string());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn empty_string_is_rejected() {
+        let result = DeliveryFrequency::parse("".to_string());
+        assert!(result.is_err());
+    }
+}
@@ -1,7 +1,15 @@
+mod delivery_frequency;
 mod new_subscriber;
+mod subscriber_attributes;
 mod subscriber_email;
 mod subscriber_name;
+mod subscriber_preferences;
+mod subscriber_tag;
 
+pub use delivery_frequency::*;
 pub use new_subscriber::*;
+pub use subscriber_attributes::*;
 pub use subscriber_email::*;
 pub use subscriber_name::*;
+pub use subscriber_preferences::*;
+pub use subscriber_tag::*;
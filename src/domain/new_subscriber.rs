@@ -1,7 +1,11 @@
+use super::delivery_frequency::*;
+use super::subscriber_attributes::*;
 use super::subscriber_email::*;
 use super::subscriber_name::*;
 
 pub struct NewSubscriber {
     pub email: SubscriberEmail,
     pub name: SubscriberName,
+    pub delivery_frequency: DeliveryFrequency,
+    pub attributes: SubscriberAttributes,
 }
@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+
+use serde_json::{Map, Number, Value};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::configuration::{AttributeSettings, AttributeType};
+
+/// Text attributes without a `max_length` are capped at this many graphemes.
+const DEFAULT_MAX_LENGTH: usize = 256;
+
+/// Custom attributes that passed validation against the configured schema,
+/// ready to be stored as a JSON object.
+#[derive(Debug, Default, PartialEq)]
+pub struct SubscriberAttributes(Map<String, Value>);
+
+#[derive(Debug, PartialEq)]
+pub struct AttributeError {
+    pub attribute: String,
+    pub message: String,
+}
+
+impl SubscriberAttributes {
+    /// Picks the attributes defined in `schema` out of `values` and validates
+    /// them. Values that are not part of the schema are ignored.
+    pub fn parse(
+        values: &HashMap<String, Value>,
+        schema: &[AttributeSettings],
+    ) -> Result<Self, Vec<AttributeError>> {
+        let mut attributes = Map::new();
+        let mut errors = Vec::new();
+        for definition in schema {
+            match parse_value(values.get(&definition.name), definition) {
+                Ok(Some(value)) => {
+                    attributes.insert(definition.name.clone(), value);
+                }
+                Ok(None) => {}
+                Err(message) => errors.push(AttributeError {
+                    attribute: definition.name.clone(),
+                    message,
+                }),
+            }
+        }
+        if errors.is_empty() {
+            Ok(Self(attributes))
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl AsRef<Map<String, Value>> for SubscriberAttributes {
+    fn as_ref(&self) -> &Map<String, Value> {
+        &self.0
+    }
+}
+
+impl From<SubscriberAttributes> for Value {
+    fn from(attributes: SubscriberAttributes) -> Self {
+        Value::Object(attributes.0)
+    }
+}
+
+// Forms send every value as a string, so numbers and booleans are accepted
+// both natively and as their string form.
+fn parse_value(
+    value: Option<&Value>,
+    definition: &AttributeSettings,
+) -> Result<Option<Value>, String> {
+    let name = &definition.name;
+    let value = match value {
+        None | Some(Value::Null) => None,
+        Some(Value::String(s)) if s.trim().is_empty() => None,
+        Some(value) => Some(value),
+    };
+    let Some(value) = value else {
+        if definition.required {
+            return Err(format!("{} is required.", name));
+        }
+        return Ok(None);
+    };
+    match definition.kind {
+        AttributeType::Text => {
+            let Value::String(s) = value else {
+                return Err(format!("{} must be text.", name));
+            };
+            let s = s.trim();
+            let max_length = definition.max_length.unwrap_or(DEFAULT_MAX_LENGTH);
+            if s.graphemes(true).count() > max_length {
+                return Err(format!(
+                    "{} must be at most {} characters long.",
+                    name, max_length
+                ));
+            }
+            Ok(Some(Value::String(s.to_string())))
+        }
+        AttributeType::Number => {
+            let number = match value {
+                Value::Number(n) => Some(n.clone()),
+                Value::String(s) => s.trim().parse::<Number>().ok(),
+                _ => None,
+            }
+            .ok_or_else(|| format!("{} must be a number.", name))?;
+            // Always `Some` without serde_json's arbitrary precision.
+            let value = number.as_f64().unwrap_or_default();
+            if let Some(min) = definition.min.filter(|min| value < *min) {
+                return Err(format!("{} must be at least {}.", name, min));
+            }
+            if let Some(max) = definition.max.filter(|max| value > *max) {
+                return Err(format!("{} must be at most {}.", name, max));
+            }
+            Ok(Some(Value::Number(number)))
+        }
+        AttributeType::Boolean => match value {
+            Value::Bool(b) => Ok(Some(Value::Bool(*b))),
+            // Checked checkboxes are sent as `on`.
+            Value::String(s) => match s.trim() {
+                "true" | "on" => Ok(Some(Value::Bool(true))),
+                "false" => Ok(Some(Value::Bool(false))),
+                _ => Err(format!("{} must be true or false.", name)),
+            },
+            _ => Err(format!("{} must be true or false.", name)),
+        },
+        AttributeType::Choice => {
+            let choice = value.as_str().map(str::trim);
+            match choice.filter(|c| definition.choices.iter().any(|choice| choice == c)) {
+                Some(choice) => Ok(Some(Value::String(choice.to_string()))),
+                None => Err(format!(
+                    "{} must be one of {}.",
+                    name,
+                    definition.choices.join(", ")
+                )),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn definition(name: &str, kind: AttributeType) -> AttributeSettings {
+        AttributeSettings {
+            name: name.to_string(),
+            kind,
+            required: false,
+            max_length: None,
+            min: None,
+            max: None,
+            choices: vec![],
+        }
+    }
+
+    fn schema() -> Vec<AttributeSettings> {
+        vec![
+            AttributeSettings {
+                max_length: Some(10),
+                ..definition("company", AttributeType::Text)
+            },
+            AttributeSettings {
+                min: Some(1.0),
+                max: Some(100.0),
+                ..definition("employees", AttributeType::Number)
+            },
+            definition("beta_tester", AttributeType::Boolean),
+            AttributeSettings {
+                choices: vec!["engineer".to_string(), "marketing".to_string()],
+                ..definition("role", AttributeType::Choice)
+            },
+        ]
+    }
+
+    fn values(value: Value) -> HashMap<String, Value> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn valid_values_are_parsed_successfully() {
+        let result = SubscriberAttributes::parse(
+            &values(json!({
+                "company": " Acme ",
+                "employees": "42",
+                "beta_tester": "on",
+                "role": "engineer",
+            })),
+            &schema(),
+        )
+        .unwrap();
+        assert_eq!(
+            Value::from(result),
+            json!({
+                "company": "Acme",
+                "employees": 42,
+                "beta_tester": true,
+                "role": "engineer",
+            })
+        );
+    }
+
+    #[test]
+    fn values_outside_the_schema_are_ignored() {
+        let result = SubscriberAttributes::parse(&values(json!({"website": "x"})), &schema());
+        assert_eq!(result, Ok(SubscriberAttributes::default()));
+    }
+
+    #[test]
+    fn missing_required_attributes_are_rejected() {
+        let schema = vec![AttributeSettings {
+            required: true,
+            ..definition("country", AttributeType::Text)
+        }];
+        let result = SubscriberAttributes::parse(&values(json!({"country": " "})), &schema);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn every_invalid_value_is_reported() {
+        let errors = SubscriberAttributes::parse(
+            &values(json!({
+                "company": "a".repeat(11),
+                "employees": 0,
+                "beta_tester": "maybe",
+                "role": "sales",
+            })),
+            &schema(),
+        )
+        .unwrap_err();
+        let attributes: Vec<_> = errors.iter().map(|e| e.attribute.as_str()).collect();
+        assert_eq!(attributes, ["company", "employees", "beta_tester", "role"]);
+    }
+
+    #[test]
+    fn numbers_must_be_numeric() {
+        let result = SubscriberAttributes::parse(&values(json!({"employees": "many"})), &schema());
+        assert!(result.is_err());
+    }
+}
@@ -1,25 +1,48 @@
 use validator::ValidateEmail;
+
+/// An address in canonical form: trimmed, with the domain lowercased and
+/// internationalized domains converted to punycode. The local part is kept
+/// as typed, since mail servers may treat it as case-sensitive.
 #[derive(Debug, Clone)]
-pub struct SubscriberEmail(String);
+pub struct SubscriberEmail {
+    address: String,
+    /// The address as the subscriber typed it, minus surrounding whitespace.
+    display: String,
+}
 
 impl SubscriberEmail {
     pub fn parse(s: String) -> Result<Self, String> {
-        if ValidateEmail::validate_email(&s) {
-            return Ok(Self(s));
+        let display = s.trim();
+        let address = display
+            .rsplit_once('@')
+            .and_then(|(local, domain)| {
+                let domain = idna::domain_to_ascii(domain).ok()?;
+                Some(format!("{}@{}", local, domain))
+            })
+            .filter(ValidateEmail::validate_email);
+        match address {
+            Some(address) => Ok(Self {
+                address,
+                display: display.to_string(),
+            }),
+            None => Err(format!("{} is not a valid subscriber email.", s)),
         }
-        Err(format!("{} is not a valid subscriber email.", s))
+    }
+
+    pub fn display(&self) -> &str {
+        &self.display
     }
 }
 
 impl AsRef<str> for SubscriberEmail {
     fn as_ref(&self) -> &str {
-        &self.0
+        &self.address
     }
 }
 
 impl AsMut<str> for SubscriberEmail {
     fn as_mut(&mut self) -> &mut str {
-        &mut self.0
+        &mut self.address
     }
 }
 #[cfg(test)]
@@ -48,6 +71,33 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn surrounding_whitespace_is_trimmed() {
+        let email = SubscriberEmail::parse("  ursula@domain.com\n".to_string()).unwrap();
+        assert_eq!(email.as_ref(), "ursula@domain.com");
+        assert_eq!(email.display(), "ursula@domain.com");
+    }
+
+    #[test]
+    fn the_domain_is_lowercased_but_the_local_part_is_kept() {
+        let email = SubscriberEmail::parse("Ursula.LeGuin@Domain.COM".to_string()).unwrap();
+        assert_eq!(email.as_ref(), "Ursula.LeGuin@domain.com");
+        assert_eq!(email.display(), "Ursula.LeGuin@Domain.COM");
+    }
+
+    #[test]
+    fn internationalized_domains_are_converted_to_punycode() {
+        let email = SubscriberEmail::parse("ursula@Bücher.example".to_string()).unwrap();
+        assert_eq!(email.as_ref(), "ursula@xn--bcher-kva.example");
+        assert_eq!(email.display(), "ursula@Bücher.example");
+    }
+
+    #[test]
+    fn invalid_domains_are_rejected() {
+        let result = SubscriberEmail::parse("ursula@exa mple.com".to_string());
+        assert!(result.is_err());
+    }
+
     #[derive(Debug, Clone)]
     struct ValidEmailFixture(pub String);
     impl quickcheck::Arbitrary for ValidEmailFixture {
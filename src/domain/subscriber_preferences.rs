@@ -0,0 +1,9 @@
+use super::delivery_frequency::*;
+use super::subscriber_name::*;
+
+pub struct SubscriberPreferences {
+    pub name: SubscriberName,
+    pub topics: Vec<String>,
+    pub delivery_frequency: DeliveryFrequency,
+    pub language: String,
+}
@@ -0,0 +1,54 @@
+/// A label set by admins, e.g. `vip` or `beta-2025`. Tags are lowercase so
+/// that segments cannot miss subscribers because of the spelling.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubscriberTag(String);
+
+impl SubscriberTag {
+    pub fn parse(s: String) -> Result<Self, String> {
+        let is_valid = !s.is_empty()
+            && s.len() <= 64
+            && s.chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+        if !is_valid {
+            return Err(format!("{} is not a valid tag.", s));
+        }
+        Ok(Self(s))
+    }
+}
+
+impl AsRef<str> for SubscriberTag {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_valid_tag_is_parsed_successfully() {
+        let result = SubscriberTag::parse("beta-2025_vip".to_string());
+        assert!(result.is_ok(), "{:?}", result.unwrap_err());
+    }
+
+    #[test]
+    fn a_tag_longer_than_64_characters_is_rejected() {
+        let result = SubscriberTag::parse("a".repeat(65));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn empty_string_is_rejected() {
+        let result = SubscriberTag::parse("".to_string());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn uppercase_and_whitespace_are_rejected() {
+        for tag in ["VIP", "early adopter"] {
+            let result = SubscriberTag::parse(tag.to_string());
+            assert!(result.is_err());
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    PgConnection, PgPool,
+};
+use uuid::Uuid;
+
+use crate::{audit::record_audit_event, domain::SubscriberEmail, erasure::anonymize_subscribers};
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct BackfillSummary {
+    /// Addresses rewritten to punycode.
+    pub converted: u64,
+    /// Subscribers whose punycode address belonged to another subscriber,
+    /// merged into one.
+    pub merged: u64,
+}
+
+struct MergeCandidate {
+    id: Uuid,
+    status: String,
+    subscribed_at: DateTime<Utc>,
+}
+
+/// Converts the internationalized domains of stored addresses to punycode,
+/// which the migration to canonical addresses could not do in SQL. Runs at
+/// startup and finds nothing to do once every address is canonical.
+#[tracing::instrument(name = "Convert stored email domains to punycode", skip(pool))]
+pub async fn backfill_punycode_domains(pool: &PgPool) -> Result<BackfillSummary, sqlx::Error> {
+    let subscribers =
+        sqlx::query!(r#"SELECT id, email FROM subscriptions WHERE email !~ '^[[:ascii:]]*$'"#)
+            .fetch_all(pool)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to execute query: {:?}", e);
+                e
+            })?;
+    let mut summary = BackfillSummary::default();
+    for subscriber in subscribers {
+        let Ok(email) = SubscriberEmail::parse(subscriber.email.clone()) else {
+            tracing::warn!(subscriber_id = %subscriber.id, "Left an invalid stored address as it is");
+            continue;
+        };
+        // Non-ASCII local parts have no ASCII form.
+        if email.as_ref() == subscriber.email {
+            continue;
+        }
+        let mut transaction = pool.begin().await?;
+        if merge_duplicate(&mut transaction, subscriber.id, &email).await? {
+            summary.merged += 1;
+        } else {
+            summary.converted += 1;
+        }
+        transaction.commit().await?;
+    }
+    tracing::info!(
+        converted = summary.converted,
+        merged = summary.merged,
+        "Converted stored email domains to punycode"
+    );
+    Ok(summary)
+}
+
+/// Gives the subscriber its punycode address. If another subscriber already
+/// holds it, the two are the same person and are merged the way the
+/// case-insensitive migration merged addresses differing only in case: the
+/// confirmed row, otherwise the oldest, is kept and takes over the list
+/// memberships; the other is anonymized as `merged` and audit logged.
+/// Returns whether a merge happened.
+async fn merge_duplicate(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    email: &SubscriberEmail,
+) -> Result<bool, sqlx::Error> {
+    let mut candidates = sqlx::query_as!(
+        MergeCandidate,
+        r#"
+    SELECT id, status, subscribed_at FROM subscriptions
+    WHERE id = $1 OR lower(email) = lower($2)
+    FOR UPDATE
+    "#,
+        subscriber_id,
+        email.as_ref(),
+    )
+    .fetch_all(&mut *connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    candidates.sort_by_key(|c| (c.status != "confirmed", c.subscribed_at, c.id));
+    let kept_id = candidates.first().map_or(subscriber_id, |c| c.id);
+    let merged_ids: Vec<Uuid> = candidates.iter().skip(1).map(|c| c.id).collect();
+    if !merged_ids.is_empty() {
+        // A confirmed membership wins over a pending one, whichever row
+        // held it.
+        sqlx::query!(
+            r#"
+    INSERT INTO list_memberships
+        (subscriber_id, list_id, status, subscribed_at, confirmed_at, left_at)
+    SELECT DISTINCT ON (list_id) $2::uuid, list_id, status, subscribed_at, confirmed_at, left_at
+    FROM list_memberships WHERE subscriber_id = ANY($1)
+    ORDER BY list_id, status = 'confirmed' DESC, subscribed_at
+    ON CONFLICT (subscriber_id, list_id) DO UPDATE
+    SET status = EXCLUDED.status, subscribed_at = EXCLUDED.subscribed_at,
+        confirmed_at = EXCLUDED.confirmed_at, left_at = EXCLUDED.left_at
+    WHERE EXCLUDED.status = 'confirmed' AND list_memberships.status <> 'confirmed'
+    "#,
+            &merged_ids,
+            kept_id,
+        )
+        .execute(&mut *connection)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        sqlx::query!(
+            r#"DELETE FROM list_memberships WHERE subscriber_id = ANY($1)"#,
+            &merged_ids,
+        )
+        .execute(&mut *connection)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        // The kept row may only have taken over a list the address was
+        // verified through.
+        sqlx::query!(
+            r#"
+    UPDATE subscriptions SET email_verified_at = (
+        SELECT MIN(email_verified_at) FROM subscriptions WHERE id = ANY($1) OR id = $2
+    )
+    WHERE id = $2
+    "#,
+            &merged_ids,
+            kept_id,
+        )
+        .execute(&mut *connection)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        anonymize_subscribers(connection, &merged_ids, "merged").await?;
+        for merged_id in &merged_ids {
+            tracing::warn!(
+                subscriber_id = %merged_id,
+                kept_subscriber_id = %kept_id,
+                "Merged a subscriber whose punycode address belonged to another"
+            );
+            record_audit_event(
+                connection,
+                "email_backfill",
+                "subscriber_merged",
+                Some(*merged_id),
+            )
+            .await?;
+        }
+    }
+    if kept_id == subscriber_id {
+        sqlx::query!(
+            r#"UPDATE subscriptions SET email = $2 WHERE id = $1"#,
+            subscriber_id,
+            email.as_ref(),
+        )
+        .execute(connection)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    }
+    Ok(!merged_ids.is_empty())
+}
@@ -1,6 +1,6 @@
 use std::time::Duration;
 
-use crate::domain::SubscriberEmail;
+use crate::{domain::SubscriberEmail, suppressions::SuppressionList};
 use lettre::message::{header, Mailbox, SinglePart};
 use lettre::{Address, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
 
@@ -8,6 +8,7 @@ use lettre::{Address, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executo
 pub struct EmailClient {
     mailer: AsyncSmtpTransport<Tokio1Executor>,
     sender: SubscriberEmail,
+    suppressions: Option<SuppressionList>,
 }
 
 impl EmailClient {
@@ -18,16 +19,51 @@ impl EmailClient {
                 .timeout(Some(timeout))
                 .build(),
             sender,
+            suppressions: None,
         }
     }
 
+    /// Makes every send check the recipient against the suppression list.
+    /// Suppressed recipients are skipped without an error, so callers do not
+    /// reveal that an address is suppressed.
+    pub fn with_suppressions(mut self, suppressions: SuppressionList) -> Self {
+        self.suppressions = Some(suppressions);
+        self
+    }
+
     pub async fn send_email(
         &self,
         recipient: SubscriberEmail,
         subject: &str,
         text_content: &str,
     ) -> Result<(), String> {
-        let address: Address = self.sender.as_ref().parse().unwrap();
+        self.send_email_as(&self.sender, recipient, subject, text_content)
+            .await
+    }
+
+    /// Sends from another address than the configured one, e.g. the sender
+    /// of a mailing list. The SMTP account must be allowed to send as it.
+    pub async fn send_email_as(
+        &self,
+        sender: &SubscriberEmail,
+        recipient: SubscriberEmail,
+        subject: &str,
+        text_content: &str,
+    ) -> Result<(), String> {
+        if let Some(suppressions) = &self.suppressions {
+            if suppressions
+                .contains(&recipient)
+                .await
+                .map_err(|err| format!("failed to check suppressions: {}", err))?
+            {
+                tracing::info!("Skipped an email to a suppressed address");
+                return Ok(());
+            }
+        }
+        let address: Address = sender
+            .as_ref()
+            .parse()
+            .map_err(|err| format!("invalid sender address: {}", err))?;
         let email = Message::builder()
             .from(Mailbox {
                 name: Some(address.user().to_string()),
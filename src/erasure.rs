@@ -0,0 +1,71 @@
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::{
+    audit::record_audit_event,
+    domain::SubscriberEmail,
+    hmac_secret::HmacSecret,
+    suppressions::{suppression_keys, tombstone_email},
+};
+
+/// Erases a subscriber's personal data. The row itself stays, with its
+/// signup date and list memberships, so aggregate counts remain valid, and
+/// the address is suppressed so it cannot be imported again by accident.
+#[tracing::instrument(name = "Erase a subscriber", skip(connection, hmac_secret, email))]
+pub async fn erase_subscriber(
+    connection: &mut PgConnection,
+    hmac_secret: &HmacSecret,
+    subscriber_id: Uuid,
+    email: &SubscriberEmail,
+    actor: &str,
+) -> Result<(), sqlx::Error> {
+    tombstone_email(connection, &suppression_keys(hmac_secret, email), actor).await?;
+    anonymize_subscribers(connection, &[subscriber_id], "erased").await?;
+    record_audit_event(connection, actor, "erasure_completed", Some(subscriber_id)).await
+}
+
+/// Replaces everything that identifies the people behind the rows and drops
+/// what only makes sense for a reachable subscriber. `status` records why.
+pub async fn anonymize_subscribers(
+    connection: &mut PgConnection,
+    subscriber_ids: &[Uuid],
+    status: &str,
+) -> Result<(), sqlx::Error> {
+    let queries = [
+        sqlx::query!(
+            r#"
+    UPDATE subscriptions
+    SET email = id || '@anonymized.invalid', display_email = id || '@anonymized.invalid',
+        name = 'anonymized', status = $2,
+        unsubscribe_reason = NULL, topics = '{}', attributes = '{}'
+    WHERE id = ANY($1)
+    "#,
+            subscriber_ids,
+            status,
+        ),
+        // The consent trail stays, minus what identifies the person.
+        sqlx::query!(
+            r#"UPDATE consent_events SET ip = NULL, user_agent = NULL WHERE subscriber_id = ANY($1)"#,
+            subscriber_ids,
+        ),
+        sqlx::query!(
+            r#"DELETE FROM subscription_tokens WHERE subscriber_id = ANY($1)"#,
+            subscriber_ids,
+        ),
+        sqlx::query!(
+            r#"DELETE FROM preference_sessions WHERE subscriber_id = ANY($1)"#,
+            subscriber_ids,
+        ),
+        sqlx::query!(
+            r#"DELETE FROM email_changes WHERE subscriber_id = ANY($1)"#,
+            subscriber_ids,
+        ),
+    ];
+    for query in queries {
+        query.execute(&mut *connection).await.map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    }
+    Ok(())
+}
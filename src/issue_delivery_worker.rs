@@ -0,0 +1,237 @@
+use std::time::{Duration, Instant};
+
+use sqlx::{types::chrono::Utc, PgConnection, PgPool};
+use uuid::Uuid;
+
+use crate::{
+    configuration::{IssueDeliverySettings, Settings},
+    digests::send_due_digests,
+    domain::SubscriberEmail,
+    email_client::EmailClient,
+    hmac_secret::HmacSecret,
+    issues::DeliveryStatus,
+    routes::unsubscribe_link,
+    startup::get_connection_pool,
+    subject_tests::{add_tracking, finish_subject_tests},
+    suppressions::SuppressionList,
+};
+
+pub async fn run_worker_until_stopped(configuration: Settings) -> Result<(), std::io::Error> {
+    let pool = get_connection_pool(&configuration.database);
+    let hmac_secret = HmacSecret::new(configuration.application.hmac_secret);
+    let email_client = configuration
+        .email_client
+        .client()
+        .with_suppressions(SuppressionList::new(pool.clone(), hmac_secret.clone()));
+    worker_loop(
+        pool,
+        email_client,
+        configuration.application.base_url,
+        hmac_secret,
+        configuration.issue_delivery,
+    )
+    .await
+}
+
+async fn worker_loop(
+    pool: PgPool,
+    email_client: EmailClient,
+    base_url: String,
+    hmac_secret: HmacSecret,
+    settings: IssueDeliverySettings,
+) -> Result<(), std::io::Error> {
+    let mut next_test_check = Instant::now();
+    let mut next_digest_check = Instant::now();
+    loop {
+        // Deliveries released by a decided test are queued like any other.
+        if Instant::now() >= next_test_check {
+            if let Err(e) = finish_subject_tests(&pool).await {
+                tracing::error!("Failed to finish subject tests: {:?}", e);
+            }
+            next_test_check = Instant::now() + Duration::from_secs(10);
+        }
+        if Instant::now() >= next_digest_check {
+            if let Err(e) = send_due_digests(&pool, &email_client, &base_url, &hmac_secret).await {
+                tracing::error!("Failed to send digests: {:?}", e);
+            }
+            next_digest_check = Instant::now() + Duration::from_secs(60);
+        }
+        match try_execute_task(&pool, &email_client, &base_url, &hmac_secret, &settings).await {
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+            Ok(ExecutionOutcome::EmptyQueue) => tokio::time::sleep(Duration::from_secs(10)).await,
+            Err(_) => tokio::time::sleep(Duration::from_secs(1)).await,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+}
+
+struct Delivery {
+    issue_id: Uuid,
+    subscriber_id: Uuid,
+    attempts: i32,
+    subject: String,
+    html_content: String,
+    /// Opens and clicks are only tracked for issues with a subject test.
+    tracked: bool,
+    email: String,
+    reachable: bool,
+}
+
+/// Sends the next queued delivery. The row stays locked until it is marked,
+/// so two workers never mail the same subscriber the same issue.
+#[tracing::instrument(
+    name = "Deliver an issue",
+    skip_all,
+    fields(issue_id = tracing::field::Empty, subscriber_id = tracing::field::Empty)
+)]
+pub async fn try_execute_task(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    base_url: &str,
+    hmac_secret: &HmacSecret,
+    settings: &IssueDeliverySettings,
+) -> Result<ExecutionOutcome, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let Some(delivery) = dequeue_delivery(&mut transaction).await? else {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    };
+    let span = tracing::Span::current();
+    span.record("issue_id", tracing::field::display(delivery.issue_id));
+    span.record(
+        "subscriber_id",
+        tracing::field::display(delivery.subscriber_id),
+    );
+    // The subscriber may have left since the issue was queued.
+    let email = match SubscriberEmail::parse(delivery.email.clone()) {
+        Ok(email) if delivery.reachable => email,
+        _ => {
+            mark_delivery(&mut transaction, &delivery, DeliveryStatus::Skipped).await?;
+            transaction.commit().await?;
+            return Ok(ExecutionOutcome::TaskCompleted);
+        }
+    };
+    let html_content = if delivery.tracked {
+        add_tracking(
+            &delivery.html_content,
+            base_url,
+            hmac_secret,
+            delivery.issue_id,
+            delivery.subscriber_id,
+        )
+    } else {
+        delivery.html_content.clone()
+    };
+    let body = format!(
+        "{}<br />
+    <a href=\"{}\">Unsubscribe</a>",
+        html_content,
+        unsubscribe_link(base_url, hmac_secret, delivery.subscriber_id)
+    );
+    match email_client
+        .send_email(email, &delivery.subject, &body)
+        .await
+    {
+        Ok(()) => mark_delivery(&mut transaction, &delivery, DeliveryStatus::Sent).await?,
+        Err(e) => {
+            tracing::error!("Failed to deliver an issue: {}", e);
+            retry_delivery(&mut transaction, &delivery, &e, settings).await?;
+        }
+    }
+    transaction.commit().await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+async fn dequeue_delivery(connection: &mut PgConnection) -> Result<Option<Delivery>, sqlx::Error> {
+    sqlx::query_as!(
+        Delivery,
+        r#"
+    SELECT d.issue_id, d.subscriber_id, d.attempts, COALESCE(v.subject, i.subject) AS "subject!",
+        i.html_content, i.winner_metric IS NOT NULL AS "tracked!", s.email,
+        (s.status = 'confirmed' OR (s.status = 'pending_confirmation'
+            AND s.email_verified_at IS NOT NULL)) AS "reachable!"
+    FROM issue_deliveries d
+    JOIN newsletter_issues i ON i.id = d.issue_id
+    JOIN subscriptions s ON s.id = d.subscriber_id
+    LEFT JOIN issue_subject_variants v ON v.issue_id = d.issue_id AND v.variant = d.variant
+    WHERE d.status = 'queued' AND d.next_attempt_at <= $1
+    ORDER BY d.next_attempt_at
+    FOR UPDATE OF d SKIP LOCKED
+    LIMIT 1
+    "#,
+        Utc::now(),
+    )
+    .fetch_optional(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+async fn mark_delivery(
+    connection: &mut PgConnection,
+    delivery: &Delivery,
+    status: DeliveryStatus,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    UPDATE issue_deliveries SET status = $3, delivered_at = $4
+    WHERE issue_id = $1 AND subscriber_id = $2
+    "#,
+        delivery.issue_id,
+        delivery.subscriber_id,
+        status.as_ref(),
+        Utc::now(),
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// Puts a failed delivery back in the queue, waiting twice as long after
+/// every attempt, or sets it aside as `failed` for an admin to requeue or
+/// discard.
+async fn retry_delivery(
+    connection: &mut PgConnection,
+    delivery: &Delivery,
+    error: &str,
+    settings: &IssueDeliverySettings,
+) -> Result<(), sqlx::Error> {
+    let now = Utc::now();
+    let attempts = delivery.attempts + 1;
+    let status = if attempts as u32 >= settings.max_attempts {
+        DeliveryStatus::Failed
+    } else {
+        DeliveryStatus::Queued
+    };
+    sqlx::query!(
+        r#"
+    UPDATE issue_deliveries
+    SET status = $3, attempts = $4, next_attempt_at = $5, last_error = $6, failed_at = $7
+    WHERE issue_id = $1 AND subscriber_id = $2
+    "#,
+        delivery.issue_id,
+        delivery.subscriber_id,
+        status.as_ref(),
+        attempts,
+        now + settings.retry_delay(attempts as u32),
+        error,
+        (status == DeliveryStatus::Failed).then_some(now),
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
@@ -0,0 +1,325 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    PgConnection,
+};
+use uuid::Uuid;
+
+use crate::subject_tests::WinnerMetric;
+
+/// Where a recipient's copy of an issue stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    /// Waiting for the delivery worker.
+    Queued,
+    Sent,
+    /// Gave up after repeated failures, until an admin requeues or
+    /// discards the delivery.
+    Failed,
+    /// A failed delivery an admin decided not to retry.
+    Discarded,
+    Suppressed,
+    /// Held back for subscribers who asked for a weekly digest.
+    Digest,
+    /// The subscriber left before the worker got to them.
+    Skipped,
+    /// Waiting for the subject test to pick the winning subject.
+    Held,
+}
+
+impl AsRef<str> for DeliveryStatus {
+    fn as_ref(&self) -> &str {
+        match self {
+            Self::Queued => "queued",
+            Self::Sent => "sent",
+            Self::Failed => "failed",
+            Self::Discarded => "discarded",
+            Self::Suppressed => "suppressed",
+            Self::Digest => "digest",
+            Self::Skipped => "skipped",
+            Self::Held => "held",
+        }
+    }
+}
+
+pub struct NewIssue<'a> {
+    pub segment: &'a str,
+    pub subject: &'a str,
+    pub html_content: &'a str,
+    /// Lets a client retry a send without mailing everyone twice.
+    pub idempotency_key: Option<&'a str>,
+    pub subject_test: Option<SubjectTest<'a>>,
+}
+
+/// Subjects tried out on a sample of the recipients. `subject` is the first
+/// variant and the one `html_content` goes out with until a winner is picked.
+pub struct SubjectTest<'a> {
+    pub variants: &'a [String],
+    pub winner_metric: WinnerMetric,
+    pub duration: Duration,
+}
+
+#[tracing::instrument(name = "Find an issue by idempotency key", skip(connection))]
+pub async fn get_issue_id_by_idempotency_key(
+    connection: &mut PgConnection,
+    idempotency_key: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    Ok(sqlx::query!(
+        r#"SELECT id FROM newsletter_issues WHERE idempotency_key = $1"#,
+        idempotency_key,
+    )
+    .fetch_optional(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .map(|r| r.id))
+}
+
+#[tracing::instrument(name = "Store a newsletter issue", skip(connection, issue))]
+pub async fn insert_issue(
+    connection: &mut PgConnection,
+    issue: &NewIssue<'_>,
+) -> Result<Uuid, sqlx::Error> {
+    let issue_id = Uuid::new_v4();
+    let now = Utc::now();
+    let test = issue.subject_test.as_ref();
+    sqlx::query!(
+        r#"
+    INSERT INTO newsletter_issues
+        (id, segment, subject, html_content, idempotency_key, created_at, winner_metric,
+        test_ends_at)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+    "#,
+        issue_id,
+        issue.segment,
+        issue.subject,
+        issue.html_content,
+        issue.idempotency_key,
+        now,
+        test.map(|test| test.winner_metric.as_ref()),
+        test.map(|test| now + test.duration),
+    )
+    .execute(&mut *connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    if let Some(test) = test {
+        sqlx::query!(
+            r#"
+    INSERT INTO issue_subject_variants (issue_id, variant, subject)
+    SELECT $1, variant - 1, subject FROM UNNEST($2::text[]) WITH ORDINALITY AS v(subject, variant)
+    "#,
+            issue_id,
+            test.variants,
+        )
+        .execute(connection)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    }
+    Ok(issue_id)
+}
+
+/// Adds one delivery row per subscriber. Queued rows are picked up by the
+/// delivery worker; the others only record why nothing was sent. Rows of a
+/// subject test carry the variant their recipient was assigned.
+#[tracing::instrument(
+    name = "Record issue deliveries",
+    skip(connection, subscriber_ids),
+    fields(recipients = subscriber_ids.len())
+)]
+pub async fn insert_deliveries(
+    connection: &mut PgConnection,
+    issue_id: Uuid,
+    subscriber_ids: &[Uuid],
+    status: DeliveryStatus,
+    variant: Option<i16>,
+) -> Result<(), sqlx::Error> {
+    let now = Utc::now();
+    sqlx::query!(
+        r#"
+    INSERT INTO issue_deliveries
+        (issue_id, subscriber_id, status, queued_at, next_attempt_at, variant)
+    SELECT $1, subscriber_id, $3, $4, $4, $5 FROM UNNEST($2::uuid[]) AS subscriber_id
+    "#,
+        issue_id,
+        subscriber_ids,
+        status.as_ref(),
+        now,
+        variant,
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// How the recipients of an issue were split when it was sent.
+#[derive(Serialize, Debug)]
+pub struct IssueSummary {
+    pub issue_id: Uuid,
+    pub recipients: i64,
+    /// Recipients that get the issue now, whether or not the worker has
+    /// reached them yet.
+    pub queued: i64,
+    /// Recipients that get the issue once its subject test is decided.
+    pub held: i64,
+    pub suppressed: i64,
+    pub digest: i64,
+}
+
+#[tracing::instrument(name = "Summarize an issue", skip(connection))]
+pub async fn get_issue_summary(
+    connection: &mut PgConnection,
+    issue_id: Uuid,
+) -> Result<IssueSummary, sqlx::Error> {
+    let counts = sqlx::query!(
+        r#"
+    SELECT
+        COUNT(*) AS "recipients!",
+        COUNT(*) FILTER (WHERE status = 'held') AS "held!",
+        COUNT(*) FILTER (WHERE status = 'suppressed') AS "suppressed!",
+        COUNT(*) FILTER (WHERE status = 'digest') AS "digest!"
+    FROM issue_deliveries WHERE issue_id = $1
+    "#,
+        issue_id,
+    )
+    .fetch_one(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(IssueSummary {
+        issue_id,
+        recipients: counts.recipients,
+        queued: counts.recipients - counts.held - counts.suppressed - counts.digest,
+        held: counts.held,
+        suppressed: counts.suppressed,
+        digest: counts.digest,
+    })
+}
+
+/// How far the delivery worker has got with an issue. Held deliveries count
+/// as remaining; suppressed, skipped and digest ones are left out.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryProgress {
+    pub sent: i64,
+    pub failed: i64,
+    pub remaining: i64,
+}
+
+impl DeliveryProgress {
+    pub fn is_finished(&self) -> bool {
+        self.remaining == 0
+    }
+}
+
+/// `None` if there is no such issue.
+#[tracing::instrument(name = "Fetch the delivery progress of an issue", skip(connection))]
+pub async fn get_delivery_progress(
+    connection: &mut PgConnection,
+    issue_id: Uuid,
+) -> Result<Option<DeliveryProgress>, sqlx::Error> {
+    sqlx::query_as!(
+        DeliveryProgress,
+        r#"
+    SELECT
+        COUNT(d.subscriber_id) FILTER (WHERE d.status = 'sent') AS "sent!",
+        COUNT(d.subscriber_id) FILTER (WHERE d.status = 'failed') AS "failed!",
+        COUNT(d.subscriber_id) FILTER (WHERE d.status IN ('queued', 'held')) AS "remaining!"
+    FROM newsletter_issues i
+    LEFT JOIN issue_deliveries d ON d.issue_id = i.id
+    WHERE i.id = $1
+    GROUP BY i.id
+    "#,
+        issue_id,
+    )
+    .fetch_optional(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+/// A delivery the worker gave up on.
+#[derive(Serialize, Debug)]
+pub struct FailedDelivery {
+    pub issue_id: Uuid,
+    pub subscriber_id: Uuid,
+    pub subject: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub failed_at: Option<DateTime<Utc>>,
+}
+
+#[tracing::instrument(name = "List failed deliveries", skip(connection))]
+pub async fn get_failed_deliveries(
+    connection: &mut PgConnection,
+    issue_id: Option<Uuid>,
+) -> Result<Vec<FailedDelivery>, sqlx::Error> {
+    sqlx::query_as!(
+        FailedDelivery,
+        r#"
+    SELECT d.issue_id, d.subscriber_id, i.subject, d.attempts, d.last_error, d.failed_at
+    FROM issue_deliveries d
+    JOIN newsletter_issues i ON i.id = d.issue_id
+    WHERE d.status = 'failed' AND ($1::uuid IS NULL OR d.issue_id = $1)
+    ORDER BY d.failed_at, d.issue_id, d.subscriber_id
+    "#,
+        issue_id,
+    )
+    .fetch_all(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+/// Moves failed deliveries of an issue to `status`, all of them or only
+/// those of `subscriber_ids`. Requeued deliveries start over with a fresh
+/// attempt count. Returns how many deliveries were moved.
+#[tracing::instrument(name = "Resolve failed deliveries", skip(connection, subscriber_ids))]
+pub async fn resolve_failed_deliveries(
+    connection: &mut PgConnection,
+    issue_id: Uuid,
+    subscriber_ids: Option<&[Uuid]>,
+    status: DeliveryStatus,
+) -> Result<u64, sqlx::Error> {
+    let requeue = status == DeliveryStatus::Queued;
+    let result = sqlx::query!(
+        r#"
+    UPDATE issue_deliveries
+    SET status = $3,
+        attempts = CASE WHEN $4 THEN 0 ELSE attempts END,
+        next_attempt_at = CASE WHEN $4 THEN $5 ELSE next_attempt_at END
+    WHERE issue_id = $1 AND status = 'failed'
+        AND ($2::uuid[] IS NULL OR subscriber_id = ANY($2))
+    "#,
+        issue_id,
+        subscriber_ids,
+        status.as_ref(),
+        requeue,
+        Utc::now(),
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(result.rows_affected())
+}
@@ -1,6 +1,22 @@
+pub mod audit;
 pub mod configuration;
+pub mod consent;
+pub mod csv;
+pub mod digests;
 pub mod domain;
+pub mod email_backfill;
 pub mod email_client;
+pub mod erasure;
+pub mod hmac_secret;
+pub mod issue_delivery_worker;
+pub mod issues;
+pub mod mailing_lists;
+pub mod rate_limit;
+pub mod retention_worker;
 pub mod routes;
+pub mod segments;
 pub mod startup;
+pub mod subject_tests;
+pub mod suppressions;
 pub mod telemetry;
+pub mod token_cleanup_worker;
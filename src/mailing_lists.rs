@@ -0,0 +1,221 @@
+use sqlx::{types::chrono::Utc, PgConnection, PgPool};
+use uuid::Uuid;
+
+use crate::configuration::ListSettings;
+
+pub struct MailingList {
+    pub id: Uuid,
+    pub slug: String,
+    pub name: String,
+    pub sender_email: String,
+    pub welcome_subject: String,
+    pub welcome_body: String,
+}
+
+#[tracing::instrument(name = "Sync mailing lists from the configuration", skip(pool, lists))]
+pub async fn sync_lists(pool: &PgPool, lists: &[ListSettings]) -> Result<(), sqlx::Error> {
+    for list in lists {
+        sqlx::query!(
+            r#"
+    INSERT INTO lists (id, slug, name, sender_email, welcome_subject, welcome_body)
+    VALUES ($1, $2, $3, $4, $5, $6)
+    ON CONFLICT (slug) DO UPDATE
+    SET name = $3, sender_email = $4, welcome_subject = $5, welcome_body = $6
+    "#,
+            Uuid::new_v4(),
+            list.slug,
+            list.name,
+            list.sender_email,
+            list.welcome_subject,
+            list.welcome_body,
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    }
+    Ok(())
+}
+
+#[tracing::instrument(name = "Get mailing lists by slug", skip(connection))]
+pub async fn get_lists_by_slug(
+    connection: &mut PgConnection,
+    slugs: &[String],
+) -> Result<Vec<MailingList>, sqlx::Error> {
+    sqlx::query_as!(
+        MailingList,
+        r#"
+    SELECT id, slug, name, sender_email, welcome_subject, welcome_body
+    FROM lists WHERE slug = ANY($1) ORDER BY slug
+    "#,
+        slugs,
+    )
+    .fetch_all(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+#[tracing::instrument(name = "Get the pending lists of a subscriber", skip(connection))]
+pub async fn get_pending_lists(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+) -> Result<Vec<MailingList>, sqlx::Error> {
+    sqlx::query_as!(
+        MailingList,
+        r#"
+    SELECT l.id, l.slug, l.name, l.sender_email, l.welcome_subject, l.welcome_body
+    FROM lists l JOIN list_memberships m ON m.list_id = l.id
+    WHERE m.subscriber_id = $1 AND m.status = 'pending_confirmation'
+    ORDER BY l.slug
+    "#,
+        subscriber_id,
+    )
+    .fetch_all(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+#[tracing::instrument(name = "Get the lists of a subscriber", skip(connection))]
+pub async fn get_joined_lists(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+) -> Result<Vec<MailingList>, sqlx::Error> {
+    sqlx::query_as!(
+        MailingList,
+        r#"
+    SELECT l.id, l.slug, l.name, l.sender_email, l.welcome_subject, l.welcome_body
+    FROM lists l JOIN list_memberships m ON m.list_id = l.id
+    WHERE m.subscriber_id = $1 AND m.status <> 'unsubscribed'
+    ORDER BY l.slug
+    "#,
+        subscriber_id,
+    )
+    .fetch_all(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+#[tracing::instrument(
+    name = "Get the status of a list membership",
+    skip(connection, subscriber_id, list_id)
+)]
+pub async fn get_membership_status(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+) -> Result<Option<String>, sqlx::Error> {
+    Ok(sqlx::query!(
+        r#"
+    SELECT status FROM list_memberships
+    WHERE subscriber_id = $1 AND list_id = $2
+    FOR UPDATE
+    "#,
+        subscriber_id,
+        list_id,
+    )
+    .fetch_optional(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .map(|r| r.status))
+}
+
+/// Rejoining a list the subscriber left starts over as pending.
+#[tracing::instrument(
+    name = "Add a pending list membership",
+    skip(connection, subscriber_id, list_id)
+)]
+pub async fn insert_membership(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    INSERT INTO list_memberships (subscriber_id, list_id, status, subscribed_at)
+    VALUES ($1, $2, 'pending_confirmation', $3)
+    ON CONFLICT (subscriber_id, list_id) DO UPDATE
+    SET status = 'pending_confirmation', subscribed_at = $3, confirmed_at = NULL, left_at = NULL
+    WHERE list_memberships.status = 'unsubscribed'
+    "#,
+        subscriber_id,
+        list_id,
+        Utc::now(),
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Mark list membership as confirmed",
+    skip(connection, subscriber_id, list_id)
+)]
+pub async fn confirm_membership(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    UPDATE list_memberships SET status = 'confirmed', confirmed_at = $3
+    WHERE subscriber_id = $1 AND list_id = $2 AND status = 'pending_confirmation'
+    "#,
+        subscriber_id,
+        list_id,
+        Utc::now(),
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// Leaving one list keeps the subscriber on the newsletter and every other
+/// list. Returns whether the subscriber was on the list.
+#[tracing::instrument(
+    name = "Mark list membership as unsubscribed",
+    skip(connection, subscriber_id, list_id)
+)]
+pub async fn leave_membership(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+    UPDATE list_memberships SET status = 'unsubscribed', left_at = $3
+    WHERE subscriber_id = $1 AND list_id = $2 AND status <> 'unsubscribed'
+    "#,
+        subscriber_id,
+        list_id,
+        Utc::now(),
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(result.rows_affected() > 0)
+}
@@ -1,6 +1,12 @@
+use std::fmt::Display;
+
+use tokio::task::JoinError;
 use zero2prod::configuration::get_configuration;
+use zero2prod::issue_delivery_worker;
+use zero2prod::retention_worker;
 use zero2prod::startup::Application;
 use zero2prod::telemetry::{get_subscriber, init_subscriber};
+use zero2prod::token_cleanup_worker::run_worker_until_stopped;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
@@ -13,7 +19,29 @@ async fn main() -> std::io::Result<()> {
 
     let configuration = get_configuration().expect("Failed to read configuration.");
 
-    let application = Application::build(configuration).await?;
-    application.run_until_stopped().await?;
+    let application = Application::build(configuration.clone()).await?;
+    let application_task = tokio::spawn(application.run_until_stopped());
+    let worker_task = tokio::spawn(run_worker_until_stopped(configuration.clone()));
+    let retention_task = tokio::spawn(retention_worker::run_worker_until_stopped(
+        configuration.clone(),
+    ));
+    let delivery_task = tokio::spawn(issue_delivery_worker::run_worker_until_stopped(
+        configuration,
+    ));
+
+    tokio::select! {
+        o = application_task => report_exit("API", o),
+        o = worker_task => report_exit("Token cleanup worker", o),
+        o = retention_task => report_exit("Retention worker", o),
+        o = delivery_task => report_exit("Issue delivery worker", o),
+    };
     Ok(())
 }
+
+fn report_exit(task_name: &str, outcome: Result<Result<(), impl Display>, JoinError>) {
+    match outcome {
+        Ok(Ok(())) => tracing::info!("{} has exited", task_name),
+        Ok(Err(e)) => tracing::error!(error = %e, "{} failed", task_name),
+        Err(e) => tracing::error!(error = %e, "{} task failed to complete", task_name),
+    }
+}
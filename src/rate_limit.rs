@@ -0,0 +1,314 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::http::HeaderMap;
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    PgPool,
+};
+
+use crate::configuration::{RateLimitSettings, RateLimitStoreKind, TokenBucketSettings};
+
+/// The in-memory store never holds more buckets than this; the least
+/// recently used one makes room for a new one.
+const MAX_IN_MEMORY_BUCKETS: usize = 10_000;
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    store: RateLimitStore,
+    pub settings: RateLimitSettings,
+}
+
+#[derive(Clone)]
+enum RateLimitStore {
+    InMemory(Arc<Mutex<InMemoryBuckets>>),
+    Postgres(PgPool),
+}
+
+/// Buckets by key, with the order they were last used in so the least
+/// recently used one is found without scanning the map. Evicting a bucket
+/// only forgets tokens it was still missing, and the least recently used
+/// one has usually refilled already.
+struct InMemoryBuckets {
+    buckets: HashMap<String, (Bucket, u64)>,
+    last_used: BTreeMap<u64, String>,
+    next_use: u64,
+    capacity: usize,
+}
+
+impl InMemoryBuckets {
+    fn new(capacity: usize) -> Self {
+        Self {
+            buckets: HashMap::new(),
+            last_used: BTreeMap::new(),
+            next_use: 0,
+            capacity,
+        }
+    }
+
+    fn take(
+        &mut self,
+        key: &str,
+        settings: &TokenBucketSettings,
+        now: DateTime<Utc>,
+    ) -> Result<(), Duration> {
+        let use_id = self.next_use;
+        self.next_use += 1;
+        let (mut bucket, previous_use) = match self.buckets.remove(key) {
+            Some(entry) => entry,
+            None => {
+                if self.buckets.len() >= self.capacity {
+                    if let Some((_, evicted)) = self.last_used.pop_first() {
+                        self.buckets.remove(&evicted);
+                    }
+                }
+                (Bucket::full(settings, now), use_id)
+            }
+        };
+        self.last_used.remove(&previous_use);
+        self.last_used.insert(use_id, key.to_string());
+        let outcome = bucket.take(settings, now);
+        self.buckets.insert(key.to_string(), (bucket, use_id));
+        outcome
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Bucket {
+    tokens: f64,
+    updated_at: DateTime<Utc>,
+}
+
+impl Bucket {
+    fn full(settings: &TokenBucketSettings, now: DateTime<Utc>) -> Self {
+        Self {
+            tokens: settings.capacity as f64,
+            updated_at: now,
+        }
+    }
+
+    /// Refills the bucket up to `now` and takes a token out of it. Returns
+    /// how long to wait for the next token if the bucket is empty.
+    fn take(&mut self, settings: &TokenBucketSettings, now: DateTime<Utc>) -> Result<(), Duration> {
+        let refill_interval = settings.refill_interval().as_secs_f64();
+        let elapsed = (now - self.updated_at).num_milliseconds().max(0) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed / refill_interval).min(settings.capacity as f64);
+        self.updated_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) * refill_interval,
+            ))
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(settings: RateLimitSettings, pool: PgPool) -> Self {
+        let store = match settings.store {
+            RateLimitStoreKind::InMemory => RateLimitStore::InMemory(Arc::new(Mutex::new(
+                InMemoryBuckets::new(MAX_IN_MEMORY_BUCKETS),
+            ))),
+            RateLimitStoreKind::Postgres => RateLimitStore::Postgres(pool),
+        };
+        Self { store, settings }
+    }
+
+    /// Takes a token from the bucket identified by `key`. Returns
+    /// `Ok(Some(retry_after))` when the bucket is empty.
+    #[tracing::instrument(name = "Take a rate limit token", skip(self, settings))]
+    pub async fn take(
+        &self,
+        key: &str,
+        settings: &TokenBucketSettings,
+    ) -> Result<Option<Duration>, sqlx::Error> {
+        let now = Utc::now();
+        match &self.store {
+            RateLimitStore::InMemory(buckets) => {
+                Ok(buckets.lock().unwrap().take(key, settings, now).err())
+            }
+            RateLimitStore::Postgres(pool) => take_from_postgres(pool, key, settings, now).await,
+        }
+    }
+
+    /// The client address, read from `X-Forwarded-For` only when the request
+    /// came through one of the trusted proxies.
+    pub fn client_ip(&self, peer: SocketAddr, headers: &HeaderMap) -> IpAddr {
+        let trusted = &self.settings.trusted_proxies;
+        if !trusted.contains(&peer.ip()) {
+            return peer.ip();
+        }
+        let forwarded: Vec<IpAddr> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|value| value.trim().parse().ok())
+            .collect();
+        // Proxies append the address they saw, so the client is the last
+        // hop that is not one of our own proxies.
+        forwarded
+            .iter()
+            .rev()
+            .find(|ip| !trusted.contains(ip))
+            .or(forwarded.first())
+            .copied()
+            .unwrap_or(peer.ip())
+    }
+}
+
+/// The part of a client address that identifies the client for rate
+/// limiting. An IPv6 client usually controls a whole /64, so all of it
+/// shares one bucket.
+pub fn client_network(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & (u128::MAX << 64))),
+        },
+    }
+}
+
+async fn take_from_postgres(
+    pool: &PgPool,
+    key: &str,
+    settings: &TokenBucketSettings,
+    now: DateTime<Utc>,
+) -> Result<Option<Duration>, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    sqlx::query!(
+        r#"
+    INSERT INTO rate_limit_buckets (key, tokens, updated_at)
+    VALUES ($1, $2, $3)
+    ON CONFLICT (key) DO NOTHING
+    "#,
+        key,
+        settings.capacity as f64,
+        now,
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    let mut bucket = sqlx::query_as!(
+        Bucket,
+        r#"SELECT tokens, updated_at FROM rate_limit_buckets WHERE key = $1 FOR UPDATE"#,
+        key,
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    let retry_after = bucket.take(settings, now).err();
+    sqlx::query!(
+        r#"UPDATE rate_limit_buckets SET tokens = $2, updated_at = $3 WHERE key = $1"#,
+        key,
+        bucket.tokens,
+        bucket.updated_at,
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    transaction.commit().await?;
+    Ok(retry_after)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> TokenBucketSettings {
+        TokenBucketSettings {
+            capacity: 2,
+            refill_interval_seconds: 10,
+        }
+    }
+
+    #[test]
+    fn a_full_bucket_allows_a_burst_up_to_its_capacity() {
+        let now = Utc::now();
+        let mut bucket = Bucket::full(&settings(), now);
+        assert!(bucket.take(&settings(), now).is_ok());
+        assert!(bucket.take(&settings(), now).is_ok());
+        assert_eq!(bucket.take(&settings(), now), Err(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn tokens_are_refilled_over_time() {
+        let now = Utc::now();
+        let mut bucket = Bucket {
+            tokens: 0.0,
+            updated_at: now,
+        };
+        assert_eq!(
+            bucket.take(&settings(), now + Duration::from_secs(5)),
+            Err(Duration::from_secs(5))
+        );
+        assert!(bucket
+            .take(&settings(), now + Duration::from_secs(10))
+            .is_ok());
+    }
+
+    #[test]
+    fn buckets_never_hold_more_than_their_capacity() {
+        let now = Utc::now();
+        let mut bucket = Bucket::full(&settings(), now);
+        let later = now + Duration::from_secs(3600);
+        assert!(bucket.take(&settings(), later).is_ok());
+        assert_eq!(bucket.tokens, 1.0);
+    }
+
+    #[test]
+    fn the_least_recently_used_bucket_is_evicted_at_capacity() {
+        let now = Utc::now();
+        let mut buckets = InMemoryBuckets::new(2);
+        buckets.take("a", &settings(), now).unwrap();
+        buckets.take("b", &settings(), now).unwrap();
+        buckets.take("a", &settings(), now).unwrap();
+
+        buckets.take("c", &settings(), now).unwrap();
+
+        assert_eq!(buckets.buckets.len(), 2);
+        assert_eq!(buckets.last_used.len(), 2);
+        assert!(!buckets.buckets.contains_key("b"));
+        // "a" kept its state: it has no tokens left.
+        assert!(buckets.take("a", &settings(), now).is_err());
+    }
+
+    #[test]
+    fn ipv6_clients_are_grouped_by_their_64() {
+        let first: IpAddr = "2001:db8:1:2:aaaa::1".parse().unwrap();
+        let second: IpAddr = "2001:db8:1:2:bbbb::2".parse().unwrap();
+        let other: IpAddr = "2001:db8:1:3::1".parse().unwrap();
+
+        assert_eq!(
+            client_network(first),
+            "2001:db8:1:2::".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(client_network(first), client_network(second));
+        assert_ne!(client_network(first), client_network(other));
+    }
+
+    #[test]
+    fn ipv4_clients_keep_their_address() {
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let mapped: IpAddr = "::ffff:203.0.113.7".parse().unwrap();
+
+        assert_eq!(client_network(ip), ip);
+        assert_eq!(client_network(mapped), ip);
+    }
+}
@@ -0,0 +1,160 @@
+use std::time::Duration;
+
+use sqlx::{types::chrono::Utc, PgConnection, PgPool};
+use uuid::Uuid;
+
+use crate::{
+    configuration::{RetentionMode, RetentionSettings, Settings},
+    erasure::anonymize_subscribers,
+    startup::get_connection_pool,
+};
+
+pub async fn run_worker_until_stopped(configuration: Settings) -> Result<(), std::io::Error> {
+    let pool = get_connection_pool(&configuration.database);
+    worker_loop(pool, configuration.retention).await
+}
+
+async fn worker_loop(pool: PgPool, settings: RetentionSettings) -> Result<(), std::io::Error> {
+    let mut interval = tokio::time::interval(settings.interval());
+    loop {
+        interval.tick().await;
+        // A failed run is retried on the next tick.
+        let _ = purge_stale_subscribers(&pool, &settings).await;
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PurgeSummary {
+    pub subscribers: u64,
+    pub tokens: u64,
+}
+
+/// Deletes or anonymizes subscribers that have been pending for longer than
+/// the configured age. In dry-run mode nothing is changed, but the summary
+/// still reports what would have been purged.
+#[tracing::instrument(
+    name = "Purge stale unconfirmed subscribers",
+    skip(pool, settings),
+    fields(mode = ?settings.mode, dry_run = settings.dry_run)
+)]
+pub async fn purge_stale_subscribers(
+    pool: &PgPool,
+    settings: &RetentionSettings,
+) -> Result<PurgeSummary, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let subscriber_ids =
+        get_stale_subscriber_ids(&mut transaction, settings.pending_max_age()).await?;
+    let summary = PurgeSummary {
+        subscribers: subscriber_ids.len() as u64,
+        tokens: count_tokens(&mut transaction, &subscriber_ids).await?,
+    };
+    if !settings.dry_run {
+        match settings.mode {
+            // Tokens, sessions and memberships go with the row.
+            RetentionMode::Delete => delete_subscribers(&mut transaction, &subscriber_ids).await?,
+            RetentionMode::Anonymize => {
+                anonymize_stale_subscribers(&mut transaction, &subscriber_ids).await?
+            }
+        }
+    }
+    transaction.commit().await?;
+    if settings.dry_run {
+        tracing::info!(
+            subscribers = summary.subscribers,
+            tokens = summary.tokens,
+            "Would purge stale unconfirmed subscribers"
+        );
+    } else {
+        tracing::info!(
+            subscribers = summary.subscribers,
+            tokens = summary.tokens,
+            "Purged stale unconfirmed subscribers"
+        );
+    }
+    Ok(summary)
+}
+
+// Age is counted from the latest confirmation email, so a subscriber who
+// just asked for a resend keeps the time to use the new link. Subscribers
+// who confirmed a list are not stale, even if the newsletter is pending.
+async fn get_stale_subscriber_ids(
+    connection: &mut PgConnection,
+    max_age: Duration,
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    Ok(sqlx::query!(
+        r#"
+    SELECT s.id FROM subscriptions s
+    WHERE s.status = 'pending_confirmation' AND s.email_verified_at IS NULL
+        AND s.subscribed_at <= $1
+        AND NOT EXISTS (
+            SELECT 1 FROM subscription_tokens t
+            WHERE t.subscriber_id = s.id AND t.purpose = 'confirmation' AND t.created_at > $1
+        )
+    FOR UPDATE OF s SKIP LOCKED
+    "#,
+        Utc::now() - max_age,
+    )
+    .fetch_all(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .into_iter()
+    .map(|r| r.id)
+    .collect())
+}
+
+async fn count_tokens(
+    connection: &mut PgConnection,
+    subscriber_ids: &[Uuid],
+) -> Result<u64, sqlx::Error> {
+    Ok(sqlx::query!(
+        r#"SELECT COUNT(*) AS "count!" FROM subscription_tokens WHERE subscriber_id = ANY($1)"#,
+        subscriber_ids,
+    )
+    .fetch_one(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .count as u64)
+}
+
+async fn delete_subscribers(
+    connection: &mut PgConnection,
+    subscriber_ids: &[Uuid],
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"DELETE FROM subscriptions WHERE id = ANY($1)"#,
+        subscriber_ids,
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+// Keeps the row, and with it the signup date, for statistics. Memberships
+// were never confirmed, so they go as well.
+async fn anonymize_stale_subscribers(
+    connection: &mut PgConnection,
+    subscriber_ids: &[Uuid],
+) -> Result<(), sqlx::Error> {
+    anonymize_subscribers(connection, subscriber_ids, "anonymized").await?;
+    sqlx::query!(
+        r#"DELETE FROM list_memberships WHERE subscriber_id = ANY($1)"#,
+        subscriber_ids,
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
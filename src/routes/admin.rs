@@ -0,0 +1,251 @@
+use axum::{
+    async_trait,
+    extract::{Form, FromRequestParts, Query, State},
+    http::{header::AUTHORIZATION, request::Parts, StatusCode},
+    response::IntoResponse,
+};
+use secrecy::ExposeSecret;
+use serde::Deserialize;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use super::{build_subscriber_export, export_response, get_subscriber_by_email};
+use crate::{
+    audit::record_audit_event,
+    domain::{SubscriberEmail, SubscriberTag},
+    erasure::erase_subscriber,
+    startup::ApplicationState,
+};
+
+/// Guards the admin endpoints with the `Authorization: Bearer` token from
+/// `application.admin_token`. Without a configured token they are closed.
+pub struct AdminAuth;
+
+#[async_trait]
+impl FromRequestParts<ApplicationState> for AdminAuth {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &ApplicationState,
+    ) -> Result<Self, Self::Rejection> {
+        let expected = state.admin_token.as_ref().ok_or(StatusCode::UNAUTHORIZED)?;
+        let provided = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+        // Comparing MACs instead of the tokens themselves keeps the
+        // comparison constant time.
+        let signature = state.hmac_secret.sign(expected.expose_secret());
+        if !state.hmac_secret.verify(provided, &signature) {
+            tracing::warn!("Rejected an admin request with a wrong token");
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        Ok(Self)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AdminExportParameters {
+    pub email: String,
+}
+
+#[tracing::instrument(name = "Export a subscriber's data", skip(parameters, state))]
+pub async fn admin_export_subscriber(
+    _: AdminAuth,
+    State(state): State<ApplicationState>,
+    parameters: Query<AdminExportParameters>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let email = SubscriberEmail::parse(parameters.0.email).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let mut transaction = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let subscriber = get_subscriber_by_email(&mut transaction, &email)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let export = build_subscriber_export(&mut transaction, subscriber.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if transaction.commit().await.is_err() {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    Ok(export_response(export))
+}
+
+#[derive(Deserialize)]
+pub struct AdminEraseFormData {
+    email: String,
+}
+
+#[tracing::instrument(name = "Erase a subscriber's personal data", skip(form_data, state))]
+pub async fn admin_erase_subscriber(
+    _: AdminAuth,
+    State(state): State<ApplicationState>,
+    Form(form_data): Form<AdminEraseFormData>,
+) -> Result<(), StatusCode> {
+    let email = SubscriberEmail::parse(form_data.email).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let mut transaction = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let subscriber = get_subscriber_by_email(&mut transaction, &email)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    // The request is logged outside the erasure transaction, so it is on
+    // record even if the erasure itself fails.
+    let mut connection = state
+        .pool
+        .acquire()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    record_audit_event(
+        &mut connection,
+        "admin",
+        "erasure_requested",
+        Some(subscriber.id),
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    erase_subscriber(
+        &mut transaction,
+        &state.hmac_secret,
+        subscriber.id,
+        &email,
+        "admin",
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if transaction.commit().await.is_err() {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct AdminTagFormData {
+    email: String,
+    /// Repeated once per tag.
+    #[serde(default)]
+    tag: Vec<String>,
+}
+
+impl AdminTagFormData {
+    fn parse(self) -> Result<(SubscriberEmail, Vec<SubscriberTag>), StatusCode> {
+        let email = SubscriberEmail::parse(self.email).map_err(|_| StatusCode::BAD_REQUEST)?;
+        let tags = self
+            .tag
+            .into_iter()
+            .map(SubscriberTag::parse)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        Ok((email, tags))
+    }
+}
+
+#[tracing::instrument(name = "Tag a subscriber", skip(form_data, state))]
+pub async fn admin_tag_subscriber(
+    _: AdminAuth,
+    State(state): State<ApplicationState>,
+    axum_extra::extract::Form(form_data): axum_extra::extract::Form<AdminTagFormData>,
+) -> Result<(), StatusCode> {
+    let (email, tags) = form_data.parse()?;
+    let mut transaction = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let subscriber = get_subscriber_by_email(&mut transaction, &email)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    add_tags(&mut transaction, subscriber.id, &tags)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if transaction.commit().await.is_err() {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    Ok(())
+}
+
+#[tracing::instrument(name = "Untag a subscriber", skip(form_data, state))]
+pub async fn admin_untag_subscriber(
+    _: AdminAuth,
+    State(state): State<ApplicationState>,
+    axum_extra::extract::Form(form_data): axum_extra::extract::Form<AdminTagFormData>,
+) -> Result<(), StatusCode> {
+    let (email, tags) = form_data.parse()?;
+    let mut transaction = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let subscriber = get_subscriber_by_email(&mut transaction, &email)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    remove_tags(&mut transaction, subscriber.id, &tags)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if transaction.commit().await.is_err() {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    Ok(())
+}
+
+#[tracing::instrument(name = "Add tags to a subscriber", skip(connection, tags))]
+async fn add_tags(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    tags: &[SubscriberTag],
+) -> Result<(), sqlx::Error> {
+    let tags: Vec<String> = tags.iter().map(|tag| tag.as_ref().to_string()).collect();
+    sqlx::query!(
+        r#"
+    UPDATE subscriptions
+    SET tags = ARRAY(SELECT DISTINCT unnest(tags || $2::text[]) ORDER BY 1)
+    WHERE id = $1
+    "#,
+        subscriber_id,
+        &tags,
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Remove tags from a subscriber", skip(connection, tags))]
+async fn remove_tags(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    tags: &[SubscriberTag],
+) -> Result<(), sqlx::Error> {
+    let tags: Vec<String> = tags.iter().map(|tag| tag.as_ref().to_string()).collect();
+    sqlx::query!(
+        r#"
+    UPDATE subscriptions
+    SET tags = ARRAY(SELECT tag FROM unnest(tags) AS tag WHERE tag <> ALL($2) ORDER BY 1)
+    WHERE id = $1
+    "#,
+        subscriber_id,
+        &tags,
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+use uuid::Uuid;
+
+use super::{
+    confirm_subscriber, generate_subscription_token, get_subscriber_by_email, insert_subscriber,
+    send_confirmation_email, store_token, unsubscribe_link, AdminAuth, TokenPurpose,
+};
+use crate::{
+    consent::record_import_consent,
+    csv::{Record, RecordReader},
+    domain::{NewSubscriber, SubscriberAttributes, SubscriberEmail, SubscriberName},
+    startup::ApplicationState,
+    suppressions::is_suppressed,
+};
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    /// The rows are already confirmed elsewhere; requires an attestation.
+    #[serde(rename = "confirmed")]
+    Confirmed,
+    /// Every row gets a confirmation email, as if it had signed up.
+    #[serde(rename = "send_confirmation")]
+    SendConfirmation,
+}
+
+#[derive(Deserialize)]
+pub struct ImportParameters {
+    mode: ImportMode,
+    /// How consent was collected for rows imported as confirmed, e.g.
+    /// "Double opt-in through our previous provider".
+    attestation: Option<String>,
+}
+
+#[derive(Serialize, Default)]
+pub struct ImportReport {
+    accepted: u64,
+    skipped: u64,
+    rejected: u64,
+    rows: Vec<RowReport>,
+    /// Set when the upload broke off; the rows reported were imported.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct RowReport {
+    /// 1-based, counting the header.
+    row: u64,
+    email: Option<String>,
+    outcome: RowOutcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub enum RowOutcome {
+    #[serde(rename = "accepted")]
+    Accepted,
+    #[serde(rename = "skipped")]
+    Skipped,
+    #[serde(rename = "rejected")]
+    Rejected,
+}
+
+struct Columns {
+    email: usize,
+    name: usize,
+    /// Every other column, offered to the custom attribute schema.
+    others: Vec<(usize, String)>,
+}
+
+impl Columns {
+    fn parse(header: &[String]) -> Option<Self> {
+        let position = |name: &str| header.iter().position(|h| h.trim() == name);
+        let (email, name) = (position("email")?, position("name")?);
+        let others = header
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != email && *i != name)
+            .map(|(i, h)| (i, h.trim().to_string()))
+            .collect();
+        Some(Self {
+            email,
+            name,
+            others,
+        })
+    }
+}
+
+/// Imports subscribers from a CSV upload with `email` and `name` columns.
+/// Each row is committed on its own, so one bad row does not undo the rest.
+/// Confirmation emails go out in the background once the import is done.
+/// If the upload breaks off, the report of the rows imported so far comes
+/// back with a 400 and an `error`.
+#[tracing::instrument(name = "Import subscribers", skip(parameters, state, body))]
+pub async fn admin_import_subscribers(
+    _: AdminAuth,
+    State(state): State<ApplicationState>,
+    Query(parameters): Query<ImportParameters>,
+    body: Body,
+) -> Result<(StatusCode, Json<ImportReport>), StatusCode> {
+    let attestation = parameters
+        .attestation
+        .map(|attestation| attestation.trim().to_string())
+        .filter(|attestation| !attestation.is_empty());
+    if parameters.mode == ImportMode::Confirmed && attestation.is_none() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let mut importer = Importer {
+        state: &state,
+        mode: parameters.mode,
+        attestation: attestation.as_deref(),
+        columns: None,
+        seen: HashMap::new(),
+        next_row: 1,
+        report: ImportReport::default(),
+        confirmations: Vec::new(),
+    };
+    let result = importer.read(body).await;
+    // Committed rows get their email even if a later part of the upload
+    // failed.
+    let confirmations = std::mem::take(&mut importer.confirmations);
+    if !confirmations.is_empty() {
+        tokio::spawn(send_confirmations(state.clone(), confirmations).in_current_span());
+    }
+    result?;
+    let status = if importer.report.error.is_some() {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::OK
+    };
+    tracing::info!(
+        accepted = importer.report.accepted,
+        skipped = importer.report.skipped,
+        rejected = importer.report.rejected,
+        "Imported subscribers"
+    );
+    Ok((status, Json(importer.report)))
+}
+
+/// A confirmation email owed to an imported subscriber.
+struct Confirmation {
+    subscriber_id: Uuid,
+    email: SubscriberEmail,
+    subscription_token: String,
+}
+
+async fn send_confirmations(state: ApplicationState, confirmations: Vec<Confirmation>) {
+    for confirmation in confirmations {
+        let unsubscribe_link = unsubscribe_link(
+            &state.base_url,
+            &state.hmac_secret,
+            confirmation.subscriber_id,
+        );
+        // The subscriber can ask for the email again.
+        if let Err(e) = send_confirmation_email(
+            &state.email_client,
+            confirmation.email,
+            state.base_url.clone(),
+            &confirmation.subscription_token,
+            &unsubscribe_link,
+        )
+        .await
+        {
+            tracing::error!(
+                subscriber_id = %confirmation.subscriber_id,
+                "Failed to send an import confirmation email: {}",
+                e
+            );
+        }
+    }
+}
+
+struct Importer<'a> {
+    state: &'a ApplicationState,
+    mode: ImportMode,
+    attestation: Option<&'a str>,
+    columns: Option<Columns>,
+    /// Addresses already imported from this file, by row.
+    seen: HashMap<String, u64>,
+    next_row: u64,
+    report: ImportReport,
+    confirmations: Vec<Confirmation>,
+}
+
+impl Importer<'_> {
+    async fn read(&mut self, body: Body) -> Result<(), StatusCode> {
+        let mut reader = RecordReader::default();
+        let mut stream = body.into_data_stream();
+        while let Some(chunk) = stream.next().await {
+            let Ok(chunk) = chunk else {
+                // Without a header nothing was imported.
+                if self.columns.is_none() {
+                    return Err(StatusCode::BAD_REQUEST);
+                }
+                self.report.error = Some(format!(
+                    "The upload broke off after row {}; later rows were not imported.",
+                    self.next_row - 1
+                ));
+                return Ok(());
+            };
+            for record in reader.feed(&chunk) {
+                self.import(record).await?;
+            }
+        }
+        for record in reader.finish() {
+            self.import(record).await?;
+        }
+        if self.columns.is_none() {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        Ok(())
+    }
+
+    async fn import(&mut self, record: Record) -> Result<(), StatusCode> {
+        let row = self.next_row;
+        self.next_row += 1;
+        let Some(columns) = &self.columns else {
+            let header = record.map_err(|_| StatusCode::BAD_REQUEST)?;
+            self.columns = Some(Columns::parse(&header).ok_or(StatusCode::BAD_REQUEST)?);
+            return Ok(());
+        };
+        let (email, outcome, reason) = match record {
+            Err(_) => (
+                None,
+                RowOutcome::Rejected,
+                Some("The row is not valid UTF-8.".to_string()),
+            ),
+            Ok(fields) => {
+                let email = fields.get(columns.email).map(|e| e.trim().to_string());
+                let (outcome, reason) = match self.import_fields(row, &fields).await {
+                    Ok(()) => (RowOutcome::Accepted, None),
+                    Err((outcome, reason)) => (outcome, Some(reason)),
+                };
+                (email, outcome, reason)
+            }
+        };
+        match outcome {
+            RowOutcome::Accepted => self.report.accepted += 1,
+            RowOutcome::Skipped => self.report.skipped += 1,
+            RowOutcome::Rejected => self.report.rejected += 1,
+        }
+        self.report.rows.push(RowReport {
+            row,
+            email,
+            outcome,
+            reason,
+        });
+        Ok(())
+    }
+
+    async fn import_fields(
+        &mut self,
+        row: u64,
+        fields: &[String],
+    ) -> Result<(), (RowOutcome, String)> {
+        let columns = self.columns.as_ref().expect("The header is read first");
+        let field = |i: usize| {
+            fields
+                .get(i)
+                .map(|f| f.trim().to_string())
+                .unwrap_or_default()
+        };
+        let values: HashMap<_, _> = columns
+            .others
+            .iter()
+            .map(|(i, name)| (name.clone(), serde_json::Value::String(field(*i))))
+            .collect();
+        let email = SubscriberEmail::parse(field(columns.email));
+        let name = SubscriberName::parse(field(columns.name));
+        let attributes = SubscriberAttributes::parse(&values, &self.state.attributes);
+        let new_subscriber = match (email, name, attributes) {
+            (Ok(email), Ok(name), Ok(attributes)) => NewSubscriber {
+                email,
+                name,
+                delivery_frequency: Default::default(),
+                attributes,
+            },
+            (email, name, attributes) => {
+                let reasons: Vec<String> = [email.err(), name.err()]
+                    .into_iter()
+                    .flatten()
+                    .chain(attributes.err().into_iter().flatten().map(|e| e.message))
+                    .collect();
+                return Err((RowOutcome::Rejected, reasons.join(" ")));
+            }
+        };
+        let key = new_subscriber.email.as_ref().to_lowercase();
+        if let Some(first_row) = self.seen.get(&key) {
+            return Err((
+                RowOutcome::Skipped,
+                format!("The address is a duplicate of row {}.", first_row),
+            ));
+        }
+        self.seen.insert(key, row);
+        self.insert(new_subscriber).await
+    }
+
+    async fn insert(&mut self, new_subscriber: NewSubscriber) -> Result<(), (RowOutcome, String)> {
+        let state = self.state;
+        let unexpected = |_| {
+            (
+                RowOutcome::Rejected,
+                "An unexpected error occurred.".to_string(),
+            )
+        };
+        let mut transaction = state.pool.begin().await.map_err(unexpected)?;
+        if is_suppressed(&mut transaction, &state.hmac_secret, &new_subscriber.email)
+            .await
+            .map_err(unexpected)?
+        {
+            return Err((
+                RowOutcome::Skipped,
+                "The address is suppressed.".to_string(),
+            ));
+        }
+        if let Some(existing) = get_subscriber_by_email(&mut transaction, &new_subscriber.email)
+            .await
+            .map_err(unexpected)?
+        {
+            return Err((
+                RowOutcome::Skipped,
+                format!(
+                    "The address is already {}.",
+                    existing.status.replace('_', " ")
+                ),
+            ));
+        }
+        let Some(subscriber_id) = insert_subscriber(&mut transaction, &new_subscriber)
+            .await
+            .map_err(unexpected)?
+        else {
+            return Err((
+                RowOutcome::Skipped,
+                "The address was added while importing.".to_string(),
+            ));
+        };
+        let confirmation = match self.mode {
+            ImportMode::Confirmed => {
+                confirm_subscriber(&mut transaction, subscriber_id)
+                    .await
+                    .map_err(unexpected)?;
+                None
+            }
+            ImportMode::SendConfirmation => {
+                let subscription_token = generate_subscription_token();
+                store_token(
+                    &mut transaction,
+                    subscriber_id,
+                    &subscription_token,
+                    TokenPurpose::Confirmation,
+                    &state.hmac_secret,
+                    state.subscription_token_expiry,
+                )
+                .await
+                .map_err(unexpected)?;
+                Some(Confirmation {
+                    subscriber_id,
+                    email: new_subscriber.email,
+                    subscription_token,
+                })
+            }
+        };
+        record_import_consent(&mut transaction, subscriber_id, self.attestation)
+            .await
+            .map_err(unexpected)?;
+        transaction.commit().await.map_err(unexpected)?;
+        self.confirmations.extend(confirmation);
+        Ok(())
+    }
+}
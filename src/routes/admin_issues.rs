@@ -0,0 +1,187 @@
+use std::{convert::Infallible, time::Duration};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Json,
+};
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::AdminAuth;
+use crate::{
+    issues::{
+        get_delivery_progress, get_failed_deliveries, resolve_failed_deliveries, DeliveryProgress,
+        DeliveryStatus, FailedDelivery,
+    },
+    startup::ApplicationState,
+};
+
+/// How often the stream checks on the delivery worker.
+const PROGRESS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Streams the delivery progress of an issue as Server-Sent Events. A
+/// `progress` event goes out whenever the worker's counts change, and a
+/// final `complete` event once nothing is left to send, after which the
+/// stream ends. Event ids are the counts themselves, so a client that
+/// reconnects with `Last-Event-ID` only hears about changes it missed; one
+/// that already saw the final event gets a 204, which tells browsers to stop
+/// reconnecting.
+#[tracing::instrument(
+    name = "Stream the delivery progress of an issue",
+    skip(state, headers)
+)]
+pub async fn issue_progress(
+    _: AdminAuth,
+    State(state): State<ApplicationState>,
+    Path(issue_id): Path<Uuid>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let last_event_id = headers
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let mut connection = state
+        .pool
+        .acquire()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let progress = get_delivery_progress(&mut connection, issue_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    drop(connection);
+    if progress.is_finished() && last_event_id.as_deref() == Some(&event_id(&progress)) {
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    }
+    let events = stream::unfold(Some((state.pool, last_event_id)), move |watch| async move {
+        let (pool, last_event_id) = watch?;
+        let progress = next_progress(&pool, issue_id, last_event_id.as_deref()).await?;
+        let id = event_id(&progress);
+        let event = Event::default()
+            .event(if progress.is_finished() {
+                "complete"
+            } else {
+                "progress"
+            })
+            .id(&id)
+            .json_data(&progress)
+            .expect("Progress always serializes");
+        let next = (!progress.is_finished()).then_some((pool, Some(id)));
+        Some((Ok::<_, Infallible>(event), next))
+    });
+    Ok(Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+/// Waits until the progress differs from the event the client saw last, or
+/// the delivery is finished. `None` ends the stream on a database error.
+async fn next_progress(
+    pool: &PgPool,
+    issue_id: Uuid,
+    last_event_id: Option<&str>,
+) -> Option<DeliveryProgress> {
+    loop {
+        let mut connection = pool.acquire().await.ok()?;
+        let progress = get_delivery_progress(&mut connection, issue_id)
+            .await
+            .ok()
+            .flatten()?;
+        drop(connection);
+        if progress.is_finished() || last_event_id != Some(event_id(&progress).as_str()) {
+            return Some(progress);
+        }
+        tokio::time::sleep(PROGRESS_POLL_INTERVAL).await;
+    }
+}
+
+fn event_id(progress: &DeliveryProgress) -> String {
+    format!(
+        "{}-{}-{}",
+        progress.sent, progress.failed, progress.remaining
+    )
+}
+
+#[derive(Deserialize)]
+pub struct FailedDeliveriesParameters {
+    issue_id: Option<Uuid>,
+}
+
+/// Lists the deliveries the worker gave up on, oldest failure first.
+#[tracing::instrument(name = "List failed deliveries", skip(state, parameters))]
+pub async fn admin_failed_deliveries(
+    _: AdminAuth,
+    State(state): State<ApplicationState>,
+    Query(parameters): Query<FailedDeliveriesParameters>,
+) -> Result<Json<Vec<FailedDelivery>>, StatusCode> {
+    let mut connection = state
+        .pool
+        .acquire()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let deliveries = get_failed_deliveries(&mut connection, parameters.issue_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(deliveries))
+}
+
+/// Picks failed deliveries of one issue: all of them, or only those of
+/// `subscriber_ids`.
+#[derive(Deserialize)]
+pub struct FailedDeliverySelection {
+    issue_id: Uuid,
+    subscriber_ids: Option<Vec<Uuid>>,
+}
+
+#[derive(Serialize)]
+pub struct FailedDeliveryReport {
+    deliveries: u64,
+}
+
+/// Gives failed deliveries a fresh set of attempts.
+#[tracing::instrument(name = "Requeue failed deliveries", skip(state, selection))]
+pub async fn admin_requeue_deliveries(
+    _: AdminAuth,
+    State(state): State<ApplicationState>,
+    Json(selection): Json<FailedDeliverySelection>,
+) -> Result<Json<FailedDeliveryReport>, StatusCode> {
+    resolve_deliveries(&state, selection, DeliveryStatus::Queued).await
+}
+
+/// Gives up on failed deliveries for good.
+#[tracing::instrument(name = "Discard failed deliveries", skip(state, selection))]
+pub async fn admin_discard_deliveries(
+    _: AdminAuth,
+    State(state): State<ApplicationState>,
+    Json(selection): Json<FailedDeliverySelection>,
+) -> Result<Json<FailedDeliveryReport>, StatusCode> {
+    resolve_deliveries(&state, selection, DeliveryStatus::Discarded).await
+}
+
+async fn resolve_deliveries(
+    state: &ApplicationState,
+    selection: FailedDeliverySelection,
+    status: DeliveryStatus,
+) -> Result<Json<FailedDeliveryReport>, StatusCode> {
+    let mut connection = state
+        .pool
+        .acquire()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let deliveries = resolve_failed_deliveries(
+        &mut connection,
+        selection.issue_id,
+        selection.subscriber_ids.as_deref(),
+        status,
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(FailedDeliveryReport { deliveries }))
+}
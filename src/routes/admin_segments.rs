@@ -0,0 +1,271 @@
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use super::AdminAuth;
+use crate::{
+    domain::{DeliveryFrequency, SubscriberEmail},
+    issues::{
+        get_issue_id_by_idempotency_key, get_issue_summary, insert_deliveries, insert_issue,
+        DeliveryStatus, IssueSummary, NewIssue, SubjectTest,
+    },
+    segments::{
+        count_segment, delete_segment, get_segment_filter, get_segment_recipients, save_segment,
+        SegmentFilter, SegmentSize,
+    },
+    startup::ApplicationState,
+    subject_tests::WinnerMetric,
+    suppressions::{get_suppressed_keys, suppression_keys},
+};
+
+/// Including `subject`.
+const MAX_SUBJECT_VARIANTS: usize = 5;
+
+#[tracing::instrument(name = "Save a segment", skip(filter, state))]
+pub async fn admin_save_segment(
+    _: AdminAuth,
+    State(state): State<ApplicationState>,
+    Path(name): Path<String>,
+    Json(filter): Json<SegmentFilter>,
+) -> Result<(), StatusCode> {
+    if name.trim().is_empty() || name.len() > 64 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    filter.validate().map_err(|_| StatusCode::BAD_REQUEST)?;
+    let mut connection = state
+        .pool
+        .acquire()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    save_segment(&mut connection, &name, &filter)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[tracing::instrument(name = "Delete a segment", skip(state))]
+pub async fn admin_delete_segment(
+    _: AdminAuth,
+    State(state): State<ApplicationState>,
+    Path(name): Path<String>,
+) -> Result<(), StatusCode> {
+    let mut connection = state
+        .pool
+        .acquire()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !delete_segment(&mut connection, &name)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(())
+}
+
+#[tracing::instrument(name = "Preview the size of a segment", skip(state))]
+pub async fn admin_preview_segment(
+    _: AdminAuth,
+    State(state): State<ApplicationState>,
+    Path(name): Path<String>,
+) -> Result<Json<SegmentSize>, StatusCode> {
+    let mut connection = state
+        .pool
+        .acquire()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let filter = get_segment_filter(&mut connection, &name)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let size = count_segment(&mut connection, &filter)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(size))
+}
+
+#[derive(Deserialize)]
+pub struct IssueData {
+    subject: String,
+    html_content: String,
+    /// Further subjects to test against `subject` on a sample of the
+    /// recipients before the issue goes out to the rest of the segment.
+    #[serde(default)]
+    subject_variants: Vec<String>,
+    #[serde(default)]
+    winner_metric: WinnerMetric,
+}
+
+impl IssueData {
+    /// Every subject to send, `subject` first.
+    fn subjects(&self) -> Result<Vec<String>, String> {
+        let subjects: Vec<String> = std::iter::once(&self.subject)
+            .chain(&self.subject_variants)
+            .map(|subject| subject.trim().to_string())
+            .collect();
+        if subjects.iter().any(|subject| subject.is_empty()) {
+            return Err("Subjects cannot be empty.".to_string());
+        }
+        if subjects.len() > MAX_SUBJECT_VARIANTS {
+            return Err(format!(
+                "At most {} subjects can be tested.",
+                MAX_SUBJECT_VARIANTS
+            ));
+        }
+        if (1..subjects.len()).any(|i| subjects[..i].contains(&subjects[i])) {
+            return Err("Subject variants must differ.".to_string());
+        }
+        Ok(subjects)
+    }
+}
+
+/// Queues an issue for the confirmed subscribers the segment matches at the
+/// time of sending, so subscribers who joined or were tagged after the
+/// segment was saved are included. The issue and one delivery row per
+/// recipient are stored together; the delivery worker does the mailing.
+/// Subscribers who asked for a weekly digest are held back.
+///
+/// With `subject_variants`, a random sample of the recipients is split
+/// between the subjects and the rest is held until the delivery worker picks
+/// the variant with the best open or click rate.
+///
+/// A retry with the same `Idempotency-Key` header gets the summary of the
+/// first request instead of queueing the issue again.
+#[tracing::instrument(name = "Send an issue to a segment", skip(issue, state, headers))]
+pub async fn admin_send_issue(
+    _: AdminAuth,
+    State(state): State<ApplicationState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(issue): Json<IssueData>,
+) -> Result<(StatusCode, Json<IssueSummary>), StatusCode> {
+    let subjects = issue.subjects().map_err(|_| StatusCode::BAD_REQUEST)?;
+    let idempotency_key = match headers.get("Idempotency-Key") {
+        None => None,
+        Some(value) => {
+            let key = value.to_str().map_err(|_| StatusCode::BAD_REQUEST)?.trim();
+            if key.is_empty() || key.len() > 256 {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            Some(key)
+        }
+    };
+    let mut transaction = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if let Some(key) = idempotency_key {
+        if let Some(issue_id) = get_issue_id_by_idempotency_key(&mut transaction, key)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        {
+            let summary = get_issue_summary(&mut transaction, issue_id)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            return Ok((StatusCode::OK, Json(summary)));
+        }
+    }
+    let filter = get_segment_filter(&mut transaction, &name)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let recipients = get_segment_recipients(&mut transaction, &filter)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let issue_id = insert_issue(
+        &mut transaction,
+        &NewIssue {
+            segment: &name,
+            subject: &issue.subject,
+            html_content: &issue.html_content,
+            idempotency_key,
+            subject_test: (subjects.len() > 1).then(|| SubjectTest {
+                variants: &subjects,
+                winner_metric: issue.winner_metric,
+                duration: state.subject_tests.wait(),
+            }),
+        },
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut digest = vec![];
+    let mut immediate = vec![];
+    for recipient in recipients {
+        if recipient.delivery_frequency != DeliveryFrequency::Immediate.as_ref() {
+            digest.push(recipient.id);
+            continue;
+        }
+        let Ok(email) = SubscriberEmail::parse(recipient.email) else {
+            tracing::warn!(subscriber_id = %recipient.id, "Skipped a recipient with an invalid address");
+            continue;
+        };
+        immediate.push((recipient.id, suppression_keys(&state.hmac_secret, &email)));
+    }
+    // One lookup for the whole segment. The email client checks again at
+    // send time, for addresses suppressed while the issue was queued.
+    let all_keys: Vec<String> = immediate
+        .iter()
+        .flat_map(|(_, keys)| keys.iter().cloned())
+        .collect();
+    let suppressed_keys = get_suppressed_keys(&mut transaction, &all_keys)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let (suppressed, queued): (Vec<_>, Vec<_>) = immediate
+        .into_iter()
+        .partition(|(_, keys)| keys.iter().any(|key| suppressed_keys.contains(key)));
+    let suppressed = suppressed.into_iter().map(|(id, _)| id).collect();
+    let mut queued: Vec<Uuid> = queued.into_iter().map(|(id, _)| id).collect();
+    let mut groups = vec![];
+    if subjects.len() > 1 {
+        queued.shuffle(&mut rand::rng());
+        let sample_size = sample_size(
+            queued.len(),
+            subjects.len(),
+            state.subject_tests.sample_percent,
+        );
+        let held = queued.split_off(sample_size);
+        for variant in 0..subjects.len() {
+            let sample = queued
+                .iter()
+                .skip(variant)
+                .step_by(subjects.len())
+                .copied()
+                .collect();
+            groups.push((sample, DeliveryStatus::Queued, Some(variant as i16)));
+        }
+        groups.push((held, DeliveryStatus::Held, None));
+    } else {
+        groups.push((queued, DeliveryStatus::Queued, None));
+    }
+    groups.push((suppressed, DeliveryStatus::Suppressed, None));
+    groups.push((digest, DeliveryStatus::Digest, None));
+    for (subscriber_ids, status, variant) in groups {
+        insert_deliveries(&mut transaction, issue_id, &subscriber_ids, status, variant)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+    let summary = get_issue_summary(&mut transaction, issue_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // A concurrent request with the same key got there first.
+    if let Err(e) = transaction.commit().await {
+        return Err(match e {
+            sqlx::Error::Database(e) if e.is_unique_violation() => StatusCode::CONFLICT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        });
+    }
+    Ok((StatusCode::ACCEPTED, Json(summary)))
+}
+
+/// Every variant gets at least one recipient when there are enough of them.
+fn sample_size(recipients: usize, variants: usize, sample_percent: u32) -> usize {
+    (recipients * sample_percent as usize)
+        .div_ceil(100)
+        .max(variants)
+        .min(recipients)
+}
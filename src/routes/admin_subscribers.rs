@@ -0,0 +1,212 @@
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::SecondsFormat;
+use futures_util::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    PgPool,
+};
+use tokio::sync::mpsc;
+use tracing::Instrument;
+use uuid::Uuid;
+
+use super::AdminAuth;
+use crate::{csv::write_record, startup::ApplicationState};
+
+/// The columns of CSV exports, in order. Downstream tools rely on them, so
+/// new columns are only ever appended.
+pub const SUBSCRIBER_COLUMNS: [&str; 12] = [
+    "id",
+    "email",
+    "name",
+    "status",
+    "subscribed_at",
+    "delivery_frequency",
+    "language",
+    "topics",
+    "tags",
+    "attributes",
+    "unsubscribed_at",
+    "display_email",
+];
+
+/// Exports keep a pool connection busy for as long as the client takes to
+/// read them, so only this many may run at once.
+pub const MAX_CONCURRENT_EXPORTS: usize = 2;
+
+/// How many encoded rows may wait for a slow client before the database
+/// stream is paused.
+const EXPORT_BUFFER_ROWS: usize = 256;
+
+#[derive(Deserialize, Clone, Copy, Default)]
+pub enum ExportFormat {
+    #[default]
+    #[serde(rename = "csv")]
+    Csv,
+    #[serde(rename = "jsonl")]
+    JsonLines,
+}
+
+#[derive(Deserialize)]
+pub struct SubscriberExportParameters {
+    #[serde(default)]
+    format: ExportFormat,
+    status: Option<String>,
+    /// Slug of a list whose confirmed members are exported.
+    list: Option<String>,
+    subscribed_after: Option<DateTime<Utc>>,
+    subscribed_before: Option<DateTime<Utc>>,
+}
+
+/// One line of an export. JSON Lines keeps the field order of this struct.
+#[derive(Serialize)]
+pub struct SubscriberRow {
+    pub id: Uuid,
+    pub email: String,
+    pub name: String,
+    pub status: String,
+    pub subscribed_at: DateTime<Utc>,
+    pub delivery_frequency: String,
+    pub language: String,
+    pub topics: Vec<String>,
+    pub tags: Vec<String>,
+    pub attributes: serde_json::Value,
+    pub unsubscribed_at: Option<DateTime<Utc>>,
+    pub display_email: String,
+}
+
+impl SubscriberRow {
+    fn to_csv(&self) -> String {
+        let timestamp = |t: &DateTime<Utc>| t.to_rfc3339_opts(SecondsFormat::AutoSi, true);
+        write_record(&[
+            self.id.to_string(),
+            self.email.clone(),
+            self.name.clone(),
+            self.status.clone(),
+            timestamp(&self.subscribed_at),
+            self.delivery_frequency.clone(),
+            self.language.clone(),
+            self.topics.join(";"),
+            self.tags.join(";"),
+            self.attributes.to_string(),
+            self.unsubscribed_at
+                .as_ref()
+                .map(timestamp)
+                .unwrap_or_default(),
+            self.display_email.clone(),
+        ])
+    }
+
+    fn to_json_line(&self) -> String {
+        let mut line = serde_json::to_string(self).expect("Subscriber rows always serialize");
+        line.push('\n');
+        line
+    }
+}
+
+/// Streams every matching subscriber, oldest first, as CSV or JSON Lines.
+/// Rows are encoded as they come out of the database, so the size of the
+/// export does not matter. Answers 503 while `MAX_CONCURRENT_EXPORTS`
+/// exports are running.
+#[tracing::instrument(name = "Stream a subscriber export", skip(parameters, state))]
+pub async fn admin_stream_subscribers(
+    _: AdminAuth,
+    State(state): State<ApplicationState>,
+    Query(parameters): Query<SubscriberExportParameters>,
+) -> Response {
+    let Ok(export_slot) = state.export_slots.clone().try_acquire_owned() else {
+        tracing::warn!("Refused a subscriber export while others are running");
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, "30")],
+        )
+            .into_response();
+    };
+    let format = parameters.format;
+    let (sender, receiver) = mpsc::channel(EXPORT_BUFFER_ROWS);
+    tokio::spawn(
+        async move {
+            send_subscriber_rows(state.pool.clone(), parameters, sender).await;
+            drop(export_slot);
+        }
+        .in_current_span(),
+    );
+    let body = Body::from_stream(stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|line| (line, receiver))
+    }));
+    let (content_type, file_name) = match format {
+        ExportFormat::Csv => ("text/csv; charset=utf-8", "subscribers.csv"),
+        ExportFormat::JsonLines => ("application/x-ndjson", "subscribers.jsonl"),
+    };
+    (
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", file_name),
+            ),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+async fn send_subscriber_rows(
+    pool: PgPool,
+    parameters: SubscriberExportParameters,
+    sender: mpsc::Sender<Result<String, sqlx::Error>>,
+) {
+    if let ExportFormat::Csv = parameters.format {
+        if sender
+            .send(Ok(write_record(&SUBSCRIBER_COLUMNS)))
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+    let mut rows = sqlx::query_as!(
+        SubscriberRow,
+        r#"
+    SELECT s.id, s.email, s.name, s.status, s.subscribed_at, s.delivery_frequency, s.language,
+        s.topics, s.tags, s.attributes, s.unsubscribed_at, s.display_email
+    FROM subscriptions s
+    WHERE ($1::text IS NULL OR s.status = $1)
+        AND ($2::text IS NULL OR EXISTS (
+            SELECT 1 FROM list_memberships m JOIN lists l ON l.id = m.list_id
+            WHERE m.subscriber_id = s.id AND m.status = 'confirmed' AND l.slug = $2
+        ))
+        AND ($3::timestamptz IS NULL OR s.subscribed_at >= $3)
+        AND ($4::timestamptz IS NULL OR s.subscribed_at < $4)
+    ORDER BY s.subscribed_at, s.id
+    "#,
+        parameters.status,
+        parameters.list,
+        parameters.subscribed_after,
+        parameters.subscribed_before,
+    )
+    .fetch(&pool);
+    while let Some(row) = rows.next().await {
+        let line = row
+            .map(|row| match parameters.format {
+                ExportFormat::Csv => row.to_csv(),
+                ExportFormat::JsonLines => row.to_json_line(),
+            })
+            .map_err(|e| {
+                // The client sees the body end early rather than a clean end
+                // of file.
+                tracing::error!("Failed to stream subscribers: {:?}", e);
+                e
+            });
+        let failed = line.is_err();
+        // A closed channel means the client went away.
+        if sender.send(line).await.is_err() || failed {
+            return;
+        }
+    }
+}
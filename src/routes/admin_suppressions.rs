@@ -0,0 +1,145 @@
+use axum::{extract::State, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+
+use super::AdminAuth;
+use crate::{
+    audit::record_audit_event,
+    domain::SubscriberEmail,
+    startup::ApplicationState,
+    suppressions::{
+        suppress_email, suppression_key, suppression_keys, unsuppress_email, SuppressionReason,
+        Unsuppression,
+    },
+};
+
+#[derive(Deserialize)]
+pub struct SuppressionEntry {
+    email: String,
+    reason: SuppressionReason,
+}
+
+#[derive(Deserialize)]
+pub struct AddSuppressionsData {
+    entries: Vec<SuppressionEntry>,
+    /// Where the entries come from, e.g. the name of a bounce feed.
+    source: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RemoveSuppressionsData {
+    emails: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct RejectedEntry {
+    email: String,
+    message: String,
+}
+
+#[derive(Serialize, Default)]
+pub struct AddSuppressionsReport {
+    added: u64,
+    already_suppressed: u64,
+    rejected: Vec<RejectedEntry>,
+}
+
+#[derive(Serialize, Default)]
+pub struct RemoveSuppressionsReport {
+    removed: u64,
+    not_found: u64,
+    rejected: Vec<RejectedEntry>,
+}
+
+/// Suppresses addresses in bulk. Suppressed addresses are never mailed again
+/// and cannot subscribe, whether or not they are subscribers today.
+#[tracing::instrument(name = "Add suppressions", skip(data, state))]
+pub async fn admin_add_suppressions(
+    _: AdminAuth,
+    State(state): State<ApplicationState>,
+    Json(data): Json<AddSuppressionsData>,
+) -> Result<Json<AddSuppressionsReport>, StatusCode> {
+    let source = data
+        .source
+        .map(|source| source.trim().chars().take(256).collect::<String>())
+        .filter(|source| !source.is_empty())
+        .unwrap_or_else(|| "admin".to_string());
+    let mut transaction = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut report = AddSuppressionsReport::default();
+    for entry in data.entries {
+        let email = match SubscriberEmail::parse(entry.email.trim().to_string()) {
+            Ok(email) => email,
+            Err(message) => {
+                report.rejected.push(RejectedEntry {
+                    email: entry.email,
+                    message,
+                });
+                continue;
+            }
+        };
+        let key = suppression_key(&state.hmac_secret, &email);
+        if suppress_email(&mut transaction, &key, entry.reason.as_ref(), &source)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        {
+            report.added += 1;
+        } else {
+            report.already_suppressed += 1;
+        }
+    }
+    record_audit_event(&mut transaction, "admin", "suppressions_added", None)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if transaction.commit().await.is_err() {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    Ok(Json(report))
+}
+
+/// Lifts suppressions in bulk, e.g. after a bounce turned out to be
+/// temporary. It does not resubscribe anyone. Erased addresses are
+/// rejected: their tombstones stay.
+#[tracing::instrument(name = "Remove suppressions", skip(data, state))]
+pub async fn admin_remove_suppressions(
+    _: AdminAuth,
+    State(state): State<ApplicationState>,
+    Json(data): Json<RemoveSuppressionsData>,
+) -> Result<Json<RemoveSuppressionsReport>, StatusCode> {
+    let mut transaction = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut report = RemoveSuppressionsReport::default();
+    for email in data.emails {
+        let parsed = match SubscriberEmail::parse(email.trim().to_string()) {
+            Ok(parsed) => parsed,
+            Err(message) => {
+                report.rejected.push(RejectedEntry { email, message });
+                continue;
+            }
+        };
+        let keys = suppression_keys(&state.hmac_secret, &parsed);
+        match unsuppress_email(&mut transaction, &keys)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        {
+            Unsuppression::Removed => report.removed += 1,
+            Unsuppression::NotFound => report.not_found += 1,
+            Unsuppression::Erased => report.rejected.push(RejectedEntry {
+                email,
+                message: "The address was erased and cannot be mailed again.".to_string(),
+            }),
+        }
+    }
+    record_audit_event(&mut transaction, "admin", "suppressions_removed", None)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if transaction.commit().await.is_err() {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    Ok(Json(report))
+}
@@ -0,0 +1,85 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{
+        header::{CACHE_CONTROL, CONTENT_TYPE},
+        StatusCode,
+    },
+    response::{IntoResponse, Redirect},
+};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    startup::ApplicationState,
+    subject_tests::{click_message, open_message, record_click, record_open},
+};
+
+/// A transparent 1x1 GIF.
+const TRACKING_PIXEL: &[u8] = &[
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+#[derive(Deserialize)]
+pub struct OpenParameters {
+    subscriber_id: Uuid,
+    signature: String,
+}
+
+#[tracing::instrument(name = "Track an issue open", skip(state, parameters))]
+pub async fn track_open(
+    State(state): State<ApplicationState>,
+    Path(issue_id): Path<Uuid>,
+    Query(parameters): Query<OpenParameters>,
+) -> Result<impl IntoResponse, StatusCode> {
+    if !state.hmac_secret.verify(
+        &open_message(issue_id, parameters.subscriber_id),
+        &parameters.signature,
+    ) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    let mut connection = state
+        .pool
+        .acquire()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    record_open(&mut connection, issue_id, parameters.subscriber_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok((
+        [(CONTENT_TYPE, "image/gif"), (CACHE_CONTROL, "no-store")],
+        TRACKING_PIXEL,
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct ClickParameters {
+    subscriber_id: Uuid,
+    url: String,
+    signature: String,
+}
+
+/// Only redirects to links signed for this issue and recipient.
+#[tracing::instrument(name = "Track an issue click", skip(state, parameters))]
+pub async fn track_click(
+    State(state): State<ApplicationState>,
+    Path(issue_id): Path<Uuid>,
+    Query(parameters): Query<ClickParameters>,
+) -> Result<Redirect, StatusCode> {
+    if !state.hmac_secret.verify(
+        &click_message(issue_id, parameters.subscriber_id, &parameters.url),
+        &parameters.signature,
+    ) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    let mut connection = state
+        .pool
+        .acquire()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    record_click(&mut connection, issue_id, parameters.subscriber_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Redirect::to(&parameters.url))
+}
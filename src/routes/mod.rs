@@ -1,7 +1,35 @@
+mod admin;
+mod admin_import;
+mod admin_issues;
+mod admin_segments;
+mod admin_subscribers;
+mod admin_suppressions;
 mod health_check;
+mod issue_tracking;
+mod subscription_tokens;
 mod subscriptions;
+mod subscriptions_bot_protection;
 mod subscriptions_confirm;
+mod subscriptions_email_change;
+mod subscriptions_export;
+mod subscriptions_preferences;
+mod subscriptions_resend;
+mod subscriptions_unsubscribe;
 
+pub use admin::*;
+pub use admin_import::*;
+pub use admin_issues::*;
+pub use admin_segments::*;
+pub use admin_subscribers::*;
+pub use admin_suppressions::*;
 pub use health_check::*;
+pub use issue_tracking::*;
+pub use subscription_tokens::*;
 pub use subscriptions::*;
+pub use subscriptions_bot_protection::*;
 pub use subscriptions_confirm::*;
+pub use subscriptions_email_change::*;
+pub use subscriptions_export::*;
+pub use subscriptions_preferences::*;
+pub use subscriptions_resend::*;
+pub use subscriptions_unsubscribe::*;
@@ -0,0 +1,374 @@
+use std::{iter::repeat_with, time::Duration};
+
+use rand::prelude::*;
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    PgConnection,
+};
+use uuid::Uuid;
+
+use crate::hmac_secret::HmacSecret;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenPurpose {
+    Confirmation,
+    MagicLink,
+    EmailChange,
+    EmailRevert,
+    DataExport,
+}
+
+impl AsRef<str> for TokenPurpose {
+    fn as_ref(&self) -> &str {
+        match self {
+            Self::Confirmation => "confirmation",
+            Self::MagicLink => "magic_link",
+            Self::EmailChange => "email_change",
+            Self::EmailRevert => "email_revert",
+            Self::DataExport => "data_export",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TokenStatus {
+    Valid(Uuid),
+    Expired,
+    Consumed,
+}
+
+pub fn generate_subscription_token() -> String {
+    let mut rng = rand::rng();
+    repeat_with(|| rng.sample(rand::distr::Alphanumeric))
+        .map(char::from)
+        .take(25)
+        .collect()
+}
+
+pub async fn store_token(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    subscription_token: &str,
+    purpose: TokenPurpose,
+    hmac_secret: &HmacSecret,
+    expiry: Duration,
+) -> Result<(), sqlx::Error> {
+    insert_token(
+        connection,
+        subscriber_id,
+        TokenTarget::default(),
+        subscription_token,
+        purpose,
+        hmac_secret,
+        expiry,
+    )
+    .await
+}
+
+/// Confirmation tokens for a single mailing list remember which list they
+/// confirm.
+pub async fn store_list_token(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+    subscription_token: &str,
+    hmac_secret: &HmacSecret,
+    expiry: Duration,
+) -> Result<(), sqlx::Error> {
+    insert_token(
+        connection,
+        subscriber_id,
+        TokenTarget {
+            list_id: Some(list_id),
+            ..Default::default()
+        },
+        subscription_token,
+        TokenPurpose::Confirmation,
+        hmac_secret,
+        expiry,
+    )
+    .await
+}
+
+/// Revert tokens remember which email change they undo, so an old link
+/// cannot undo a later change instead.
+pub async fn store_email_revert_token(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    email_change_id: Uuid,
+    subscription_token: &str,
+    hmac_secret: &HmacSecret,
+    expiry: Duration,
+) -> Result<(), sqlx::Error> {
+    insert_token(
+        connection,
+        subscriber_id,
+        TokenTarget {
+            email_change_id: Some(email_change_id),
+            ..Default::default()
+        },
+        subscription_token,
+        TokenPurpose::EmailRevert,
+        hmac_secret,
+        expiry,
+    )
+    .await
+}
+
+/// What a token applies to beyond its subscriber.
+#[derive(Default)]
+struct TokenTarget {
+    list_id: Option<Uuid>,
+    email_change_id: Option<Uuid>,
+}
+
+#[tracing::instrument(
+    name = "Store subscription token in the database",
+    skip(connection, subscriber_id, target, subscription_token, hmac_secret)
+)]
+async fn insert_token(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    target: TokenTarget,
+    subscription_token: &str,
+    purpose: TokenPurpose,
+    hmac_secret: &HmacSecret,
+    expiry: Duration,
+) -> Result<(), sqlx::Error> {
+    let created_at = Utc::now();
+    sqlx::query!(
+        r#"
+    INSERT INTO subscription_tokens
+        (subscription_token_hash, subscriber_id, list_id, email_change_id, purpose, created_at, expires_at)
+    VALUES ($1, $2, $3, $4, $5, $6, $7)
+    "#,
+        hmac_secret.sign(subscription_token),
+        subscriber_id,
+        target.list_id,
+        target.email_change_id,
+        purpose.as_ref(),
+        created_at,
+        created_at + expiry,
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Delete subscription tokens of a subscriber",
+    skip(connection, subscriber_id)
+)]
+pub async fn delete_tokens(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"DELETE FROM subscription_tokens WHERE subscriber_id = $1"#,
+        subscriber_id
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Delete subscription tokens of a subscriber for one purpose",
+    skip(connection, subscriber_id)
+)]
+pub async fn delete_tokens_with_purpose(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    purpose: TokenPurpose,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"DELETE FROM subscription_tokens WHERE subscriber_id = $1 AND purpose = $2"#,
+        subscriber_id,
+        purpose.as_ref(),
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Delete confirmation tokens of a subscriber for one list",
+    skip(connection, subscriber_id, list_id)
+)]
+pub async fn delete_confirmation_tokens(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    list_id: Option<Uuid>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    DELETE FROM subscription_tokens
+    WHERE subscriber_id = $1 AND purpose = $2 AND list_id IS NOT DISTINCT FROM $3
+    "#,
+        subscriber_id,
+        TokenPurpose::Confirmation.as_ref(),
+        list_id,
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Get subscriber_id from token",
+    skip(connection, hmac_secret, subscription_token)
+)]
+pub async fn get_subscriber_id_from_token(
+    connection: &mut PgConnection,
+    hmac_secret: &HmacSecret,
+    purpose: TokenPurpose,
+    subscription_token: &str,
+) -> Result<Option<TokenStatus>, sqlx::Error> {
+    // Rows written before tokens were hashed still hold the plaintext token.
+    Ok(sqlx::query!(
+        r#"
+    SELECT subscriber_id, expires_at, consumed_at FROM subscription_tokens
+    WHERE (subscription_token_hash = $1 OR subscription_token = $2) AND purpose = $3
+    FOR UPDATE
+    "#,
+        hmac_secret.sign(subscription_token),
+        subscription_token,
+        purpose.as_ref(),
+    )
+    .fetch_optional(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .map(|r| {
+        if r.consumed_at.is_some() {
+            TokenStatus::Consumed
+        } else if r.expires_at <= Utc::now() {
+            TokenStatus::Expired
+        } else {
+            TokenStatus::Valid(r.subscriber_id)
+        }
+    }))
+}
+
+#[tracing::instrument(
+    name = "Mark token as consumed",
+    skip(connection, hmac_secret, subscription_token)
+)]
+pub async fn consume_token(
+    connection: &mut PgConnection,
+    hmac_secret: &HmacSecret,
+    subscription_token: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    UPDATE subscription_tokens SET consumed_at = $1
+    WHERE subscription_token_hash = $2 OR subscription_token = $3
+    "#,
+        Utc::now(),
+        hmac_secret.sign(subscription_token),
+        subscription_token,
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Get the mailing list of a token",
+    skip(connection, hmac_secret, subscription_token)
+)]
+pub async fn get_token_list_id(
+    connection: &mut PgConnection,
+    hmac_secret: &HmacSecret,
+    subscription_token: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    Ok(sqlx::query!(
+        r#"
+    SELECT list_id FROM subscription_tokens
+    WHERE subscription_token_hash = $1 OR subscription_token = $2
+    "#,
+        hmac_secret.sign(subscription_token),
+        subscription_token,
+    )
+    .fetch_optional(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .and_then(|r| r.list_id))
+}
+
+#[tracing::instrument(
+    name = "Get the email change of a token",
+    skip(connection, hmac_secret, subscription_token)
+)]
+pub async fn get_token_email_change_id(
+    connection: &mut PgConnection,
+    hmac_secret: &HmacSecret,
+    subscription_token: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    Ok(sqlx::query!(
+        r#"
+    SELECT email_change_id FROM subscription_tokens
+    WHERE subscription_token_hash = $1 OR subscription_token = $2
+    "#,
+        hmac_secret.sign(subscription_token),
+        subscription_token,
+    )
+    .fetch_optional(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .and_then(|r| r.email_change_id))
+}
+
+#[tracing::instrument(
+    name = "Get when the last token for a purpose was issued",
+    skip(connection, subscriber_id)
+)]
+pub async fn get_last_token_time(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    purpose: TokenPurpose,
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    Ok(sqlx::query!(
+        r#"
+    SELECT MAX(created_at) AS created_at FROM subscription_tokens
+    WHERE subscriber_id = $1 AND purpose = $2 AND list_id IS NULL
+    "#,
+        subscriber_id,
+        purpose.as_ref(),
+    )
+    .fetch_one(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .created_at)
+}
@@ -1,91 +1,529 @@
-use std::iter::repeat_with;
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 
 use axum::{
-    extract::{Form, State},
-    http::StatusCode,
-    response,
+    async_trait,
+    extract::{ConnectInfo, FromRequest, Request, State},
+    http::{
+        header::{ACCEPT, CONTENT_TYPE, RETRY_AFTER},
+        HeaderMap, HeaderName, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
 };
-use rand::prelude::*;
-use serde::Deserialize;
+use axum_extra::extract::Form;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use sqlx::{types::chrono::Utc, PgConnection};
 use uuid::Uuid;
 
+use super::{
+    check_submission, claim_form_token, delete_confirmation_tokens, generate_subscription_token,
+    store_list_token, store_token, unsubscribe_link, BotProtectionFields, TokenPurpose,
+};
 use crate::{
-    domain::{NewSubscriber, SubscriberEmail, SubscriberName},
+    configuration::AttributeSettings,
+    consent::{record_subscribe_consent, ConsentContext, SubscribeConsent},
+    domain::{
+        DeliveryFrequency, NewSubscriber, SubscriberAttributes, SubscriberEmail, SubscriberName,
+    },
     email_client::EmailClient,
+    mailing_lists::{get_lists_by_slug, get_membership_status, insert_membership, MailingList},
+    rate_limit::client_network,
     startup::ApplicationState,
+    suppressions::is_suppressed,
 };
 
+/// The fields `FormData` reads itself. Custom attributes cannot use these
+/// names, or they would never reach `extra_fields`.
+pub const RESERVED_FORM_FIELDS: &[&str] = &[
+    "email",
+    "name",
+    "delivery_frequency",
+    "lists",
+    "source",
+    "consent_version",
+    "form_token",
+    "proof_of_work",
+];
+
 #[derive(Deserialize)]
 pub struct FormData {
     email: String,
     name: String,
+    delivery_frequency: Option<String>,
+    /// Slugs of the mailing lists to join, repeated once per list. Without
+    /// any, the subscriber joins the main newsletter.
+    #[serde(default)]
+    lists: Vec<String>,
+    /// Where the signup form lives, e.g. `blog-footer`.
+    source: Option<String>,
+    /// The version of the consent text the form showed.
+    consent_version: Option<String>,
+    /// Issued by `/subscriptions/challenge`.
+    form_token: Option<String>,
+    proof_of_work: Option<String>,
+    /// Catches the honeypot field and the custom attributes, whatever they
+    /// are configured to be called.
+    #[serde(flatten)]
+    extra_fields: HashMap<String, serde_json::Value>,
 }
 
-impl TryFrom<FormData> for NewSubscriber {
-    type Error = String;
+impl FormData {
+    /// Validates the submission, including the custom attributes defined in
+    /// `schema`, and reports every invalid field at once.
+    fn parse(self, schema: &[AttributeSettings]) -> Result<NewSubscriber, Vec<FieldError>> {
+        let name = SubscriberName::parse(self.name).map_err(|e| FieldError::new("name", e));
+        let email = SubscriberEmail::parse(self.email).map_err(|e| FieldError::new("email", e));
+        let delivery_frequency = self
+            .delivery_frequency
+            .map(DeliveryFrequency::parse)
+            .transpose()
+            .map(Option::unwrap_or_default)
+            .map_err(|e| FieldError::new("delivery_frequency", e));
+        let attributes =
+            SubscriberAttributes::parse(&self.extra_fields, schema).map_err(|errors| {
+                errors
+                    .into_iter()
+                    .map(|e| FieldError::new(e.attribute, e.message))
+                    .collect::<Vec<_>>()
+            });
+        match (name, email, delivery_frequency, attributes) {
+            (Ok(name), Ok(email), Ok(delivery_frequency), Ok(attributes)) => Ok(NewSubscriber {
+                name,
+                email,
+                delivery_frequency,
+                attributes,
+            }),
+            (name, email, delivery_frequency, attributes) => {
+                Err([name.err(), email.err(), delivery_frequency.err()]
+                    .into_iter()
+                    .flatten()
+                    .chain(attributes.err().into_iter().flatten())
+                    .collect())
+            }
+        }
+    }
+}
+
+/// A subscription sent either as an HTML form or as JSON. Clients that send
+/// JSON or accept it get JSON answers, everyone else gets bare status codes.
+pub struct SubscribeRequest {
+    form_data: FormData,
+    wants_json: bool,
+}
 
-    fn try_from(value: FormData) -> Result<Self, Self::Error> {
+#[async_trait]
+impl<S> FromRequest<S> for SubscribeRequest
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let is_json = has_media_type(req.headers(), CONTENT_TYPE, "application/json");
+        let wants_json = is_json || has_media_type(req.headers(), ACCEPT, "application/json");
+        let form_data = if is_json {
+            Json::<FormData>::from_request(req, state)
+                .await
+                .map(|Json(form_data)| form_data)
+                .map_err(|e| SubscribeError::Malformed(e.status(), e.body_text()))
+        } else {
+            // Unlike the plain axum extractor this one supports repeated
+            // fields, but it rejects missing fields with a 400 instead of a
+            // 422.
+            Form::<FormData>::from_request(req, state)
+                .await
+                .map(|Form(form_data)| form_data)
+                .map_err(|e| {
+                    SubscribeError::Malformed(StatusCode::UNPROCESSABLE_ENTITY, e.to_string())
+                })
+        }
+        .map_err(|e| e.into_response(wants_json))?;
         Ok(Self {
-            name: SubscriberName::parse(value.name.clone())?,
-            email: SubscriberEmail::parse(value.email.clone())?,
+            form_data,
+            wants_json,
         })
     }
 }
 
+fn has_media_type(headers: &HeaderMap, name: HeaderName, media_type: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|value| value.split(';').next())
+        .any(|value| value.trim().eq_ignore_ascii_case(media_type))
+}
+
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    field: String,
+    message: String,
+}
+
+impl FieldError {
+    fn new(field: impl Into<String>, message: String) -> Self {
+        Self {
+            field: field.into(),
+            message,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SubscribeError {
+    Malformed(StatusCode, String),
+    Invalid(Vec<FieldError>),
+    RateLimited(Duration),
+    Unexpected,
+}
+
+impl SubscribeError {
+    pub fn into_response(self, wants_json: bool) -> Response {
+        let retry_after = match &self {
+            Self::RateLimited(retry_after) => Some(retry_after.as_secs_f64().ceil().max(1.0)),
+            _ => None,
+        };
+        let (status, body) = match self {
+            Self::Malformed(status, message) => (
+                status,
+                json!({ "error": "malformed_request", "message": message }),
+            ),
+            Self::Invalid(fields) => (
+                StatusCode::BAD_REQUEST,
+                json!({ "error": "invalid_fields", "fields": fields }),
+            ),
+            Self::RateLimited(_) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                json!({ "error": "rate_limited" }),
+            ),
+            Self::Unexpected => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({ "error": "unexpected_error" }),
+            ),
+        };
+        let mut response = if wants_json {
+            (status, Json(body)).into_response()
+        } else {
+            status.into_response()
+        };
+        if let Some(retry_after) = retry_after {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, (retry_after as u64).into());
+        }
+        response
+    }
+}
+
 #[tracing::instrument(
     name = "Adding a new subscriber",
-    skip(form_data, state),
+    skip(request, state),
     fields(
         request_id = %Uuid::new_v4(),
-        subscriber_email = %form_data.email,
-        subscriber_name = %form_data.name,
+        subscriber_email = %request.form_data.email,
+        subscriber_name = %request.form_data.name,
     ),
 )]
 pub async fn subscribe(
     State(state): State<ApplicationState>,
-    Form(form_data): Form<FormData>,
-) -> response::Result<(), StatusCode> {
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    request: SubscribeRequest,
+) -> Response {
+    let form_data = request.form_data;
+    let client_ip = state.rate_limiter.client_ip(peer, &headers);
+    if let Err(e) = enforce_rate_limits(&state, "subscribe", client_ip, &form_data.email).await {
+        return e.into_response(request.wants_json);
+    }
+    let bot_check = check_submission(
+        &state.bot_protection,
+        &state.hmac_secret,
+        BotProtectionFields {
+            form_token: form_data.form_token.as_deref(),
+            proof_of_work: form_data.proof_of_work.as_deref(),
+            extra_fields: &form_data.extra_fields,
+        },
+    );
+    // Bots get the same answer as everyone else so they cannot learn which
+    // check caught them.
+    if let Err(reason) = bot_check {
+        tracing::warn!(%reason, "Rejected a subscription that looks automated");
+        return accepted_response(request.wants_json);
+    }
+    let consent_version = match state.consent.version(form_data.consent_version.as_deref()) {
+        Ok(version) => version,
+        Err(e) => {
+            return SubscribeError::Invalid(vec![FieldError::new("consent_version", e)])
+                .into_response(request.wants_json)
+        }
+    };
+    let consent = SubscribeConsent {
+        context: ConsentContext::new(client_ip, &headers),
+        source: form_data
+            .source
+            .as_ref()
+            .map(|source| source.chars().take(256).collect()),
+        consent_version,
+    };
+    let form_token = form_data
+        .form_token
+        .clone()
+        .filter(|_| state.bot_protection.requires_form_token());
+    match add_subscriber(&state, form_data, form_token.as_deref(), &consent).await {
+        // The answer is the same whether or not the address was already
+        // known, so it cannot be used to find out who is on the list.
+        Ok(()) => accepted_response(request.wants_json),
+        Err(e) => e.into_response(request.wants_json),
+    }
+}
+
+// Every request counts against both the client and the target address, so
+// neither one client nor many can flood an inbox. Each endpoint that mails an
+// address it is given has its own buckets, named by `action`.
+pub async fn enforce_rate_limits(
+    state: &ApplicationState,
+    action: &str,
+    client_ip: IpAddr,
+    email: &str,
+) -> Result<(), SubscribeError> {
+    let limiter = &state.rate_limiter;
+    // Keys may end up in Postgres, which should not learn the addresses.
+    let email_key = state.hmac_secret.sign(&email.trim().to_lowercase());
+    let buckets = [
+        (
+            "ip",
+            format!("{}:ip:{}", action, client_network(client_ip)),
+            &limiter.settings.per_ip,
+        ),
+        (
+            "email",
+            format!("{}:email:{}", action, email_key),
+            &limiter.settings.per_email,
+        ),
+    ];
+    for (limit, key, settings) in buckets {
+        let retry_after = limiter
+            .take(&key, settings)
+            .await
+            .map_err(|_| SubscribeError::Unexpected)?;
+        if let Some(retry_after) = retry_after {
+            tracing::warn!(limit, action, %client_ip, "Rate limit exceeded");
+            return Err(SubscribeError::RateLimited(retry_after));
+        }
+    }
+    Ok(())
+}
+
+fn accepted_response(wants_json: bool) -> Response {
+    if wants_json {
+        Json(json!({
+            "message": "Check your inbox to confirm your subscription."
+        }))
+        .into_response()
+    } else {
+        StatusCode::OK.into_response()
+    }
+}
+
+async fn add_subscriber(
+    state: &ApplicationState,
+    mut form_data: FormData,
+    form_token: Option<&str>,
+    consent: &SubscribeConsent,
+) -> Result<(), SubscribeError> {
+    let list_slugs = std::mem::take(&mut form_data.lists);
+    let new_subscriber = form_data
+        .parse(&state.attributes)
+        .map_err(SubscribeError::Invalid)?;
     let mut transaction = state
         .pool
         .begin()
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    let new_subscriber: NewSubscriber =
-        form_data.try_into().map_err(|_| StatusCode::BAD_REQUEST)?;
-    let subscriber_id = insert_subscriber(&mut transaction, &new_subscriber)
+        .map_err(|_| SubscribeError::Unexpected)?;
+    // Claimed in the transaction, so a form rejected as invalid can be
+    // corrected and sent again.
+    if let Some(form_token) = form_token {
+        if !claim_form_token(
+            &mut transaction,
+            form_token,
+            state.bot_protection.max_form_age(),
+        )
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    let subscription_token = generate_subscription_token();
+        .map_err(|_| SubscribeError::Unexpected)?
+        {
+            tracing::warn!("Rejected a replayed form token");
+            return Ok(());
+        }
+    }
+    let lists = get_lists_by_slug(&mut transaction, &list_slugs)
+        .await
+        .map_err(|_| SubscribeError::Unexpected)?;
+    let unknown_lists: BTreeSet<_> = list_slugs
+        .iter()
+        .filter(|slug| !lists.iter().any(|list| &list.slug == *slug))
+        .collect();
+    if !unknown_lists.is_empty() {
+        return Err(SubscribeError::Invalid(vec![FieldError::new(
+            "lists",
+            format!(
+                "{} is not a known list.",
+                unknown_lists
+                    .into_iter()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        )]));
+    }
+    if is_suppressed(&mut transaction, &state.hmac_secret, &new_subscriber.email)
+        .await
+        .map_err(|_| SubscribeError::Unexpected)?
+    {
+        tracing::info!("Ignored a subscription for a suppressed address");
+        return Ok(());
+    }
+    let subscriber = get_or_insert_subscriber(&mut transaction, &new_subscriber)
+        .await
+        .map_err(|_| SubscribeError::Unexpected)?;
+    if lists.is_empty() {
+        subscribe_to_newsletter(state, &mut transaction, new_subscriber, subscriber, consent)
+            .await?;
+    } else {
+        subscribe_to_lists(
+            state,
+            &mut transaction,
+            new_subscriber,
+            subscriber,
+            &lists,
+            consent,
+        )
+        .await?;
+    }
+    if transaction.commit().await.is_err() {
+        return Err(SubscribeError::Unexpected);
+    }
+    Ok(())
+}
 
-    store_token(&mut transaction, subscriber_id, &subscription_token)
+async fn subscribe_to_newsletter(
+    state: &ApplicationState,
+    connection: &mut PgConnection,
+    new_subscriber: NewSubscriber,
+    subscriber: ExistingSubscriber,
+    consent: &SubscribeConsent,
+) -> Result<(), SubscribeError> {
+    // Answer exactly as for a new address so the endpoint cannot be used to
+    // find out who is already on the list. Unsubscribed addresses are never
+    // mailed again; they can only come back through the undo link.
+    if matches!(subscriber.status.as_str(), "confirmed" | "unsubscribed") {
+        return Ok(());
+    }
+    let subscriber_id = subscriber.id;
+    delete_confirmation_tokens(connection, subscriber_id, None)
+        .await
+        .map_err(|_| SubscribeError::Unexpected)?;
+    record_subscribe_consent(connection, subscriber_id, None, consent)
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .map_err(|_| SubscribeError::Unexpected)?;
+    let subscription_token = generate_subscription_token();
 
+    store_token(
+        connection,
+        subscriber_id,
+        &subscription_token,
+        TokenPurpose::Confirmation,
+        &state.hmac_secret,
+        state.subscription_token_expiry,
+    )
+    .await
+    .map_err(|_| SubscribeError::Unexpected)?;
+
+    let unsubscribe_link = unsubscribe_link(&state.base_url, &state.hmac_secret, subscriber_id);
     send_confirmation_email(
         &state.email_client,
-        new_subscriber,
-        state.base_url,
+        new_subscriber.email,
+        state.base_url.clone(),
         &subscription_token,
+        &unsubscribe_link,
     )
     .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    .map_err(|_| SubscribeError::Unexpected)
+}
 
-    if transaction.commit().await.is_err() {
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+// Each list is confirmed on its own, so lists the address already belongs
+// to are skipped and every other list gets its own confirmation email.
+async fn subscribe_to_lists(
+    state: &ApplicationState,
+    connection: &mut PgConnection,
+    new_subscriber: NewSubscriber,
+    subscriber: ExistingSubscriber,
+    lists: &[MailingList],
+    consent: &SubscribeConsent,
+) -> Result<(), SubscribeError> {
+    if subscriber.status == "unsubscribed" {
+        return Ok(());
+    }
+    let subscriber_id = subscriber.id;
+    let unsubscribe_link = unsubscribe_link(&state.base_url, &state.hmac_secret, subscriber_id);
+    for list in lists {
+        match get_membership_status(connection, subscriber_id, list.id)
+            .await
+            .map_err(|_| SubscribeError::Unexpected)?
+        {
+            Some(status) if status == "confirmed" => continue,
+            Some(status) if status == "pending_confirmation" => {
+                delete_confirmation_tokens(connection, subscriber_id, Some(list.id))
+                    .await
+                    .map_err(|_| SubscribeError::Unexpected)?
+            }
+            _ => insert_membership(connection, subscriber_id, list.id)
+                .await
+                .map_err(|_| SubscribeError::Unexpected)?,
+        }
+        record_subscribe_consent(connection, subscriber_id, Some(list.id), consent)
+            .await
+            .map_err(|_| SubscribeError::Unexpected)?;
+        let subscription_token = generate_subscription_token();
+        store_list_token(
+            connection,
+            subscriber_id,
+            list.id,
+            &subscription_token,
+            &state.hmac_secret,
+            state.subscription_token_expiry,
+        )
+        .await
+        .map_err(|_| SubscribeError::Unexpected)?;
+        send_list_confirmation_email(
+            &state.email_client,
+            list,
+            new_subscriber.email.clone(),
+            &state.base_url,
+            &subscription_token,
+            &unsubscribe_link,
+        )
+        .await
+        .map_err(|_| SubscribeError::Unexpected)?;
     }
     Ok(())
 }
 
 #[tracing::instrument(
     name = "Send a confirmation email to a new subscriber",
-    skip(email_client, new_subscriber)
+    skip(email_client, recipient, unsubscribe_link)
 )]
 pub async fn send_confirmation_email(
     email_client: &EmailClient,
-    new_subscriber: NewSubscriber,
+    recipient: SubscriberEmail,
     base_url: String,
     subscription_token: &str,
+    unsubscribe_link: &str,
 ) -> Result<(), String> {
     let confirmation_link = format!(
         "{}/subscriptions/confirm?subscription_token={}",
@@ -93,48 +531,106 @@ pub async fn send_confirmation_email(
     );
     email_client
         .send_email(
-            new_subscriber.email,
+            recipient,
             "Welcome newsletter!",
             &format!(
                 "Welcome to our newsletter!<br />
-    Click <a href=\"{}\">here</a> to confirm your subscription.",
-                confirmation_link,
+    Click <a href=\"{}\">here</a> to confirm your subscription.<br />
+    Didn't sign up? <a href=\"{}\">Unsubscribe</a>.",
+                confirmation_link, unsubscribe_link,
             ),
         )
         .await
 }
 
 #[tracing::instrument(
-    name = "Store subscription token in the database",
-    skip(connection, subscriber_id, subscription_token)
+    name = "Send a list confirmation email to a subscriber",
+    skip(email_client, list, recipient, subscription_token, unsubscribe_link),
+    fields(list = %list.slug)
 )]
-pub async fn store_token(
-    connection: &mut PgConnection,
-    subscriber_id: Uuid,
+pub async fn send_list_confirmation_email(
+    email_client: &EmailClient,
+    list: &MailingList,
+    recipient: SubscriberEmail,
+    base_url: &str,
     subscription_token: &str,
-) -> Result<(), sqlx::Error> {
-    sqlx::query!(
-        r#"INSERT INTO subscription_tokens (subscription_token, subscriber_id) VALUES ($1, $2)"#,
-        subscription_token,
-        subscriber_id
+    unsubscribe_link: &str,
+) -> Result<(), String> {
+    let sender = SubscriberEmail::parse(list.sender_email.clone())?;
+    let confirmation_link = format!(
+        "{}/subscriptions/confirm?subscription_token={}",
+        base_url, subscription_token,
+    );
+    email_client
+        .send_email_as(
+            &sender,
+            recipient,
+            &list.welcome_subject,
+            &format!(
+                "{}<br />
+    Click <a href=\"{}\">here</a> to confirm your subscription to {}.<br />
+    Didn't sign up? <a href=\"{}\">Unsubscribe</a>.",
+                list.welcome_body, confirmation_link, list.name, unsubscribe_link,
+            ),
+        )
+        .await
+}
+
+pub struct ExistingSubscriber {
+    pub id: Uuid,
+    pub status: String,
+    /// Set once any confirmation link was followed, including a list's.
+    pub email_verified: bool,
+}
+
+#[tracing::instrument(
+    name = "Look up an existing subscriber by email",
+    skip(connection, email)
+)]
+pub async fn get_subscriber_by_email(
+    connection: &mut PgConnection,
+    email: &SubscriberEmail,
+) -> Result<Option<ExistingSubscriber>, sqlx::Error> {
+    sqlx::query_as!(
+        ExistingSubscriber,
+        r#"
+    SELECT id, status, email_verified_at IS NOT NULL AS "email_verified!"
+    FROM subscriptions WHERE lower(email) = lower($1) FOR UPDATE
+    "#,
+        email.as_ref(),
     )
-    .execute(connection)
+    .fetch_optional(connection)
     .await
     .map_err(|e| {
         tracing::error!("Failed to execute query: {:?}", e);
         e
-    })?;
-    Ok(())
+    })
 }
 
-fn generate_subscription_token() -> String {
-    let mut rng = rand::rng();
-    repeat_with(|| rng.sample(rand::distr::Alphanumeric))
-        .map(char::from)
-        .take(25)
-        .collect()
+/// Locks the subscriber with the address, adding them as pending if they
+/// are new. `FOR UPDATE` cannot lock a row that does not exist yet, so two
+/// first-time subscribes for one address both try to insert it; the second
+/// insert waits for the first to commit and then finds the row instead.
+async fn get_or_insert_subscriber(
+    connection: &mut PgConnection,
+    new_subscriber: &NewSubscriber,
+) -> Result<ExistingSubscriber, sqlx::Error> {
+    if let Some(subscriber) = get_subscriber_by_email(connection, &new_subscriber.email).await? {
+        return Ok(subscriber);
+    }
+    if let Some(id) = insert_subscriber(connection, new_subscriber).await? {
+        return Ok(ExistingSubscriber {
+            id,
+            status: "pending_confirmation".to_string(),
+            email_verified: false,
+        });
+    }
+    get_subscriber_by_email(connection, &new_subscriber.email)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)
 }
 
+/// Returns `None` if the address was added by someone else in the meantime.
 #[tracing::instrument(
     name = "Saving new subscriber details in the database",
     skip(new_subscriber, connection)
@@ -142,17 +638,22 @@ fn generate_subscription_token() -> String {
 pub async fn insert_subscriber(
     connection: &mut PgConnection,
     new_subscriber: &NewSubscriber,
-) -> Result<Uuid, sqlx::Error> {
+) -> Result<Option<Uuid>, sqlx::Error> {
     let subscriber_id = Uuid::new_v4();
-    sqlx::query!(
+    let result = sqlx::query!(
         r#"
-    INSERT INTO subscriptions (id, email, name, subscribed_at, status)
-    VALUES ($1, $2, $3, $4, 'pending_confirmation')
+    INSERT INTO subscriptions
+        (id, email, display_email, name, subscribed_at, status, delivery_frequency, attributes)
+    VALUES ($1, $2, $3, $4, $5, 'pending_confirmation', $6, $7)
+    ON CONFLICT DO NOTHING
     "#,
         subscriber_id,
         new_subscriber.email.as_ref(),
+        new_subscriber.email.display(),
         new_subscriber.name.as_ref(),
         Utc::now(),
+        new_subscriber.delivery_frequency.as_ref(),
+        serde_json::Value::Object(new_subscriber.attributes.as_ref().clone()),
     )
     .execute(connection)
     .await
@@ -160,5 +661,5 @@ pub async fn insert_subscriber(
         tracing::error!("Failed to execute query: {:?}", err);
         err
     })?;
-    Ok(subscriber_id)
+    Ok((result.rows_affected() > 0).then_some(subscriber_id))
 }
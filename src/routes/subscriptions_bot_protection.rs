@@ -0,0 +1,269 @@
+use std::{collections::HashMap, fmt};
+
+use axum::{extract::State, Json};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::{types::chrono::Utc, PgConnection};
+
+use super::generate_subscription_token;
+use crate::{
+    configuration::BotProtectionSettings, hmac_secret::HmacSecret, startup::ApplicationState,
+};
+
+#[derive(Serialize)]
+pub struct SubscribeChallenge {
+    form_token: String,
+    honeypot_field: Option<String>,
+    proof_of_work_difficulty: u32,
+}
+
+/// Everything a subscribe form needs to pass the bot checks. The form token
+/// must be sent back as `form_token`; if a proof of work is required, the
+/// client must find a `proof_of_work` nonce such that
+/// `sha256("{form_token}:{proof_of_work}")` starts with
+/// `proof_of_work_difficulty` zero bits.
+#[tracing::instrument(name = "Issue a subscribe form challenge", skip(state))]
+pub async fn subscribe_challenge(
+    State(state): State<ApplicationState>,
+) -> Json<SubscribeChallenge> {
+    Json(SubscribeChallenge {
+        form_token: form_token(&state.hmac_secret, Utc::now().timestamp()),
+        honeypot_field: state.bot_protection.honeypot_field.clone(),
+        proof_of_work_difficulty: state.bot_protection.proof_of_work_difficulty,
+    })
+}
+
+/// The signed time the form was rendered at. The random part makes every
+/// token, and therefore every proof of work, unique.
+pub fn form_token(hmac_secret: &HmacSecret, rendered_at: i64) -> String {
+    let message = format!("{}.{}", rendered_at, generate_subscription_token());
+    format!(
+        "{}.{}",
+        message,
+        hmac_secret.sign(&form_token_message(&message))
+    )
+}
+
+fn form_token_message(message: &str) -> String {
+    format!("subscribe-form:{}", message)
+}
+
+fn parse_form_token(hmac_secret: &HmacSecret, token: &str) -> Option<i64> {
+    let (message, signature) = token.rsplit_once('.')?;
+    if !hmac_secret.verify(&form_token_message(message), signature) {
+        return None;
+    }
+    message.split_once('.')?.0.parse().ok()
+}
+
+/// The bot-protection fields of a subscribe request.
+pub struct BotProtectionFields<'a> {
+    pub form_token: Option<&'a str>,
+    pub proof_of_work: Option<&'a str>,
+    pub extra_fields: &'a HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BotRejection {
+    HoneypotFilled,
+    MissingFormToken,
+    InvalidFormToken,
+    SubmittedTooFast,
+    FormTokenExpired,
+    MissingProofOfWork,
+    InvalidProofOfWork,
+}
+
+impl fmt::Display for BotRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::HoneypotFilled => "the honeypot field was filled in",
+            Self::MissingFormToken => "the form token is missing",
+            Self::InvalidFormToken => "the form token is invalid",
+            Self::SubmittedTooFast => "the form was submitted too fast",
+            Self::FormTokenExpired => "the form token has expired",
+            Self::MissingProofOfWork => "the proof of work is missing",
+            Self::InvalidProofOfWork => "the proof of work is invalid",
+        })
+    }
+}
+
+pub fn check_submission(
+    settings: &BotProtectionSettings,
+    hmac_secret: &HmacSecret,
+    fields: BotProtectionFields,
+) -> Result<(), BotRejection> {
+    let honeypot = settings
+        .honeypot_field
+        .as_ref()
+        .and_then(|name| fields.extra_fields.get(name));
+    if honeypot.is_some_and(|value| !matches!(value, serde_json::Value::Null) && value != "") {
+        return Err(BotRejection::HoneypotFilled);
+    }
+    if !settings.requires_form_token() {
+        return Ok(());
+    }
+    let form_token = fields.form_token.ok_or(BotRejection::MissingFormToken)?;
+    let rendered_at =
+        parse_form_token(hmac_secret, form_token).ok_or(BotRejection::InvalidFormToken)?;
+    let age = Utc::now().timestamp() - rendered_at;
+    if age < settings.min_submit_seconds as i64 {
+        return Err(BotRejection::SubmittedTooFast);
+    }
+    if age > settings.max_form_age().as_secs() as i64 {
+        return Err(BotRejection::FormTokenExpired);
+    }
+    if settings.proof_of_work_difficulty > 0 {
+        let nonce = fields
+            .proof_of_work
+            .ok_or(BotRejection::MissingProofOfWork)?;
+        if !is_valid_proof_of_work(form_token, nonce, settings.proof_of_work_difficulty) {
+            return Err(BotRejection::InvalidProofOfWork);
+        }
+    }
+    Ok(())
+}
+
+/// Records a form token as used, so a solved challenge cannot be replayed
+/// while the token is still fresh. Returns `false` if it was used before.
+#[tracing::instrument(name = "Claim a form token", skip(connection, form_token))]
+pub async fn claim_form_token(
+    connection: &mut PgConnection,
+    form_token: &str,
+    max_form_age: std::time::Duration,
+) -> Result<bool, sqlx::Error> {
+    let form_token_hash = format!("{:x}", Sha256::digest(form_token));
+    let claimed = sqlx::query!(
+        r#"
+    INSERT INTO used_form_tokens (form_token_hash, expires_at)
+    VALUES ($1, $2)
+    ON CONFLICT (form_token_hash) DO NOTHING
+    "#,
+        form_token_hash,
+        Utc::now() + max_form_age,
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .rows_affected();
+    Ok(claimed == 1)
+}
+
+pub fn is_valid_proof_of_work(form_token: &str, nonce: &str, difficulty: u32) -> bool {
+    let digest = Sha256::digest(format!("{}:{}", form_token, nonce));
+    leading_zero_bits(&digest) >= difficulty
+}
+
+fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in bytes {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use secrecy::SecretString;
+
+    use super::*;
+
+    fn settings(min_submit_seconds: u64, proof_of_work_difficulty: u32) -> BotProtectionSettings {
+        BotProtectionSettings {
+            honeypot_field: Some("website".to_string()),
+            min_submit_seconds,
+            max_form_age_minutes: 60,
+            proof_of_work_difficulty,
+        }
+    }
+
+    fn fields<'a>(
+        form_token: Option<&'a str>,
+        proof_of_work: Option<&'a str>,
+        extra_fields: &'a HashMap<String, serde_json::Value>,
+    ) -> BotProtectionFields<'a> {
+        BotProtectionFields {
+            form_token,
+            proof_of_work,
+            extra_fields,
+        }
+    }
+
+    #[test]
+    fn leading_zero_bits_are_counted_across_bytes() {
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x10]), 11);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn a_filled_honeypot_is_rejected() {
+        let secret = HmacSecret::new(SecretString::from("secret"));
+        let extra = HashMap::from([("website".to_string(), "spam".into())]);
+        let result = check_submission(&settings(0, 0), &secret, fields(None, None, &extra));
+        assert_eq!(result, Err(BotRejection::HoneypotFilled));
+    }
+
+    #[test]
+    fn an_empty_honeypot_is_accepted() {
+        let secret = HmacSecret::new(SecretString::from("secret"));
+        let extra = HashMap::from([("website".to_string(), "".into())]);
+        let result = check_submission(&settings(0, 0), &secret, fields(None, None, &extra));
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    fn form_tokens_are_checked_for_age() {
+        let secret = HmacSecret::new(SecretString::from("secret"));
+        let extra = HashMap::new();
+        let now = Utc::now().timestamp();
+
+        let fresh = form_token(&secret, now);
+        let result = check_submission(&settings(5, 0), &secret, fields(Some(&fresh), None, &extra));
+        assert_eq!(result, Err(BotRejection::SubmittedTooFast));
+
+        let stale = form_token(&secret, now - 2 * 60 * 60);
+        let result = check_submission(&settings(5, 0), &secret, fields(Some(&stale), None, &extra));
+        assert_eq!(result, Err(BotRejection::FormTokenExpired));
+
+        let ok = form_token(&secret, now - 10);
+        let result = check_submission(&settings(5, 0), &secret, fields(Some(&ok), None, &extra));
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    fn form_tokens_signed_with_another_secret_are_rejected() {
+        let secret = HmacSecret::new(SecretString::from("secret"));
+        let other = HmacSecret::new(SecretString::from("other"));
+        let extra = HashMap::new();
+        let token = form_token(&other, Utc::now().timestamp() - 10);
+        let result = check_submission(&settings(5, 0), &secret, fields(Some(&token), None, &extra));
+        assert_eq!(result, Err(BotRejection::InvalidFormToken));
+    }
+
+    #[test]
+    fn a_solved_proof_of_work_is_accepted() {
+        let secret = HmacSecret::new(SecretString::from("secret"));
+        let extra = HashMap::new();
+        let token = form_token(&secret, Utc::now().timestamp());
+        let nonce = (0u64..)
+            .map(|n| n.to_string())
+            .find(|n| is_valid_proof_of_work(&token, n, 8))
+            .unwrap();
+
+        let result = check_submission(
+            &settings(0, 8),
+            &secret,
+            fields(Some(&token), Some(&nonce), &extra),
+        );
+        assert_eq!(result, Ok(()));
+        let result = check_submission(&settings(0, 8), &secret, fields(Some(&token), None, &extra));
+        assert_eq!(result, Err(BotRejection::MissingProofOfWork));
+    }
+}
@@ -1,28 +1,88 @@
+use std::net::SocketAddr;
+
 use axum::{
-    extract::{Query, State},
-    http::StatusCode,
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, StatusCode},
 };
 use serde::Deserialize;
-use sqlx::PgPool;
+use sqlx::{types::chrono::Utc, PgConnection};
 use uuid::Uuid;
 
-use crate::startup::ApplicationState;
+use super::{
+    consume_token, get_subscriber_id_from_token, get_token_list_id, TokenPurpose, TokenStatus,
+};
+use crate::{
+    consent::{record_confirm_consent, ConsentContext},
+    mailing_lists::confirm_membership,
+    startup::ApplicationState,
+};
 
-#[tracing::instrument(name = "Confirm a pending subscriber", skip(parameters, state))]
+#[tracing::instrument(
+    name = "Confirm a pending subscriber",
+    skip(parameters, state, headers)
+)]
 pub async fn confirm(
     State(state): State<ApplicationState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     parameters: Query<Parameters>,
 ) -> Result<(), StatusCode> {
-    if let Some(id) = get_subscriber_id_from_token(&state.pool, &parameters.subscription_token)
+    let mut transaction = state
+        .pool
+        .begin()
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let id = match get_subscriber_id_from_token(
+        &mut transaction,
+        &state.hmac_secret,
+        TokenPurpose::Confirmation,
+        &parameters.subscription_token,
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
     {
-        if confirm_subscriber(&state.pool, id).await.is_err() {
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-        return Ok(());
+        None => return Err(StatusCode::UNAUTHORIZED),
+        Some(TokenStatus::Expired) => return Err(StatusCode::GONE),
+        Some(TokenStatus::Consumed) => return Err(StatusCode::CONFLICT),
+        Some(TokenStatus::Valid(id)) => id,
+    };
+    consume_token(
+        &mut transaction,
+        &state.hmac_secret,
+        &parameters.subscription_token,
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let list_id = get_token_list_id(
+        &mut transaction,
+        &state.hmac_secret,
+        &parameters.subscription_token,
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Following any confirmation link proves the address belongs to the
+    // subscriber, but a list link only confirms that list: the newsletter
+    // keeps whatever status it had.
+    if let Some(list_id) = list_id {
+        confirm_membership(&mut transaction, id, list_id)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        verify_subscriber_email(&mut transaction, id)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    } else {
+        confirm_subscriber(&mut transaction, id)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+    let context = ConsentContext::new(state.rate_limiter.client_ip(peer, &headers), &headers);
+    record_confirm_consent(&mut transaction, id, list_id, &context)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if transaction.commit().await.is_err() {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
     }
-    Err(StatusCode::UNAUTHORIZED)
+    Ok(())
 }
 
 #[derive(Deserialize)]
@@ -30,13 +90,21 @@ pub struct Parameters {
     pub subscription_token: String,
 }
 
-#[tracing::instrument(name = "Mark subscriber as confirmed", skip(pool, subscriber_id))]
-pub async fn confirm_subscriber(pool: &PgPool, subscriber_id: Uuid) -> Result<(), sqlx::Error> {
+#[tracing::instrument(name = "Mark subscriber as confirmed", skip(connection, subscriber_id))]
+pub async fn confirm_subscriber(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
     sqlx::query!(
-        r#"UPDATE subscriptions SET status = 'confirmed' WHERE id = $1"#,
-        subscriber_id
+        r#"
+    UPDATE subscriptions
+    SET status = 'confirmed', email_verified_at = COALESCE(email_verified_at, $2)
+    WHERE id = $1 AND status = 'pending_confirmation'
+    "#,
+        subscriber_id,
+        Utc::now(),
     )
-    .execute(pool)
+    .execute(connection)
     .await
     .map_err(|e| {
         tracing::error!("Failed to execute query: {:?}", e);
@@ -45,20 +113,27 @@ pub async fn confirm_subscriber(pool: &PgPool, subscriber_id: Uuid) -> Result<()
     Ok(())
 }
 
-#[tracing::instrument(name = "Get subscriber_id from token", skip(pool, subscription_token))]
-pub async fn get_subscriber_id_from_token(
-    pool: &PgPool,
-    subscription_token: &str,
-) -> Result<Option<Uuid>, sqlx::Error> {
-    Ok(sqlx::query!(
-        r#"SELECT subscriber_id FROM subscription_tokens WHERE subscription_token = $1"#,
-        subscription_token,
+#[tracing::instrument(
+    name = "Mark subscriber email as verified",
+    skip(connection, subscriber_id)
+)]
+pub async fn verify_subscriber_email(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    UPDATE subscriptions SET email_verified_at = $2
+    WHERE id = $1 AND email_verified_at IS NULL
+    "#,
+        subscriber_id,
+        Utc::now(),
     )
-    .fetch_optional(pool)
+    .execute(connection)
     .await
     .map_err(|e| {
         tracing::error!("Failed to execute query: {:?}", e);
         e
-    })?
-    .map(|r| r.subscriber_id))
+    })?;
+    Ok(())
 }
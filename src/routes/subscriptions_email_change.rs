@@ -0,0 +1,582 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::Html,
+};
+use axum_extra::extract::{CookieJar, Form};
+use serde::Deserialize;
+use sqlx::{types::chrono::Utc, PgConnection};
+use uuid::Uuid;
+
+use super::{
+    consume_token, delete_tokens_with_purpose, generate_subscription_token,
+    get_session_subscriber_id, get_subscriber_by_email, get_subscriber_id_from_token,
+    get_token_email_change_id, store_email_revert_token, store_token, verify_csrf_token,
+    TokenPurpose, TokenStatus,
+};
+use crate::{domain::SubscriberEmail, email_client::EmailClient, startup::ApplicationState};
+
+#[derive(Deserialize)]
+pub struct EmailChangeFormData {
+    email: String,
+    #[serde(default)]
+    csrf_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct EmailChangeParameters {
+    pub token: String,
+}
+
+#[tracing::instrument(name = "Request an email address change", skip(jar, form_data, state))]
+pub async fn request_email_change(
+    State(state): State<ApplicationState>,
+    jar: CookieJar,
+    Form(form_data): Form<EmailChangeFormData>,
+) -> Result<Html<String>, StatusCode> {
+    let new_email = SubscriberEmail::parse(form_data.email).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let mut transaction = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let subscriber_id = get_session_subscriber_id(&mut transaction, &state.hmac_secret, &jar)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    if !verify_csrf_token(&state.hmac_secret, &jar, &form_data.csrf_token) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    // An address already on the list gets a notice instead of a link, and
+    // the answer is the same, so the form cannot be used to find out who
+    // is subscribed.
+    if get_subscriber_by_email(&mut transaction, &new_email)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .is_some()
+    {
+        send_address_taken_notice(&state.email_client, new_email, &state.base_url)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        return Ok(Html(page(EMAIL_CHANGE_REQUESTED)));
+    }
+    // A new request replaces any pending one, and links sent for the old
+    // request must not confirm the new address.
+    delete_tokens_with_purpose(&mut transaction, subscriber_id, TokenPurpose::EmailChange)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    record_email_change(&mut transaction, subscriber_id, &new_email)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let token = generate_subscription_token();
+    store_token(
+        &mut transaction,
+        subscriber_id,
+        &token,
+        TokenPurpose::EmailChange,
+        &state.hmac_secret,
+        state.subscription_token_expiry,
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    send_email_change_confirmation(&state.email_client, new_email, &state.base_url, &token)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if transaction.commit().await.is_err() {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    Ok(Html(page(EMAIL_CHANGE_REQUESTED)))
+}
+
+const EMAIL_CHANGE_REQUESTED: &str = "We sent a confirmation link to your new address. \
+    Your address changes once you click it.";
+
+/// Opening the link only shows a button, so mail scanners that follow links
+/// cannot change the address on the subscriber's behalf.
+#[tracing::instrument(
+    name = "Show the email change confirmation page",
+    skip(parameters, state)
+)]
+pub async fn confirm_email_change_form(
+    State(state): State<ApplicationState>,
+    parameters: Query<EmailChangeParameters>,
+) -> Result<Html<String>, StatusCode> {
+    check_token(&state, TokenPurpose::EmailChange, &parameters.token).await?;
+    Ok(Html(form_page(
+        "Do you want to use this address for our newsletter?",
+        "/subscriptions/email/confirm",
+        &parameters.token,
+        "Confirm new address",
+    )))
+}
+
+#[tracing::instrument(name = "Show the email change revert page", skip(parameters, state))]
+pub async fn revert_email_change_form(
+    State(state): State<ApplicationState>,
+    parameters: Query<EmailChangeParameters>,
+) -> Result<Html<String>, StatusCode> {
+    check_token(&state, TokenPurpose::EmailRevert, &parameters.token).await?;
+    Ok(Html(form_page(
+        "Do you want to undo the change of your newsletter address?",
+        "/subscriptions/email/revert",
+        &parameters.token,
+        "Restore previous address",
+    )))
+}
+
+/// Fails the way acting on the link would, without using it up.
+async fn check_token(
+    state: &ApplicationState,
+    purpose: TokenPurpose,
+    token: &str,
+) -> Result<(), StatusCode> {
+    let mut connection = state
+        .pool
+        .acquire()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    match get_subscriber_id_from_token(&mut connection, &state.hmac_secret, purpose, token)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    {
+        None => Err(StatusCode::UNAUTHORIZED),
+        Some(TokenStatus::Expired) => Err(StatusCode::GONE),
+        Some(TokenStatus::Consumed) => Err(StatusCode::CONFLICT),
+        Some(TokenStatus::Valid(_)) => Ok(()),
+    }
+}
+
+#[tracing::instrument(name = "Confirm an email address change", skip(parameters, state))]
+pub async fn confirm_email_change(
+    State(state): State<ApplicationState>,
+    parameters: Query<EmailChangeParameters>,
+) -> Result<Html<String>, StatusCode> {
+    let mut transaction = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let subscriber_id = match get_subscriber_id_from_token(
+        &mut transaction,
+        &state.hmac_secret,
+        TokenPurpose::EmailChange,
+        &parameters.token,
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    {
+        None => return Err(StatusCode::UNAUTHORIZED),
+        Some(TokenStatus::Expired) => return Err(StatusCode::GONE),
+        Some(TokenStatus::Consumed) => return Err(StatusCode::CONFLICT),
+        Some(TokenStatus::Valid(id)) => id,
+    };
+    consume_token(&mut transaction, &state.hmac_secret, &parameters.token)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let change = get_pending_email_change(&mut transaction, subscriber_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::GONE)?;
+    let new_email =
+        SubscriberEmail::parse(change.new_email).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // The new address may have joined the list since the change was requested.
+    update_subscriber_email(&mut transaction, subscriber_id, &new_email)
+        .await
+        .map_err(conflict_on_unique_violation)?;
+    confirm_email_change_record(&mut transaction, change.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let revert_token = generate_subscription_token();
+    store_email_revert_token(
+        &mut transaction,
+        subscriber_id,
+        change.id,
+        &revert_token,
+        &state.hmac_secret,
+        state.subscription_token_expiry,
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let old_email =
+        SubscriberEmail::parse(change.old_email).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    send_email_change_notice(
+        &state.email_client,
+        old_email,
+        &state.base_url,
+        &revert_token,
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if transaction.commit().await.is_err() {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    Ok(Html(page("Your email address has been updated.")))
+}
+
+#[tracing::instrument(name = "Revert an email address change", skip(parameters, state))]
+pub async fn revert_email_change(
+    State(state): State<ApplicationState>,
+    parameters: Query<EmailChangeParameters>,
+) -> Result<Html<String>, StatusCode> {
+    let mut transaction = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let subscriber_id = match get_subscriber_id_from_token(
+        &mut transaction,
+        &state.hmac_secret,
+        TokenPurpose::EmailRevert,
+        &parameters.token,
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    {
+        None => return Err(StatusCode::UNAUTHORIZED),
+        Some(TokenStatus::Expired) => return Err(StatusCode::GONE),
+        Some(TokenStatus::Consumed) => return Err(StatusCode::CONFLICT),
+        Some(TokenStatus::Valid(id)) => id,
+    };
+    // Tokens issued before they were bound to a change cannot tell which
+    // change they undo.
+    let change_id =
+        get_token_email_change_id(&mut transaction, &state.hmac_secret, &parameters.token)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or(StatusCode::GONE)?;
+    consume_token(&mut transaction, &state.hmac_secret, &parameters.token)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let change = get_revertible_email_change(&mut transaction, subscriber_id, change_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::GONE)?;
+    let old_email =
+        SubscriberEmail::parse(change.old_email).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    update_subscriber_email(&mut transaction, subscriber_id, &old_email)
+        .await
+        .map_err(conflict_on_unique_violation)?;
+    // Changes confirmed after this one were made by whoever took over the
+    // address, so they are undone too, along with anything still pending.
+    revert_email_changes_since(&mut transaction, subscriber_id, change.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    delete_pending_email_changes(&mut transaction, subscriber_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    delete_tokens_with_purpose(&mut transaction, subscriber_id, TokenPurpose::EmailChange)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Whoever changed the address may still hold a preference center session.
+    delete_preference_sessions(&mut transaction, subscriber_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if transaction.commit().await.is_err() {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    Ok(Html(page("Your previous email address has been restored.")))
+}
+
+fn conflict_on_unique_violation(e: sqlx::Error) -> StatusCode {
+    match e {
+        sqlx::Error::Database(e) if e.is_unique_violation() => StatusCode::CONFLICT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn page(message: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head><meta charset="utf-8"><title>Email address</title></head>
+<body>
+    <p>{}</p>
+</body>
+</html>"#,
+        message
+    )
+}
+
+// Only valid tokens get this far, so `token` is safe to put in the page.
+fn form_page(message: &str, action: &str, token: &str, button: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head><meta charset="utf-8"><title>Email address</title></head>
+<body>
+    <p>{}</p>
+    <form action="{}?token={}" method="post">
+        <button type="submit">{}</button>
+    </form>
+</body>
+</html>"#,
+        message, action, token, button
+    )
+}
+
+#[tracing::instrument(
+    name = "Send an email change confirmation",
+    skip(email_client, new_email, token)
+)]
+pub async fn send_email_change_confirmation(
+    email_client: &EmailClient,
+    new_email: SubscriberEmail,
+    base_url: &str,
+    token: &str,
+) -> Result<(), String> {
+    let confirmation_link = format!("{}/subscriptions/email/confirm?token={}", base_url, token);
+    email_client
+        .send_email(
+            new_email,
+            "Confirm your new email address",
+            &format!(
+                "Click <a href=\"{}\">here</a> to use this address for our newsletter.",
+                confirmation_link
+            ),
+        )
+        .await
+}
+
+#[tracing::instrument(
+    name = "Notify the old address of an email change",
+    skip(email_client, old_email, token)
+)]
+pub async fn send_email_change_notice(
+    email_client: &EmailClient,
+    old_email: SubscriberEmail,
+    base_url: &str,
+    token: &str,
+) -> Result<(), String> {
+    let revert_link = format!("{}/subscriptions/email/revert?token={}", base_url, token);
+    email_client
+        .send_email(
+            old_email,
+            "Your email address was changed",
+            &format!(
+                "The address of your newsletter subscription was changed.<br />
+    If this wasn't you, click <a href=\"{}\">here</a> to undo the change.",
+                revert_link
+            ),
+        )
+        .await
+}
+
+#[tracing::instrument(
+    name = "Notify an address that is already subscribed",
+    skip(email_client, email)
+)]
+pub async fn send_address_taken_notice(
+    email_client: &EmailClient,
+    email: SubscriberEmail,
+    base_url: &str,
+) -> Result<(), String> {
+    let preferences_link = format!("{}/subscriptions/preferences", base_url);
+    email_client
+        .send_email(
+            email,
+            "Someone tried to use your email address",
+            &format!(
+                "Someone asked to move a newsletter subscription to this address, \
+    which is already subscribed, so nothing was changed.<br />
+    You can manage your own subscription <a href=\"{}\">here</a>.",
+                preferences_link
+            ),
+        )
+        .await
+}
+
+pub struct EmailChange {
+    pub id: Uuid,
+    pub old_email: String,
+    pub new_email: String,
+}
+
+#[tracing::instrument(
+    name = "Record a pending email change",
+    skip(connection, subscriber_id, new_email)
+)]
+pub async fn record_email_change(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    new_email: &SubscriberEmail,
+) -> Result<(), sqlx::Error> {
+    delete_pending_email_changes(connection, subscriber_id).await?;
+    sqlx::query!(
+        r#"
+    INSERT INTO email_changes (id, subscriber_id, old_email, new_email, requested_at)
+    SELECT $1, id, display_email, $3, $4 FROM subscriptions WHERE id = $2
+    "#,
+        Uuid::new_v4(),
+        subscriber_id,
+        new_email.display(),
+        Utc::now(),
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Delete pending email changes", skip(connection, subscriber_id))]
+pub async fn delete_pending_email_changes(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"DELETE FROM email_changes WHERE subscriber_id = $1 AND confirmed_at IS NULL"#,
+        subscriber_id,
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Get the pending email change", skip(connection, subscriber_id))]
+pub async fn get_pending_email_change(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+) -> Result<Option<EmailChange>, sqlx::Error> {
+    sqlx::query_as!(
+        EmailChange,
+        r#"
+    SELECT id, old_email, new_email FROM email_changes
+    WHERE subscriber_id = $1 AND confirmed_at IS NULL
+    "#,
+        subscriber_id,
+    )
+    .fetch_optional(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+#[tracing::instrument(
+    name = "Get a revertible email change",
+    skip(connection, subscriber_id, change_id)
+)]
+pub async fn get_revertible_email_change(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    change_id: Uuid,
+) -> Result<Option<EmailChange>, sqlx::Error> {
+    sqlx::query_as!(
+        EmailChange,
+        r#"
+    SELECT id, old_email, new_email FROM email_changes
+    WHERE id = $1 AND subscriber_id = $2 AND confirmed_at IS NOT NULL AND reverted_at IS NULL
+    "#,
+        change_id,
+        subscriber_id,
+    )
+    .fetch_optional(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+#[tracing::instrument(
+    name = "Mark an email change as confirmed",
+    skip(connection, change_id)
+)]
+pub async fn confirm_email_change_record(
+    connection: &mut PgConnection,
+    change_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE email_changes SET confirmed_at = $2 WHERE id = $1"#,
+        change_id,
+        Utc::now(),
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// Marks a confirmed change and every change confirmed after it as reverted.
+#[tracing::instrument(
+    name = "Revert email changes",
+    skip(connection, subscriber_id, change_id)
+)]
+pub async fn revert_email_changes_since(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    change_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    UPDATE email_changes SET reverted_at = $3
+    WHERE subscriber_id = $1 AND reverted_at IS NULL
+        AND confirmed_at >= (SELECT confirmed_at FROM email_changes WHERE id = $2)
+    "#,
+        subscriber_id,
+        change_id,
+        Utc::now(),
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Update the subscriber's email address",
+    skip(connection, subscriber_id, email)
+)]
+pub async fn update_subscriber_email(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    email: &SubscriberEmail,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscriptions SET email = $2, display_email = $3 WHERE id = $1"#,
+        subscriber_id,
+        email.as_ref(),
+        email.display(),
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Delete preference sessions of a subscriber",
+    skip(connection, subscriber_id)
+)]
+pub async fn delete_preference_sessions(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"DELETE FROM preference_sessions WHERE subscriber_id = $1"#,
+        subscriber_id,
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
@@ -0,0 +1,352 @@
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Form, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    PgConnection,
+};
+use uuid::Uuid;
+
+use super::{
+    consume_token, enforce_rate_limits, generate_subscription_token, get_last_token_time,
+    get_subscriber_by_email, get_subscriber_id_from_token, store_token, TokenPurpose, TokenStatus,
+};
+use crate::{
+    consent::{get_consent_events, ConsentEventRecord},
+    domain::SubscriberEmail,
+    email_client::EmailClient,
+    startup::ApplicationState,
+};
+
+#[derive(Deserialize)]
+pub struct DataExportFormData {
+    email: String,
+}
+
+#[derive(Deserialize)]
+pub struct DataExportParameters {
+    pub token: String,
+}
+
+/// Everything stored about one subscriber. Token hashes and session ids are
+/// left out: they are credentials, not personal data.
+#[derive(Serialize)]
+pub struct SubscriberExport {
+    pub exported_at: DateTime<Utc>,
+    pub subscriber: SubscriberRecord,
+    pub tokens: Vec<TokenRecord>,
+    pub list_memberships: Vec<ListMembershipRecord>,
+    pub consent_events: Vec<ConsentEventRecord>,
+    pub email_changes: Vec<EmailChangeRecord>,
+    pub preference_sessions: Vec<PreferenceSessionRecord>,
+    pub deliveries: Vec<DeliveryRecord>,
+}
+
+#[derive(Serialize)]
+pub struct SubscriberRecord {
+    pub id: Uuid,
+    pub email: String,
+    pub display_email: String,
+    pub name: String,
+    pub status: String,
+    pub subscribed_at: DateTime<Utc>,
+    pub email_verified_at: Option<DateTime<Utc>>,
+    pub delivery_frequency: String,
+    pub language: String,
+    pub topics: Vec<String>,
+    pub attributes: serde_json::Value,
+    pub tags: Vec<String>,
+    pub unsubscribed_at: Option<DateTime<Utc>>,
+    pub unsubscribe_reason: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TokenRecord {
+    pub purpose: String,
+    pub list: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub consumed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct ListMembershipRecord {
+    pub list: String,
+    pub status: String,
+    pub subscribed_at: DateTime<Utc>,
+    pub confirmed_at: Option<DateTime<Utc>>,
+    pub left_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct EmailChangeRecord {
+    pub old_email: String,
+    pub new_email: String,
+    pub requested_at: DateTime<Utc>,
+    pub confirmed_at: Option<DateTime<Utc>>,
+    pub reverted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct PreferenceSessionRecord {
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct DeliveryRecord {
+    pub issue_id: Uuid,
+    pub subject: String,
+    pub status: String,
+    pub queued_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+/// Requests are rate limited like subscriptions, and links are not resent
+/// within the confirmation resend cooldown, so the endpoint cannot be used to
+/// flood an inbox.
+#[tracing::instrument(name = "Request a data export", skip(form_data, state, headers))]
+pub async fn request_data_export(
+    State(state): State<ApplicationState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Form(form_data): Form<DataExportFormData>,
+) -> Response {
+    let client_ip = state.rate_limiter.client_ip(peer, &headers);
+    if let Err(e) = enforce_rate_limits(&state, "export", client_ip, &form_data.email).await {
+        return e.into_response(false);
+    }
+    send_data_export_link(&state, form_data.email)
+        .await
+        .into_response()
+}
+
+async fn send_data_export_link(state: &ApplicationState, email: String) -> Result<(), StatusCode> {
+    let email = SubscriberEmail::parse(email).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let mut transaction = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Unknown and cooling-down addresses get the same empty 200, so the
+    // endpoint does not reveal who is on the list.
+    let Some(subscriber) = get_subscriber_by_email(&mut transaction, &email)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    else {
+        return Ok(());
+    };
+    let last_sent_at =
+        get_last_token_time(&mut transaction, subscriber.id, TokenPurpose::DataExport)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if last_sent_at.is_some_and(|sent_at| sent_at + state.resend_cooldown > Utc::now()) {
+        tracing::info!("A data export link was sent recently, skipping");
+        return Ok(());
+    }
+    let token = generate_subscription_token();
+    store_token(
+        &mut transaction,
+        subscriber.id,
+        &token,
+        TokenPurpose::DataExport,
+        &state.hmac_secret,
+        state.preferences.magic_link_expiry(),
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    send_data_export_email(&state.email_client, email, &state.base_url, &token)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if transaction.commit().await.is_err() {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    Ok(())
+}
+
+#[tracing::instrument(name = "Send a data export link", skip(email_client, email, token))]
+pub async fn send_data_export_email(
+    email_client: &EmailClient,
+    email: SubscriberEmail,
+    base_url: &str,
+    token: &str,
+) -> Result<(), String> {
+    let export_link = format!("{}/subscriptions/export?token={}", base_url, token);
+    email_client
+        .send_email(
+            email,
+            "Your data export",
+            &format!(
+                "Click <a href=\"{}\">here</a> to download everything we store about you.<br />
+    The link can be used once and expires shortly.",
+                export_link
+            ),
+        )
+        .await
+}
+
+#[tracing::instrument(name = "Download a data export", skip(parameters, state))]
+pub async fn download_data_export(
+    State(state): State<ApplicationState>,
+    parameters: Query<DataExportParameters>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let mut transaction = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let subscriber_id = match get_subscriber_id_from_token(
+        &mut transaction,
+        &state.hmac_secret,
+        TokenPurpose::DataExport,
+        &parameters.token,
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    {
+        None => return Err(StatusCode::UNAUTHORIZED),
+        Some(TokenStatus::Expired) => return Err(StatusCode::GONE),
+        Some(TokenStatus::Consumed) => return Err(StatusCode::CONFLICT),
+        Some(TokenStatus::Valid(id)) => id,
+    };
+    consume_token(&mut transaction, &state.hmac_secret, &parameters.token)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let export = build_subscriber_export(&mut transaction, subscriber_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    if transaction.commit().await.is_err() {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    Ok(export_response(export))
+}
+
+pub fn export_response(export: SubscriberExport) -> impl IntoResponse {
+    (
+        [(
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"subscriber-{}.json\"",
+                export.subscriber.id
+            ),
+        )],
+        Json(export),
+    )
+}
+
+#[tracing::instrument(name = "Build a subscriber data export", skip(connection))]
+pub async fn build_subscriber_export(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+) -> Result<Option<SubscriberExport>, sqlx::Error> {
+    let Some(subscriber) = sqlx::query_as!(
+        SubscriberRecord,
+        r#"
+    SELECT id, email, display_email, name, status, subscribed_at, email_verified_at,
+        delivery_frequency, language, topics, attributes, tags, unsubscribed_at,
+        unsubscribe_reason
+    FROM subscriptions WHERE id = $1
+    "#,
+        subscriber_id,
+    )
+    .fetch_optional(&mut *connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    else {
+        return Ok(None);
+    };
+    let tokens = sqlx::query_as!(
+        TokenRecord,
+        r#"
+    SELECT t.purpose, l.slug AS "list?", t.created_at, t.expires_at, t.consumed_at
+    FROM subscription_tokens t LEFT JOIN lists l ON l.id = t.list_id
+    WHERE t.subscriber_id = $1 ORDER BY t.created_at
+    "#,
+        subscriber_id,
+    )
+    .fetch_all(&mut *connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    let list_memberships = sqlx::query_as!(
+        ListMembershipRecord,
+        r#"
+    SELECT l.slug AS list, m.status, m.subscribed_at, m.confirmed_at, m.left_at
+    FROM list_memberships m JOIN lists l ON l.id = m.list_id
+    WHERE m.subscriber_id = $1 ORDER BY l.slug
+    "#,
+        subscriber_id,
+    )
+    .fetch_all(&mut *connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    let consent_events = get_consent_events(&mut *connection, subscriber_id).await?;
+    let email_changes = sqlx::query_as!(
+        EmailChangeRecord,
+        r#"
+    SELECT old_email, new_email, requested_at, confirmed_at, reverted_at
+    FROM email_changes WHERE subscriber_id = $1 ORDER BY requested_at
+    "#,
+        subscriber_id,
+    )
+    .fetch_all(&mut *connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    let preference_sessions = sqlx::query_as!(
+        PreferenceSessionRecord,
+        r#"
+    SELECT created_at, expires_at FROM preference_sessions
+    WHERE subscriber_id = $1 ORDER BY created_at
+    "#,
+        subscriber_id,
+    )
+    .fetch_all(&mut *connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    let deliveries = sqlx::query_as!(
+        DeliveryRecord,
+        r#"
+    SELECT d.issue_id, i.subject, d.status, d.queued_at, d.delivered_at
+    FROM issue_deliveries d JOIN newsletter_issues i ON i.id = d.issue_id
+    WHERE d.subscriber_id = $1 ORDER BY d.queued_at
+    "#,
+        subscriber_id,
+    )
+    .fetch_all(&mut *connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(Some(SubscriberExport {
+        exported_at: Utc::now(),
+        subscriber,
+        tokens,
+        list_memberships,
+        consent_events,
+        email_changes,
+        preference_sessions,
+        deliveries,
+    }))
+}
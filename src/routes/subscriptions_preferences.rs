@@ -0,0 +1,519 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{Html, Redirect},
+};
+use axum_extra::extract::{
+    cookie::{Cookie, CookieJar, SameSite},
+    Form,
+};
+use serde::Deserialize;
+use sqlx::{types::chrono::Utc, PgConnection};
+use uuid::Uuid;
+
+use super::{
+    consume_token, delete_confirmation_tokens, generate_subscription_token,
+    get_subscriber_by_email, get_subscriber_id_from_token, store_token, TokenPurpose, TokenStatus,
+};
+use crate::{
+    configuration::PreferenceSettings,
+    domain::{DeliveryFrequency, SubscriberEmail, SubscriberName, SubscriberPreferences},
+    email_client::EmailClient,
+    hmac_secret::HmacSecret,
+    mailing_lists::{get_joined_lists, get_lists_by_slug, leave_membership, MailingList},
+    startup::ApplicationState,
+};
+
+const SESSION_COOKIE: &str = "preferences_session";
+
+#[derive(Deserialize)]
+pub struct MagicLinkFormData {
+    email: String,
+}
+
+#[derive(Deserialize)]
+pub struct LeaveListFormData {
+    list: String,
+    #[serde(default)]
+    csrf_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct MagicLinkParameters {
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+pub struct PreferencesFormData {
+    name: String,
+    #[serde(default)]
+    topics: Vec<String>,
+    delivery_frequency: String,
+    language: String,
+    #[serde(default)]
+    csrf_token: String,
+}
+
+fn parse_preferences(
+    value: PreferencesFormData,
+    settings: &PreferenceSettings,
+) -> Result<SubscriberPreferences, String> {
+    if let Some(topic) = value.topics.iter().find(|t| !settings.topics.contains(t)) {
+        return Err(format!("{} is not a known topic.", topic));
+    }
+    if !settings.languages.contains(&value.language) {
+        return Err(format!("{} is not a supported language.", value.language));
+    }
+    Ok(SubscriberPreferences {
+        name: SubscriberName::parse(value.name)?,
+        topics: value.topics,
+        delivery_frequency: DeliveryFrequency::parse(value.delivery_frequency)?,
+        language: value.language,
+    })
+}
+
+#[tracing::instrument(name = "Request a preferences magic link", skip(form_data, state))]
+pub async fn request_magic_link(
+    State(state): State<ApplicationState>,
+    Form(form_data): Form<MagicLinkFormData>,
+) -> Result<(), StatusCode> {
+    let email = SubscriberEmail::parse(form_data.email).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let mut transaction = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Unknown and unconfirmed addresses get the same empty 200, so the
+    // endpoint does not reveal who is on the list. Subscribers who only
+    // confirmed a list have a verified address too and may log in.
+    let Some(subscriber) = get_subscriber_by_email(&mut transaction, &email)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .filter(|s| {
+            s.status == "confirmed" || (s.status == "pending_confirmation" && s.email_verified)
+        })
+    else {
+        return Ok(());
+    };
+    let token = generate_subscription_token();
+    store_token(
+        &mut transaction,
+        subscriber.id,
+        &token,
+        TokenPurpose::MagicLink,
+        &state.hmac_secret,
+        state.preferences.magic_link_expiry(),
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    send_magic_link_email(&state.email_client, email, &state.base_url, &token)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if transaction.commit().await.is_err() {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Send a preferences magic link",
+    skip(email_client, email, token)
+)]
+pub async fn send_magic_link_email(
+    email_client: &EmailClient,
+    email: SubscriberEmail,
+    base_url: &str,
+    token: &str,
+) -> Result<(), String> {
+    let login_link = format!(
+        "{}/subscriptions/preferences/login?token={}",
+        base_url, token
+    );
+    email_client
+        .send_email(
+            email,
+            "Manage your subscription",
+            &format!(
+                "Click <a href=\"{}\">here</a> to manage your subscription.<br />
+    The link can be used once and expires shortly.",
+                login_link
+            ),
+        )
+        .await
+}
+
+#[tracing::instrument(name = "Log in with a magic link", skip(jar, parameters, state))]
+pub async fn login_with_magic_link(
+    State(state): State<ApplicationState>,
+    jar: CookieJar,
+    parameters: Query<MagicLinkParameters>,
+) -> Result<(CookieJar, Redirect), StatusCode> {
+    let mut transaction = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let subscriber_id = match get_subscriber_id_from_token(
+        &mut transaction,
+        &state.hmac_secret,
+        TokenPurpose::MagicLink,
+        &parameters.token,
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    {
+        None => return Err(StatusCode::UNAUTHORIZED),
+        Some(TokenStatus::Expired) => return Err(StatusCode::GONE),
+        Some(TokenStatus::Consumed) => return Err(StatusCode::CONFLICT),
+        Some(TokenStatus::Valid(id)) => id,
+    };
+    consume_token(&mut transaction, &state.hmac_secret, &parameters.token)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let session_id = generate_subscription_token();
+    store_session(
+        &mut transaction,
+        &state.hmac_secret,
+        subscriber_id,
+        &session_id,
+        state.preferences.session_expiry(),
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if transaction.commit().await.is_err() {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    let cookie = Cookie::build((SESSION_COOKIE, session_id))
+        .path("/subscriptions/preferences")
+        .http_only(true)
+        .secure(state.base_url.starts_with("https://"))
+        .same_site(SameSite::Lax);
+    Ok((jar.add(cookie), Redirect::to("/subscriptions/preferences")))
+}
+
+#[tracing::instrument(name = "Show the preference center", skip(jar, state))]
+pub async fn preferences_form(
+    State(state): State<ApplicationState>,
+    jar: CookieJar,
+) -> Result<Html<String>, StatusCode> {
+    let mut connection = state
+        .pool
+        .acquire()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let subscriber_id = get_session_subscriber_id(&mut connection, &state.hmac_secret, &jar)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let preferences = get_preferences(&mut connection, subscriber_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let lists = get_joined_lists(&mut connection, subscriber_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let csrf_token = csrf_token(&state.hmac_secret, &jar).ok_or(StatusCode::UNAUTHORIZED)?;
+    Ok(Html(render_preferences(
+        &preferences,
+        &lists,
+        &state.preferences,
+        &csrf_token,
+    )))
+}
+
+#[tracing::instrument(name = "Leave a mailing list", skip(jar, form_data, state))]
+pub async fn leave_list(
+    State(state): State<ApplicationState>,
+    jar: CookieJar,
+    Form(form_data): Form<LeaveListFormData>,
+) -> Result<Redirect, StatusCode> {
+    let mut transaction = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let subscriber_id = get_session_subscriber_id(&mut transaction, &state.hmac_secret, &jar)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    if !verify_csrf_token(&state.hmac_secret, &jar, &form_data.csrf_token) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let list = get_lists_by_slug(&mut transaction, &[form_data.list])
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .pop()
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    leave_membership(&mut transaction, subscriber_id, list.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // An outstanding confirmation link must not put the subscriber back.
+    delete_confirmation_tokens(&mut transaction, subscriber_id, Some(list.id))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if transaction.commit().await.is_err() {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    Ok(Redirect::to("/subscriptions/preferences"))
+}
+
+#[tracing::instrument(name = "Update subscriber preferences", skip(jar, form_data, state))]
+pub async fn update_preferences(
+    State(state): State<ApplicationState>,
+    jar: CookieJar,
+    Form(form_data): Form<PreferencesFormData>,
+) -> Result<Redirect, StatusCode> {
+    let mut connection = state
+        .pool
+        .acquire()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let subscriber_id = get_session_subscriber_id(&mut connection, &state.hmac_secret, &jar)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    if !verify_csrf_token(&state.hmac_secret, &jar, &form_data.csrf_token) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let preferences =
+        parse_preferences(form_data, &state.preferences).map_err(|_| StatusCode::BAD_REQUEST)?;
+    store_preferences(&mut connection, subscriber_id, &preferences)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Redirect::to("/subscriptions/preferences"))
+}
+
+#[tracing::instrument(
+    name = "Store a preferences session",
+    skip(connection, hmac_secret, subscriber_id, session_id)
+)]
+pub async fn store_session(
+    connection: &mut PgConnection,
+    hmac_secret: &HmacSecret,
+    subscriber_id: Uuid,
+    session_id: &str,
+    expiry: Duration,
+) -> Result<(), sqlx::Error> {
+    let created_at = Utc::now();
+    sqlx::query!(
+        r#"
+    INSERT INTO preference_sessions (session_hash, subscriber_id, created_at, expires_at)
+    VALUES ($1, $2, $3, $4)
+    "#,
+        hmac_secret.sign(session_id),
+        subscriber_id,
+        created_at,
+        created_at + expiry,
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Get subscriber_id from the preferences session",
+    skip(connection, hmac_secret, jar)
+)]
+pub async fn get_session_subscriber_id(
+    connection: &mut PgConnection,
+    hmac_secret: &HmacSecret,
+    jar: &CookieJar,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let Some(cookie) = jar.get(SESSION_COOKIE) else {
+        return Ok(None);
+    };
+    Ok(sqlx::query!(
+        r#"
+    SELECT subscriber_id FROM preference_sessions
+    WHERE session_hash = $1 AND expires_at > $2
+    "#,
+        hmac_secret.sign(cookie.value()),
+        Utc::now(),
+    )
+    .fetch_optional(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .map(|r| r.subscriber_id))
+}
+
+/// The token the preference forms must send back. It is derived from the
+/// session, so another site can neither read nor guess it, and it stops
+/// working when the session ends.
+fn csrf_token(hmac_secret: &HmacSecret, jar: &CookieJar) -> Option<String> {
+    jar.get(SESSION_COOKIE)
+        .map(|cookie| hmac_secret.sign(&csrf_message(cookie.value())))
+}
+
+/// Whether a state-changing preference request came from a form rendered
+/// for the session it carries.
+pub fn verify_csrf_token(hmac_secret: &HmacSecret, jar: &CookieJar, csrf_token: &str) -> bool {
+    jar.get(SESSION_COOKIE)
+        .is_some_and(|cookie| hmac_secret.verify(&csrf_message(cookie.value()), csrf_token))
+}
+
+fn csrf_message(session_id: &str) -> String {
+    format!("csrf:{}", session_id)
+}
+
+pub struct StoredPreferences {
+    pub email: String,
+    pub name: String,
+    pub topics: Vec<String>,
+    pub delivery_frequency: String,
+    pub language: String,
+}
+
+#[tracing::instrument(name = "Get subscriber preferences", skip(connection, subscriber_id))]
+pub async fn get_preferences(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+) -> Result<StoredPreferences, sqlx::Error> {
+    sqlx::query_as!(
+        StoredPreferences,
+        r#"
+    SELECT display_email AS email, name, topics, delivery_frequency, language FROM subscriptions
+    WHERE id = $1
+    "#,
+        subscriber_id,
+    )
+    .fetch_one(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+#[tracing::instrument(
+    name = "Save subscriber preferences",
+    skip(connection, subscriber_id, preferences)
+)]
+pub async fn store_preferences(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    preferences: &SubscriberPreferences,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    UPDATE subscriptions
+    SET name = $2, topics = $3, delivery_frequency = $4, language = $5
+    WHERE id = $1
+    "#,
+        subscriber_id,
+        preferences.name.as_ref(),
+        &preferences.topics,
+        preferences.delivery_frequency.as_ref(),
+        preferences.language,
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+fn render_preferences(
+    preferences: &StoredPreferences,
+    lists: &[MailingList],
+    settings: &PreferenceSettings,
+    csrf_token: &str,
+) -> String {
+    let topics: String = settings
+        .topics
+        .iter()
+        .map(|topic| {
+            format!(
+                r#"<label><input type="checkbox" name="topics" value="{0}"{1}> {0}</label>"#,
+                html_escape(topic),
+                if preferences.topics.contains(topic) {
+                    " checked"
+                } else {
+                    ""
+                }
+            )
+        })
+        .collect();
+    let frequencies: String = [
+        DeliveryFrequency::Immediate,
+        DeliveryFrequency::WeeklyDigest,
+    ]
+    .iter()
+    .map(|frequency| option(frequency.as_ref(), &preferences.delivery_frequency))
+    .collect();
+    let languages: String = settings
+        .languages
+        .iter()
+        .map(|language| option(language, &preferences.language))
+        .collect();
+    let lists: String = lists
+        .iter()
+        .map(|list| {
+            format!(
+                r#"
+    <form action="/subscriptions/preferences/lists/leave" method="post">
+        <input type="hidden" name="csrf_token" value="{}">
+        <input type="hidden" name="list" value="{}">
+        <button type="submit">Leave {}</button>
+    </form>"#,
+                html_escape(csrf_token),
+                html_escape(&list.slug),
+                html_escape(&list.name),
+            )
+        })
+        .collect();
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head><meta charset="utf-8"><title>Your preferences</title></head>
+<body>
+    <p>Preferences for {}</p>
+    <form action="/subscriptions/preferences" method="post">
+        <input type="hidden" name="csrf_token" value="{5}">
+        <label>Name <input type="text" name="name" value="{}"></label>
+        <fieldset><legend>Topics</legend>{}</fieldset>
+        <label>Frequency <select name="delivery_frequency">{}</select></label>
+        <label>Language <select name="language">{}</select></label>
+        <button type="submit">Save</button>
+    </form>
+    <form action="/subscriptions/preferences/email" method="post">
+        <input type="hidden" name="csrf_token" value="{5}">
+        <label>New email address <input type="email" name="email"></label>
+        <button type="submit">Change address</button>
+    </form>{6}
+</body>
+</html>"#,
+        html_escape(&preferences.email),
+        html_escape(&preferences.name),
+        topics,
+        frequencies,
+        languages,
+        html_escape(csrf_token),
+        lists,
+    )
+}
+
+fn option(value: &str, selected: &str) -> String {
+    format!(
+        r#"<option value="{0}"{1}>{0}</option>"#,
+        html_escape(value),
+        if value == selected { " selected" } else { "" }
+    )
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#x27;")
+}
@@ -0,0 +1,134 @@
+use axum::{
+    extract::{Form, State},
+    http::StatusCode,
+};
+use serde::Deserialize;
+use sqlx::types::chrono::Utc;
+
+use super::{
+    delete_confirmation_tokens, generate_subscription_token, get_last_token_time,
+    get_subscriber_by_email, send_confirmation_email, send_list_confirmation_email,
+    store_list_token, store_token, unsubscribe_link, TokenPurpose,
+};
+use crate::{domain::SubscriberEmail, mailing_lists::get_pending_lists, startup::ApplicationState};
+
+#[derive(Deserialize)]
+pub struct ResendConfirmationFormData {
+    email: String,
+}
+
+/// Sends fresh confirmation links for everything still pending: one per
+/// pending list membership or, for subscribers who signed up without a
+/// list, the newsletter confirmation. Older links stop working.
+#[tracing::instrument(name = "Resend a confirmation email", skip(form_data, state))]
+pub async fn resend_confirmation(
+    State(state): State<ApplicationState>,
+    Form(form_data): Form<ResendConfirmationFormData>,
+) -> Result<(), StatusCode> {
+    let email = SubscriberEmail::parse(form_data.email).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let mut transaction = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Unknown, confirmed and cooling-down addresses all get the same empty
+    // 200, so the endpoint does not reveal who is on the list.
+    let Some(subscriber) = get_subscriber_by_email(&mut transaction, &email)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .filter(|s| matches!(s.status.as_str(), "pending_confirmation" | "confirmed"))
+    else {
+        return Ok(());
+    };
+    let pending_lists = get_pending_lists(&mut transaction, subscriber.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // A verified address that is still pending only ever signed up for
+    // lists, so there is no newsletter confirmation to resend.
+    let newsletter_pending = subscriber.status == "pending_confirmation"
+        && !subscriber.email_verified
+        && pending_lists.is_empty();
+    if !newsletter_pending && pending_lists.is_empty() {
+        return Ok(());
+    }
+    let last_sent_at =
+        get_last_token_time(&mut transaction, subscriber.id, TokenPurpose::Confirmation)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if last_sent_at.is_some_and(|sent_at| sent_at + state.resend_cooldown > Utc::now()) {
+        tracing::info!("Confirmation email was resent recently, skipping");
+        return Ok(());
+    }
+    let newsletter_token = if newsletter_pending {
+        delete_confirmation_tokens(&mut transaction, subscriber.id, None)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let subscription_token = generate_subscription_token();
+        store_token(
+            &mut transaction,
+            subscriber.id,
+            &subscription_token,
+            TokenPurpose::Confirmation,
+            &state.hmac_secret,
+            state.subscription_token_expiry,
+        )
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        Some(subscription_token)
+    } else {
+        None
+    };
+    let mut list_tokens = Vec::with_capacity(pending_lists.len());
+    for list in &pending_lists {
+        delete_confirmation_tokens(&mut transaction, subscriber.id, Some(list.id))
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let subscription_token = generate_subscription_token();
+        store_list_token(
+            &mut transaction,
+            subscriber.id,
+            list.id,
+            &subscription_token,
+            &state.hmac_secret,
+            state.subscription_token_expiry,
+        )
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        list_tokens.push((list, subscription_token));
+    }
+    if transaction.commit().await.is_err() {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    // Sent only once the tokens are stored. A failure is logged rather than
+    // returned, so a known address gets the same answer as an unknown one;
+    // the subscriber can ask again once the cooldown is over.
+    let unsubscribe_link = unsubscribe_link(&state.base_url, &state.hmac_secret, subscriber.id);
+    if let Some(subscription_token) = newsletter_token {
+        if let Err(e) = send_confirmation_email(
+            &state.email_client,
+            email.clone(),
+            state.base_url.clone(),
+            &subscription_token,
+            &unsubscribe_link,
+        )
+        .await
+        {
+            tracing::error!("Failed to resend a confirmation email: {}", e);
+        }
+    }
+    for (list, subscription_token) in list_tokens {
+        if let Err(e) = send_list_confirmation_email(
+            &state.email_client,
+            list,
+            email.clone(),
+            &state.base_url,
+            &subscription_token,
+            &unsubscribe_link,
+        )
+        .await
+        {
+            tracing::error!("Failed to resend a list confirmation email: {}", e);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,239 @@
+use axum::{
+    extract::{Form, Query, State},
+    http::StatusCode,
+    response::Html,
+};
+use serde::Deserialize;
+use sqlx::{types::chrono::Utc, PgConnection};
+use unicode_segmentation::UnicodeSegmentation;
+use uuid::Uuid;
+
+use super::{delete_preference_sessions, delete_tokens};
+use crate::{hmac_secret::HmacSecret, startup::ApplicationState};
+
+#[derive(Deserialize)]
+pub struct UnsubscribeParameters {
+    pub token: String,
+}
+
+// One-click unsubscribes (RFC 8058) post `List-Unsubscribe=One-Click`,
+// which is ignored here, and carry no reason.
+#[derive(Deserialize)]
+pub struct UnsubscribeFormData {
+    reason: Option<String>,
+}
+
+#[tracing::instrument(name = "Show the unsubscribe page", skip(parameters, state))]
+pub async fn unsubscribe_form(
+    State(state): State<ApplicationState>,
+    parameters: Query<UnsubscribeParameters>,
+) -> Result<Html<String>, StatusCode> {
+    parse_unsubscribe_token(&state.hmac_secret, &parameters.token)
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    Ok(Html(format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head><meta charset="utf-8"><title>Unsubscribe</title></head>
+<body>
+    <p>Do you want to stop receiving our newsletter?</p>
+    <form action="/subscriptions/unsubscribe?token={}" method="post">
+        <label>Tell us why (optional)
+            <textarea name="reason" maxlength="500"></textarea>
+        </label>
+        <button type="submit">Unsubscribe</button>
+    </form>
+</body>
+</html>"#,
+        parameters.token
+    )))
+}
+
+#[tracing::instrument(name = "Unsubscribe a subscriber", skip(parameters, form_data, state))]
+pub async fn unsubscribe(
+    State(state): State<ApplicationState>,
+    parameters: Query<UnsubscribeParameters>,
+    Form(form_data): Form<UnsubscribeFormData>,
+) -> Result<Html<String>, StatusCode> {
+    let subscriber_id = parse_unsubscribe_token(&state.hmac_secret, &parameters.token)
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let reason = parse_reason(form_data.reason).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let mut transaction = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    mark_subscriber_as_unsubscribed(&mut transaction, subscriber_id, reason.as_deref())
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Outstanding confirmation links must not bring the address back, and
+    // open preference sessions must not outlive the subscription.
+    delete_tokens(&mut transaction, subscriber_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    delete_preference_sessions(&mut transaction, subscriber_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if transaction.commit().await.is_err() {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    Ok(Html(format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head><meta charset="utf-8"><title>Unsubscribed</title></head>
+<body>
+    <p>You have been unsubscribed and will not receive any more emails from us.</p>
+    <form action="/subscriptions/unsubscribe/undo?token={}" method="post">
+        <button type="submit">Undo</button>
+    </form>
+</body>
+</html>"#,
+        parameters.token
+    )))
+}
+
+#[tracing::instrument(name = "Undo an unsubscribe", skip(parameters, state))]
+pub async fn undo_unsubscribe(
+    State(state): State<ApplicationState>,
+    parameters: Query<UnsubscribeParameters>,
+) -> Result<Html<String>, StatusCode> {
+    let subscriber_id = parse_unsubscribe_token(&state.hmac_secret, &parameters.token)
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let mut connection = state
+        .pool
+        .acquire()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let status = resubscribe(&mut connection, subscriber_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::GONE)?;
+    let message = if status == "confirmed" {
+        "Welcome back! You are subscribed again."
+    } else {
+        "Welcome back! Your subscription still needs confirming: ask for a new confirmation link to finish signing up."
+    };
+    Ok(Html(format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head><meta charset="utf-8"><title>Subscribed</title></head>
+<body>
+    <p>{}</p>
+</body>
+</html>"#,
+        message
+    )))
+}
+
+/// The link carries the subscriber id and its signature, so it can be
+/// included in every email without storing anything.
+pub fn unsubscribe_token(hmac_secret: &HmacSecret, subscriber_id: Uuid) -> String {
+    format!(
+        "{}.{}",
+        subscriber_id,
+        hmac_secret.sign(&unsubscribe_message(subscriber_id))
+    )
+}
+
+pub fn unsubscribe_link(base_url: &str, hmac_secret: &HmacSecret, subscriber_id: Uuid) -> String {
+    format!(
+        "{}/subscriptions/unsubscribe?token={}",
+        base_url,
+        unsubscribe_token(hmac_secret, subscriber_id)
+    )
+}
+
+fn parse_unsubscribe_token(hmac_secret: &HmacSecret, token: &str) -> Option<Uuid> {
+    let (subscriber_id, signature) = token.split_once('.')?;
+    let subscriber_id = Uuid::parse_str(subscriber_id).ok()?;
+    hmac_secret
+        .verify(&unsubscribe_message(subscriber_id), signature)
+        .then_some(subscriber_id)
+}
+
+fn unsubscribe_message(subscriber_id: Uuid) -> String {
+    format!("unsubscribe:{}", subscriber_id)
+}
+
+fn parse_reason(reason: Option<String>) -> Result<Option<String>, String> {
+    let Some(reason) = reason.map(|r| r.trim().to_string()) else {
+        return Ok(None);
+    };
+    if reason.is_empty() {
+        return Ok(None);
+    }
+    if reason.graphemes(true).count() > 500 {
+        return Err("The unsubscribe reason is too long.".to_string());
+    }
+    Ok(Some(reason))
+}
+
+#[tracing::instrument(
+    name = "Mark subscriber as unsubscribed",
+    skip(connection, subscriber_id, reason)
+)]
+pub async fn mark_subscriber_as_unsubscribed(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+    reason: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    UPDATE subscriptions
+    SET status = 'unsubscribed', status_before_unsubscribe = status, unsubscribed_at = $2,
+        unsubscribe_reason = $3
+    WHERE id = $1 AND status <> 'unsubscribed'
+    "#,
+        subscriber_id,
+        Utc::now(),
+        reason,
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// Restores the status the subscriber had before unsubscribing. The link
+/// proves ownership of the address, not that the subscriber ever confirmed,
+/// so a pending subscriber goes back to pending. Returns `None` when the
+/// unsubscribe predates the recorded status and cannot be undone.
+#[tracing::instrument(name = "Resubscribe a subscriber", skip(connection, subscriber_id))]
+pub async fn resubscribe(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+) -> Result<Option<String>, sqlx::Error> {
+    let restored = sqlx::query!(
+        r#"
+    UPDATE subscriptions
+    SET status = status_before_unsubscribe, status_before_unsubscribe = NULL,
+        unsubscribed_at = NULL, unsubscribe_reason = NULL
+    WHERE id = $1 AND status = 'unsubscribed' AND status_before_unsubscribe IS NOT NULL
+    RETURNING status
+    "#,
+        subscriber_id,
+    )
+    .fetch_optional(&mut *connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    if let Some(restored) = restored {
+        return Ok(Some(restored.status));
+    }
+    // Undoing twice is not an error.
+    let current = sqlx::query!(
+        r#"SELECT status FROM subscriptions WHERE id = $1"#,
+        subscriber_id,
+    )
+    .fetch_optional(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(current.map(|r| r.status).filter(|s| s != "unsubscribed"))
+}
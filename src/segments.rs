@@ -0,0 +1,198 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    PgConnection,
+};
+use uuid::Uuid;
+
+use crate::domain::SubscriberTag;
+
+/// A saved filter over subscribers. Every condition that is set must match;
+/// empty conditions match everyone.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SegmentFilter {
+    /// Matches any of these statuses.
+    #[serde(default)]
+    pub statuses: Vec<String>,
+    /// Matches subscribers that have every one of these tags.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Matches subscribers that have none of these tags.
+    #[serde(default)]
+    pub excluded_tags: Vec<String>,
+    /// Matches confirmed members of any of these lists, by slug.
+    #[serde(default)]
+    pub lists: Vec<String>,
+    /// Matches subscribers whose custom attributes have these values.
+    #[serde(default)]
+    pub attributes: Map<String, Value>,
+    pub subscribed_after: Option<DateTime<Utc>>,
+    pub subscribed_before: Option<DateTime<Utc>>,
+}
+
+impl SegmentFilter {
+    pub fn validate(&self) -> Result<(), String> {
+        for tag in self.tags.iter().chain(&self.excluded_tags) {
+            SubscriberTag::parse(tag.clone())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SegmentSize {
+    /// Everyone the filter matches.
+    pub subscribers: i64,
+    /// The confirmed subscribers among them, who would receive an issue.
+    pub recipients: i64,
+}
+
+pub struct Recipient {
+    pub id: Uuid,
+    pub email: String,
+    pub delivery_frequency: String,
+}
+
+#[tracing::instrument(name = "Save a segment", skip(connection, filter))]
+pub async fn save_segment(
+    connection: &mut PgConnection,
+    name: &str,
+    filter: &SegmentFilter,
+) -> Result<(), sqlx::Error> {
+    let now = Utc::now();
+    sqlx::query!(
+        r#"
+    INSERT INTO segments (name, filter, created_at, updated_at)
+    VALUES ($1, $2, $3, $3)
+    ON CONFLICT (name) DO UPDATE SET filter = $2, updated_at = $3
+    "#,
+        name,
+        serde_json::to_value(filter).expect("Segment filters always serialize"),
+        now,
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Delete a segment", skip(connection))]
+pub async fn delete_segment(
+    connection: &mut PgConnection,
+    name: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(r#"DELETE FROM segments WHERE name = $1"#, name)
+        .execute(connection)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    Ok(result.rows_affected() > 0)
+}
+
+#[tracing::instrument(name = "Get a segment", skip(connection))]
+pub async fn get_segment_filter(
+    connection: &mut PgConnection,
+    name: &str,
+) -> Result<Option<SegmentFilter>, sqlx::Error> {
+    let Some(row) = sqlx::query!(r#"SELECT filter FROM segments WHERE name = $1"#, name)
+        .fetch_optional(connection)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?
+    else {
+        return Ok(None);
+    };
+    serde_json::from_value(row.filter)
+        .map(Some)
+        .map_err(|e| sqlx::Error::Decode(Box::new(e)))
+}
+
+#[tracing::instrument(name = "Count the subscribers in a segment", skip(connection, filter))]
+pub async fn count_segment(
+    connection: &mut PgConnection,
+    filter: &SegmentFilter,
+) -> Result<SegmentSize, sqlx::Error> {
+    sqlx::query_as!(
+        SegmentSize,
+        r#"
+    SELECT COUNT(*) AS "subscribers!",
+        COUNT(*) FILTER (
+            WHERE s.status = 'confirmed' OR (cardinality($4::text[]) > 0
+                AND s.status = 'pending_confirmation' AND s.email_verified_at IS NOT NULL)
+        ) AS "recipients!"
+    FROM subscriptions s
+    WHERE (cardinality($1::text[]) = 0 OR s.status = ANY($1))
+        AND s.tags @> $2 AND NOT s.tags && $3
+        AND (cardinality($4::text[]) = 0 OR EXISTS (
+            SELECT 1 FROM list_memberships m JOIN lists l ON l.id = m.list_id
+            WHERE m.subscriber_id = s.id AND m.status = 'confirmed' AND l.slug = ANY($4)
+        ))
+        AND s.attributes @> $5
+        AND ($6::timestamptz IS NULL OR s.subscribed_at >= $6)
+        AND ($7::timestamptz IS NULL OR s.subscribed_at < $7)
+    "#,
+        &filter.statuses,
+        &filter.tags,
+        &filter.excluded_tags,
+        &filter.lists,
+        Value::Object(filter.attributes.clone()),
+        filter.subscribed_after,
+        filter.subscribed_before,
+    )
+    .fetch_one(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+/// The confirmed subscribers a segment matches right now. A segment limited
+/// to lists also reaches verified subscribers who only confirmed those lists.
+#[tracing::instrument(name = "Resolve the recipients of a segment", skip(connection, filter))]
+pub async fn get_segment_recipients(
+    connection: &mut PgConnection,
+    filter: &SegmentFilter,
+) -> Result<Vec<Recipient>, sqlx::Error> {
+    sqlx::query_as!(
+        Recipient,
+        r#"
+    SELECT s.id, s.email, s.delivery_frequency
+    FROM subscriptions s
+    WHERE (s.status = 'confirmed' OR (cardinality($4::text[]) > 0
+            AND s.status = 'pending_confirmation' AND s.email_verified_at IS NOT NULL))
+        AND (cardinality($1::text[]) = 0 OR s.status = ANY($1))
+        AND s.tags @> $2 AND NOT s.tags && $3
+        AND (cardinality($4::text[]) = 0 OR EXISTS (
+            SELECT 1 FROM list_memberships m JOIN lists l ON l.id = m.list_id
+            WHERE m.subscriber_id = s.id AND m.status = 'confirmed' AND l.slug = ANY($4)
+        ))
+        AND s.attributes @> $5
+        AND ($6::timestamptz IS NULL OR s.subscribed_at >= $6)
+        AND ($7::timestamptz IS NULL OR s.subscribed_at < $7)
+    ORDER BY s.subscribed_at
+    "#,
+        &filter.statuses,
+        &filter.tags,
+        &filter.excluded_tags,
+        &filter.lists,
+        Value::Object(filter.attributes.clone()),
+        filter.subscribed_after,
+        filter.subscribed_before,
+    )
+    .fetch_all(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
@@ -1,23 +1,39 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use crate::{
-    configuration::{DatabaseSettings, Settings},
+    configuration::{
+        AttributeSettings, BotProtectionSettings, ConsentSettings, DatabaseSettings,
+        PreferenceSettings, Settings, SubjectTestSettings,
+    },
+    email_backfill::backfill_punycode_domains,
     email_client::EmailClient,
+    hmac_secret::HmacSecret,
+    mailing_lists::sync_lists,
+    rate_limit::RateLimiter,
     routes,
+    routes::{MAX_CONCURRENT_EXPORTS, RESERVED_FORM_FIELDS},
+    suppressions::SuppressionList,
 };
 use axum::{
-    routing::{get, post},
+    extract::connect_info::{ConnectInfo, IntoMakeServiceWithConnectInfo},
+    middleware::AddExtension,
+    routing::{get, post, put},
     serve::Serve,
     Router,
 };
-use secrecy::ExposeSecret;
+use secrecy::{ExposeSecret, SecretString};
 use sqlx::PgPool;
-use tokio::net::TcpListener;
+use tokio::{net::TcpListener, sync::Semaphore};
 use tower_http::trace::TraceLayer;
 
+type Server = Serve<
+    IntoMakeServiceWithConnectInfo<Router, SocketAddr>,
+    AddExtension<Router, ConnectInfo<SocketAddr>>,
+>;
+
 pub struct Application {
     port: u16,
-    server: Serve<Router, Router>,
+    server: Server,
 }
 
 impl Application {
@@ -28,20 +44,29 @@ impl Application {
             .run(&pool)
             .await
             .expect("Failed to migrate db.");
+        backfill_punycode_domains(&pool)
+            .await
+            .expect("Failed to convert stored email domains to punycode.");
 
-        let sender_email = configuration
-            .email_client
-            .sender()
-            .expect("Invalid sender email address.");
+        for list in &configuration.lists {
+            list.sender().expect("Invalid list sender email address.");
+        }
+        let mut reserved_names = RESERVED_FORM_FIELDS.to_vec();
+        reserved_names.extend(configuration.bot_protection.honeypot_field.as_deref());
+        for attribute in &configuration.attributes {
+            attribute
+                .validate(&reserved_names)
+                .expect("Invalid custom attribute.");
+        }
+        sync_lists(&pool, &configuration.lists)
+            .await
+            .expect("Failed to sync mailing lists.");
 
-        let email_client = EmailClient::new(
-            configuration
-                .email_client
-                .connection_string()
-                .expose_secret(),
-            sender_email,
-            configuration.email_client.timeout(),
-        );
+        let hmac_secret = HmacSecret::new(configuration.application.hmac_secret);
+        let email_client = configuration
+            .email_client
+            .client()
+            .with_suppressions(SuppressionList::new(pool.clone(), hmac_secret.clone()));
 
         let address = format!(
             "{}:{}",
@@ -49,11 +74,25 @@ impl Application {
         );
         let listener = TcpListener::bind(address).await?;
         let port = listener.local_addr().unwrap().port();
+        let rate_limiter = RateLimiter::new(configuration.rate_limits, pool.clone());
         let server = run(
             listener,
-            pool,
-            email_client,
-            configuration.application.base_url,
+            ApplicationState {
+                pool,
+                base_url: configuration.application.base_url,
+                email_client: Arc::new(email_client),
+                subscription_token_expiry: configuration.subscription_tokens.expiry(),
+                resend_cooldown: configuration.subscription_tokens.resend_cooldown(),
+                hmac_secret,
+                admin_token: configuration.application.admin_token,
+                preferences: configuration.preferences,
+                bot_protection: configuration.bot_protection,
+                rate_limiter,
+                consent: configuration.consent,
+                attributes: configuration.attributes.into(),
+                subject_tests: configuration.subject_tests,
+                export_slots: Arc::new(Semaphore::new(MAX_CONCURRENT_EXPORTS)),
+            },
         )?;
 
         Ok(Self { port, server })
@@ -71,25 +110,127 @@ pub struct ApplicationState {
     pub pool: PgPool,
     pub base_url: String,
     pub email_client: Arc<EmailClient>,
+    pub subscription_token_expiry: Duration,
+    pub resend_cooldown: Duration,
+    pub hmac_secret: HmacSecret,
+    pub admin_token: Option<SecretString>,
+    pub preferences: PreferenceSettings,
+    pub bot_protection: BotProtectionSettings,
+    pub rate_limiter: RateLimiter,
+    pub consent: ConsentSettings,
+    pub attributes: Arc<[AttributeSettings]>,
+    pub subject_tests: SubjectTestSettings,
+    /// Each running subscriber export holds a pool connection.
+    pub export_slots: Arc<Semaphore>,
 }
 
-pub fn run(
-    listener: TcpListener,
-    pool: PgPool,
-    email_client: EmailClient,
-    base_url: String,
-) -> Result<Serve<Router, Router>, std::io::Error> {
+pub fn run(listener: TcpListener, state: ApplicationState) -> Result<Server, std::io::Error> {
     let app: Router = Router::new()
         .layer(TraceLayer::new_for_http())
         .route("/health_check", get(routes::health_check))
+        .route("/admin/subscribers", get(routes::admin_stream_subscribers))
+        .route(
+            "/admin/subscribers/erase",
+            post(routes::admin_erase_subscriber),
+        )
+        .route(
+            "/admin/subscribers/export",
+            get(routes::admin_export_subscriber),
+        )
+        .route(
+            "/admin/subscribers/import",
+            post(routes::admin_import_subscribers),
+        )
+        .route("/admin/subscribers/tag", post(routes::admin_tag_subscriber))
+        .route(
+            "/admin/subscribers/untag",
+            post(routes::admin_untag_subscriber),
+        )
+        .route(
+            "/admin/segments/:name",
+            put(routes::admin_save_segment).delete(routes::admin_delete_segment),
+        )
+        .route(
+            "/admin/segments/:name/preview",
+            get(routes::admin_preview_segment),
+        )
+        .route(
+            "/admin/segments/:name/issues",
+            post(routes::admin_send_issue),
+        )
+        .route(
+            "/admin/deliveries/failed",
+            get(routes::admin_failed_deliveries),
+        )
+        .route(
+            "/admin/deliveries/requeue",
+            post(routes::admin_requeue_deliveries),
+        )
+        .route(
+            "/admin/deliveries/discard",
+            post(routes::admin_discard_deliveries),
+        )
+        .route("/admin/suppressions", post(routes::admin_add_suppressions))
+        .route(
+            "/admin/suppressions/remove",
+            post(routes::admin_remove_suppressions),
+        )
+        .route("/issues/:id/progress", get(routes::issue_progress))
+        .route("/issues/:id/open", get(routes::track_open))
+        .route("/issues/:id/click", get(routes::track_click))
         .route("/subscriptions", post(routes::subscribe))
+        .route("/subscriptions/challenge", get(routes::subscribe_challenge))
         .route("/subscriptions/confirm", get(routes::confirm))
-        .with_state(ApplicationState {
-            base_url,
-            pool,
-            email_client: Arc::new(email_client),
-        });
-    Ok(axum::serve(listener, app))
+        .route(
+            "/subscriptions/resend-confirmation",
+            post(routes::resend_confirmation),
+        )
+        .route(
+            "/subscriptions/export",
+            get(routes::download_data_export).post(routes::request_data_export),
+        )
+        .route(
+            "/subscriptions/preferences",
+            get(routes::preferences_form).post(routes::update_preferences),
+        )
+        .route(
+            "/subscriptions/preferences/email",
+            post(routes::request_email_change),
+        )
+        .route(
+            "/subscriptions/preferences/lists/leave",
+            post(routes::leave_list),
+        )
+        .route(
+            "/subscriptions/email/confirm",
+            get(routes::confirm_email_change_form).post(routes::confirm_email_change),
+        )
+        .route(
+            "/subscriptions/email/revert",
+            get(routes::revert_email_change_form).post(routes::revert_email_change),
+        )
+        .route(
+            "/subscriptions/preferences/magic-link",
+            post(routes::request_magic_link),
+        )
+        .route(
+            "/subscriptions/preferences/login",
+            get(routes::login_with_magic_link),
+        )
+        .route(
+            "/subscriptions/unsubscribe",
+            get(routes::unsubscribe_form).post(routes::unsubscribe),
+        )
+        .route(
+            "/subscriptions/unsubscribe/undo",
+            post(routes::undo_unsubscribe),
+        )
+        .with_state(state);
+    // The peer address is needed to rate limit by client IP.
+    Ok(axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    ))
 }
 
 pub fn get_connection_pool(configuration: &DatabaseSettings) -> PgPool {
@@ -0,0 +1,315 @@
+use serde::Deserialize;
+use sqlx::{types::chrono::Utc, PgConnection, PgPool};
+use url::Url;
+use uuid::Uuid;
+
+use crate::hmac_secret::HmacSecret;
+
+/// What makes a subject variant win.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WinnerMetric {
+    /// The share of recipients who opened the issue.
+    #[default]
+    #[serde(rename = "opens")]
+    Opens,
+    /// The share of recipients who followed a link in the issue.
+    #[serde(rename = "clicks")]
+    Clicks,
+}
+
+impl AsRef<str> for WinnerMetric {
+    fn as_ref(&self) -> &str {
+        match self {
+            Self::Opens => "opens",
+            Self::Clicks => "clicks",
+        }
+    }
+}
+
+impl WinnerMetric {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "opens" => Ok(Self::Opens),
+            "clicks" => Ok(Self::Clicks),
+            other => Err(format!("{} is not a winner metric.", other)),
+        }
+    }
+}
+
+/// Adds open and click tracking to a copy of a tested issue: links go
+/// through `/issues/{id}/click` first, and an invisible image is loaded from
+/// `/issues/{id}/open`. Both are signed, so they cannot be forged for other
+/// recipients or turned into an open redirect.
+pub fn add_tracking(
+    html_content: &str,
+    base_url: &str,
+    hmac_secret: &HmacSecret,
+    issue_id: Uuid,
+    subscriber_id: Uuid,
+) -> String {
+    let body = rewrite_links(html_content, |link| {
+        let mut url = Url::parse(&format!("{}/issues/{}/click", base_url, issue_id))
+            .expect("The base URL is a valid URL.");
+        url.query_pairs_mut()
+            .append_pair("subscriber_id", &subscriber_id.to_string())
+            .append_pair("url", link)
+            .append_pair(
+                "signature",
+                &hmac_secret.sign(&click_message(issue_id, subscriber_id, link)),
+            );
+        url.to_string()
+    });
+    format!(
+        "{}<img src=\"{}/issues/{}/open?subscriber_id={}&amp;signature={}\" width=\"1\" height=\"1\" alt=\"\" />",
+        body,
+        base_url,
+        issue_id,
+        subscriber_id,
+        hmac_secret.sign(&open_message(issue_id, subscriber_id)),
+    )
+}
+
+pub fn open_message(issue_id: Uuid, subscriber_id: Uuid) -> String {
+    format!("open:{}:{}", issue_id, subscriber_id)
+}
+
+pub fn click_message(issue_id: Uuid, subscriber_id: Uuid, url: &str) -> String {
+    format!("click:{}:{}:{}", issue_id, subscriber_id, url)
+}
+
+/// Replaces the target of every absolute `href` in `html`.
+fn rewrite_links(html: &str, rewrite: impl Fn(&str) -> String) -> String {
+    let mut rewritten = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find("href=\"") {
+        let (before, after) = rest.split_at(start + "href=\"".len());
+        rewritten.push_str(before);
+        let Some(end) = after.find('"') else {
+            rest = after;
+            break;
+        };
+        let link = after[..end].replace("&amp;", "&");
+        if link.starts_with("http://") || link.starts_with("https://") {
+            rewritten.push_str(&rewrite(&link).replace('&', "&amp;"));
+        } else {
+            rewritten.push_str(&after[..end]);
+        }
+        rest = &after[end..];
+    }
+    rewritten.push_str(rest);
+    rewritten
+}
+
+#[tracing::instrument(name = "Record an issue open", skip(connection))]
+pub async fn record_open(
+    connection: &mut PgConnection,
+    issue_id: Uuid,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    UPDATE issue_deliveries SET opened_at = COALESCE(opened_at, $3)
+    WHERE issue_id = $1 AND subscriber_id = $2
+    "#,
+        issue_id,
+        subscriber_id,
+        Utc::now(),
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// A click counts as an open too, for readers who block images.
+#[tracing::instrument(name = "Record an issue click", skip(connection))]
+pub async fn record_click(
+    connection: &mut PgConnection,
+    issue_id: Uuid,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    UPDATE issue_deliveries
+    SET opened_at = COALESCE(opened_at, $3), clicked_at = COALESCE(clicked_at, $3)
+    WHERE issue_id = $1 AND subscriber_id = $2
+    "#,
+        issue_id,
+        subscriber_id,
+        Utc::now(),
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+struct VariantResult {
+    variant: i16,
+    subject: String,
+    sent: i64,
+    opens: i64,
+    clicks: i64,
+}
+
+impl VariantResult {
+    fn rate(&self, metric: WinnerMetric) -> f64 {
+        let hits = match metric {
+            WinnerMetric::Opens => self.opens,
+            WinnerMetric::Clicks => self.clicks,
+        };
+        if self.sent == 0 {
+            0.0
+        } else {
+            hits as f64 / self.sent as f64
+        }
+    }
+}
+
+/// Picks the winner of every subject test whose wait is over and releases
+/// the held deliveries with the winning subject. Returns how many tests
+/// were decided.
+pub async fn finish_subject_tests(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let mut decided = 0;
+    loop {
+        let mut transaction = pool.begin().await?;
+        let Some(issue) = sqlx::query!(
+            r#"
+    SELECT id, winner_metric AS "winner_metric!" FROM newsletter_issues
+    WHERE test_ends_at <= $1 AND winning_variant IS NULL
+    FOR UPDATE SKIP LOCKED
+    LIMIT 1
+    "#,
+            Utc::now(),
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?
+        else {
+            return Ok(decided);
+        };
+        let metric = WinnerMetric::parse(&issue.winner_metric).unwrap_or_default();
+        pick_winner(&mut transaction, issue.id, metric).await?;
+        transaction.commit().await?;
+        decided += 1;
+    }
+}
+
+#[tracing::instrument(name = "Pick the winning subject", skip(connection))]
+async fn pick_winner(
+    connection: &mut PgConnection,
+    issue_id: Uuid,
+    metric: WinnerMetric,
+) -> Result<(), sqlx::Error> {
+    let results = sqlx::query_as!(
+        VariantResult,
+        r#"
+    SELECT v.variant, v.subject,
+        COUNT(d.subscriber_id) FILTER (WHERE d.status = 'sent') AS "sent!",
+        COUNT(d.subscriber_id) FILTER (WHERE d.opened_at IS NOT NULL) AS "opens!",
+        COUNT(d.subscriber_id) FILTER (WHERE d.clicked_at IS NOT NULL) AS "clicks!"
+    FROM issue_subject_variants v
+    LEFT JOIN issue_deliveries d ON d.issue_id = v.issue_id AND d.variant = v.variant
+    WHERE v.issue_id = $1
+    GROUP BY v.variant, v.subject
+    ORDER BY v.variant
+    "#,
+        issue_id,
+    )
+    .fetch_all(&mut *connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    // Ties go to the earlier variant.
+    let Some(winner) = results.iter().reduce(|best, result| {
+        if result.rate(metric) > best.rate(metric) {
+            result
+        } else {
+            best
+        }
+    }) else {
+        return Ok(());
+    };
+    tracing::info!(winner = winner.variant, "Picked the winning subject");
+    sqlx::query!(
+        r#"
+    UPDATE issue_subject_variants AS v
+    SET sent = r.sent, opens = r.opens, clicks = r.clicks
+    FROM UNNEST($2::smallint[], $3::int[], $4::int[], $5::int[]) AS r(variant, sent, opens, clicks)
+    WHERE v.issue_id = $1 AND v.variant = r.variant
+    "#,
+        issue_id,
+        &results.iter().map(|r| r.variant).collect::<Vec<_>>(),
+        &results.iter().map(|r| r.sent as i32).collect::<Vec<_>>(),
+        &results.iter().map(|r| r.opens as i32).collect::<Vec<_>>(),
+        &results.iter().map(|r| r.clicks as i32).collect::<Vec<_>>(),
+    )
+    .execute(&mut *connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    sqlx::query!(
+        r#"
+    UPDATE newsletter_issues SET winning_variant = $2, subject = $3 WHERE id = $1
+    "#,
+        issue_id,
+        winner.variant,
+        winner.subject,
+    )
+    .execute(&mut *connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    sqlx::query!(
+        r#"
+    UPDATE issue_deliveries SET status = 'queued', variant = $2, next_attempt_at = $3
+    WHERE issue_id = $1 AND status = 'held'
+    "#,
+        issue_id,
+        winner.variant,
+        Utc::now(),
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn absolute_links_are_rewritten() {
+        let html = r#"<a href="https://example.com/a?b=1&amp;c=2">A</a> <a href="/local">B</a>"#;
+        let rewritten = rewrite_links(html, |link| format!("tracked({})", link));
+        assert_eq!(
+            rewritten,
+            r#"<a href="tracked(https://example.com/a?b=1&amp;c=2)">A</a> <a href="/local">B</a>"#
+        );
+    }
+
+    #[test]
+    fn html_without_links_is_unchanged() {
+        let html = "<p>No links, but an href=\"unterminated</p>";
+        assert_eq!(rewrite_links(html, |_| "x".to_string()), html);
+    }
+}
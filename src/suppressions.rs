@@ -0,0 +1,232 @@
+use std::collections::HashSet;
+
+use serde::Deserialize;
+use sqlx::{types::chrono::Utc, PgConnection, PgPool};
+
+use crate::{domain::SubscriberEmail, hmac_secret::HmacSecret};
+
+/// Why an address must not be mailed. Erasure adds entries of its own, with
+/// the reason `erasure`.
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub enum SuppressionReason {
+    #[serde(rename = "hard_bounce")]
+    HardBounce,
+    #[serde(rename = "complaint")]
+    Complaint,
+    #[serde(rename = "do_not_contact")]
+    DoNotContact,
+}
+
+impl AsRef<str> for SuppressionReason {
+    fn as_ref(&self) -> &str {
+        match self {
+            Self::HardBounce => "hard_bounce",
+            Self::Complaint => "complaint",
+            Self::DoNotContact => "do_not_contact",
+        }
+    }
+}
+
+impl SuppressionReason {
+    /// Reasons an admin may lift. Erasure entries stay, so an erased
+    /// address cannot come back.
+    pub const REMOVABLE: [Self; 3] = [Self::HardBounce, Self::Complaint, Self::DoNotContact];
+}
+
+/// The outcome of lifting a suppression.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Unsuppression {
+    Removed,
+    NotFound,
+    /// The address was erased; its tombstone was kept.
+    Erased,
+}
+
+/// The suppression list as seen by the email client, which checks every
+/// recipient against it before sending.
+pub struct SuppressionList {
+    pool: PgPool,
+    hmac_secret: HmacSecret,
+}
+
+impl SuppressionList {
+    pub fn new(pool: PgPool, hmac_secret: HmacSecret) -> Self {
+        Self { pool, hmac_secret }
+    }
+
+    pub async fn contains(&self, email: &SubscriberEmail) -> Result<bool, sqlx::Error> {
+        let mut connection = self.pool.acquire().await?;
+        is_suppressed(&mut connection, &self.hmac_secret, email).await
+    }
+}
+
+/// Suppressions are keyed by a MAC of the normalised address, so the table
+/// can say "never mail this address" without storing it. Rotating the HMAC
+/// secret invalidates every entry.
+pub fn suppression_key(hmac_secret: &HmacSecret, email: &SubscriberEmail) -> String {
+    hmac_secret.sign(&format!(
+        "suppression:{}",
+        email.as_ref().trim().to_lowercase()
+    ))
+}
+
+/// Every key an address may be suppressed under. Entries made before stored
+/// addresses were canonicalized were keyed by the Unicode form of
+/// internationalized domains, so that key is checked as well.
+pub fn suppression_keys(hmac_secret: &HmacSecret, email: &SubscriberEmail) -> Vec<String> {
+    let mut keys = vec![suppression_key(hmac_secret, email)];
+    if let Some((local, domain)) = email.as_ref().rsplit_once('@') {
+        let (unicode_domain, result) = idna::domain_to_unicode(domain);
+        if result.is_ok() && unicode_domain != domain {
+            keys.push(hmac_secret.sign(&format!(
+                "suppression:{}@{}",
+                local.to_lowercase(),
+                unicode_domain.to_lowercase()
+            )));
+        }
+    }
+    keys
+}
+
+#[tracing::instrument(name = "Suppress an email address", skip(connection, email_hash))]
+pub async fn suppress_email(
+    connection: &mut PgConnection,
+    email_hash: &str,
+    reason: &str,
+    source: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+    INSERT INTO suppressions (email_hash, reason, source, created_at)
+    VALUES ($1, $2, $3, $4)
+    ON CONFLICT (email_hash) DO NOTHING
+    "#,
+        email_hash,
+        reason,
+        source,
+        Utc::now(),
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Suppresses an erased address for good. An entry the address already had
+/// takes the reason `erasure`, so an admin cannot lift it later.
+#[tracing::instrument(name = "Tombstone an erased address", skip(connection, email_hashes))]
+pub async fn tombstone_email(
+    connection: &mut PgConnection,
+    email_hashes: &[String],
+    source: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    INSERT INTO suppressions (email_hash, reason, source, created_at)
+    SELECT email_hash, 'erasure', $2, $3 FROM UNNEST($1::text[]) AS email_hash
+    ON CONFLICT (email_hash) DO UPDATE SET reason = 'erasure'
+    "#,
+        email_hashes,
+        source,
+        Utc::now(),
+    )
+    .execute(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// Lets a suppressed address be mailed again, unless it was suppressed by
+/// an erasure.
+#[tracing::instrument(name = "Remove a suppression", skip(connection, email_hashes))]
+pub async fn unsuppress_email(
+    connection: &mut PgConnection,
+    email_hashes: &[String],
+) -> Result<Unsuppression, sqlx::Error> {
+    let reasons: Vec<String> = SuppressionReason::REMOVABLE
+        .iter()
+        .map(|reason| reason.as_ref().to_string())
+        .collect();
+    let removed = sqlx::query!(
+        r#"DELETE FROM suppressions WHERE email_hash = ANY($1) AND reason = ANY($2)"#,
+        email_hashes,
+        &reasons,
+    )
+    .execute(&mut *connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .rows_affected();
+    if removed > 0 {
+        return Ok(Unsuppression::Removed);
+    }
+    let erased = sqlx::query!(
+        r#"
+    SELECT EXISTS (
+        SELECT 1 FROM suppressions WHERE email_hash = ANY($1) AND reason = 'erasure'
+    ) AS "erased!"
+    "#,
+        email_hashes,
+    )
+    .fetch_one(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .erased;
+    Ok(if erased {
+        Unsuppression::Erased
+    } else {
+        Unsuppression::NotFound
+    })
+}
+
+/// Looks up many addresses at once: returns which of `email_hashes` are
+/// suppressed.
+#[tracing::instrument(name = "Find suppressed addresses", skip_all)]
+pub async fn get_suppressed_keys(
+    connection: &mut PgConnection,
+    email_hashes: &[String],
+) -> Result<HashSet<String>, sqlx::Error> {
+    Ok(sqlx::query!(
+        r#"SELECT email_hash FROM suppressions WHERE email_hash = ANY($1)"#,
+        email_hashes,
+    )
+    .fetch_all(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .into_iter()
+    .map(|r| r.email_hash)
+    .collect())
+}
+
+#[tracing::instrument(name = "Check whether an address is suppressed", skip_all)]
+pub async fn is_suppressed(
+    connection: &mut PgConnection,
+    hmac_secret: &HmacSecret,
+    email: &SubscriberEmail,
+) -> Result<bool, sqlx::Error> {
+    Ok(sqlx::query!(
+        r#"SELECT EXISTS (SELECT 1 FROM suppressions WHERE email_hash = ANY($1)) AS "suppressed!""#,
+        &suppression_keys(hmac_secret, email),
+    )
+    .fetch_one(connection)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .suppressed)
+}
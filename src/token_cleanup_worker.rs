@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use sqlx::{types::chrono::Utc, PgPool};
+
+use crate::{configuration::Settings, startup::get_connection_pool};
+
+pub async fn run_worker_until_stopped(configuration: Settings) -> Result<(), std::io::Error> {
+    let pool = get_connection_pool(&configuration.database);
+    let rate_limits = &configuration.rate_limits;
+    let bucket_lifetime = rate_limits
+        .per_ip
+        .refill_time()
+        .max(rate_limits.per_email.refill_time());
+    worker_loop(
+        pool,
+        configuration.subscription_tokens.cleanup_interval(),
+        bucket_lifetime,
+    )
+    .await
+}
+
+async fn worker_loop(
+    pool: PgPool,
+    period: Duration,
+    bucket_lifetime: Duration,
+) -> Result<(), std::io::Error> {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        // A failed run is retried on the next tick.
+        let _ = delete_expired_tokens(&pool).await;
+        let _ = delete_expired_preference_sessions(&pool).await;
+        let _ = delete_expired_form_tokens(&pool).await;
+        let _ = delete_refilled_rate_limit_buckets(&pool, bucket_lifetime).await;
+    }
+}
+
+#[tracing::instrument(name = "Delete expired subscription tokens", skip(pool))]
+pub async fn delete_expired_tokens(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let deleted = sqlx::query!(
+        r#"DELETE FROM subscription_tokens WHERE expires_at <= $1"#,
+        Utc::now(),
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .rows_affected();
+    tracing::info!(deleted, "Deleted expired subscription tokens");
+    Ok(deleted)
+}
+
+#[tracing::instrument(name = "Delete expired preference sessions", skip(pool))]
+pub async fn delete_expired_preference_sessions(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let deleted = sqlx::query!(
+        r#"DELETE FROM preference_sessions WHERE expires_at <= $1"#,
+        Utc::now(),
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .rows_affected();
+    tracing::info!(deleted, "Deleted expired preference sessions");
+    Ok(deleted)
+}
+
+/// A form token is rejected as too old once its row has expired, so the row
+/// is no longer needed to catch replays.
+#[tracing::instrument(name = "Delete expired form tokens", skip(pool))]
+pub async fn delete_expired_form_tokens(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let deleted = sqlx::query!(
+        r#"DELETE FROM used_form_tokens WHERE expires_at <= $1"#,
+        Utc::now(),
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .rows_affected();
+    tracing::info!(deleted, "Deleted expired form tokens");
+    Ok(deleted)
+}
+
+/// Buckets untouched for longer than it takes to refill them are full, which
+/// is the same as not having a row at all.
+#[tracing::instrument(name = "Delete refilled rate limit buckets", skip(pool))]
+pub async fn delete_refilled_rate_limit_buckets(
+    pool: &PgPool,
+    bucket_lifetime: Duration,
+) -> Result<u64, sqlx::Error> {
+    let deleted = sqlx::query!(
+        r#"DELETE FROM rate_limit_buckets WHERE updated_at <= $1"#,
+        Utc::now() - bucket_lifetime,
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .rows_affected();
+    tracing::info!(deleted, "Deleted refilled rate limit buckets");
+    Ok(deleted)
+}
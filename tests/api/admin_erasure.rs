@@ -0,0 +1,117 @@
+use crate::helpers::{spawn_admin_app, TestApp, ADMIN_TOKEN};
+
+async fn erase(app: &TestApp, email: &str, token: Option<&str>) -> reqwest::Response {
+    let mut request = reqwest::Client::new()
+        .post(format!("{}/admin/subscribers/erase", app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body(format!("email={}", email));
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    request.send().await.expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn erasure_requires_the_admin_token() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+
+    let response = erase(&app, "ursula_le_guin%40gmail.com", None).await;
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn erasing_an_unknown_address_is_a_404() {
+    let app = spawn_admin_app().await;
+
+    let response = erase(&app, "nobody%40gmail.com", Some(ADMIN_TOKEN)).await;
+
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn erasure_removes_personal_data_but_keeps_the_row() {
+    let app = spawn_admin_app().await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&lists=weekly".into())
+        .await;
+
+    let response = erase(&app, "ursula_le_guin%40gmail.com", Some(ADMIN_TOKEN)).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT id, email, name, status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.email, format!("{}@anonymized.invalid", saved.id));
+    assert_eq!(saved.name, "anonymized");
+    assert_eq!(saved.status, "erased");
+    assert_eq!(app.list_memberships().await.len(), 1);
+    let tokens = sqlx::query!("SELECT subscriber_id FROM subscription_tokens")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(tokens.is_empty());
+}
+
+#[tokio::test]
+async fn erasure_is_audit_logged() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+
+    erase(&app, "ursula_le_guin%40gmail.com", Some(ADMIN_TOKEN)).await;
+
+    let actions: Vec<String> = sqlx::query!("SELECT action FROM audit_log ORDER BY occurred_at")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| r.action)
+        .collect();
+    assert_eq!(actions, ["erasure_requested", "erasure_completed"]);
+}
+
+#[tokio::test]
+async fn an_erased_address_cannot_subscribe_again() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    erase(&app, "ursula_le_guin%40gmail.com", Some(ADMIN_TOKEN)).await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let suppression = sqlx::query!("SELECT email_hash, reason FROM suppressions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(suppression.reason, "erasure");
+    assert!(!suppression.email_hash.contains("ursula"));
+    let subscriptions = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(subscriptions.len(), 1);
+}
+
+#[tokio::test]
+async fn erasure_ends_preference_sessions() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    let cookie = app.log_in_to_preferences("ursula_le_guin@gmail.com").await;
+
+    erase(&app, "ursula_le_guin%40gmail.com", Some(ADMIN_TOKEN)).await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/subscriptions/preferences", app.address))
+        .header(reqwest::header::COOKIE, &cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+}
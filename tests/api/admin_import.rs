@@ -0,0 +1,221 @@
+use reqwest::Method;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::helpers::{spawn_admin_app, TestApp, ADMIN_TOKEN};
+
+async fn import(app: &TestApp, query: &str, csv: &str) -> reqwest::Response {
+    app.admin_request(
+        Method::POST,
+        &format!("/admin/subscribers/import?{}", query),
+    )
+    .header("Content-Type", "text/csv")
+    .body(csv.to_string())
+    .send()
+    .await
+    .expect("Failed to execute request.")
+}
+
+const ATTESTED: &str = "mode=confirmed&attestation=Double%20opt-in%20at%20our%20old%20provider";
+
+#[tokio::test]
+async fn import_requires_the_admin_token() {
+    let app = spawn_admin_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!(
+            "{}/admin/subscribers/import?mode=send_confirmation",
+            app.address
+        ))
+        .body("email,name\n")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn confirmed_imports_require_an_attestation() {
+    let app = spawn_admin_app().await;
+
+    let response = import(
+        &app,
+        "mode=confirmed",
+        "email,name\nursula@gmail.com,Ursula\n",
+    )
+    .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn a_csv_without_email_and_name_columns_is_rejected() {
+    let app = spawn_admin_app().await;
+
+    let response = import(
+        &app,
+        ATTESTED,
+        "address,full_name\nursula@gmail.com,Ursula\n",
+    )
+    .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn confirmed_imports_record_the_attestation() {
+    let app = spawn_admin_app().await;
+
+    let response = import(
+        &app,
+        ATTESTED,
+        "name,email,company\nUrsula,ursula@gmail.com,Acme\nTolkien,tolkien@gmail.com,\n",
+    )
+    .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["accepted"], 2);
+    let saved = sqlx::query!("SELECT status, attributes FROM subscriptions ORDER BY email DESC")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(saved.iter().all(|s| s.status == "confirmed"));
+    assert_eq!(saved[0].attributes, serde_json::json!({"company": "Acme"}));
+    let events = sqlx::query!("SELECT event, attestation FROM consent_events")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(events.len(), 2);
+    assert!(events.iter().all(|e| e.event == "import"
+        && e.attestation.as_deref() == Some("Double opt-in at our old provider")));
+}
+
+#[tokio::test]
+async fn send_confirmation_imports_create_pending_subscribers_with_tokens() {
+    let app = spawn_admin_app().await;
+
+    let response = import(
+        &app,
+        "mode=send_confirmation",
+        "email,name\nursula@gmail.com,Ursula\n",
+    )
+    .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "pending_confirmation");
+    let tokens = sqlx::query!("SELECT purpose FROM subscription_tokens")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(tokens.len(), 1);
+    let events = sqlx::query!("SELECT event, attestation FROM consent_events")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].event, "import");
+    assert!(events[0].attestation.is_none());
+}
+
+#[tokio::test]
+async fn the_report_explains_every_row() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("existing@gmail.com").await;
+
+    let response = import(
+        &app,
+        ATTESTED,
+        "email,name\n\
+         ursula@gmail.com,Ursula\n\
+         not-an-email,Nobody\n\
+         existing@gmail.com,Existing\n\
+         URSULA@gmail.com,Ursula again\n\
+         tolkien@gmail.com,\n",
+    )
+    .await;
+
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["accepted"], 1);
+    assert_eq!(report["skipped"], 2);
+    assert_eq!(report["rejected"], 2);
+    let outcomes: Vec<(u64, &str)> = report["rows"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|row| {
+            (
+                row["row"].as_u64().unwrap(),
+                row["outcome"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        outcomes,
+        [
+            (2, "accepted"),
+            (3, "rejected"),
+            (4, "skipped"),
+            (5, "skipped"),
+            (6, "rejected"),
+        ]
+    );
+    assert_eq!(
+        report["rows"][3]["reason"],
+        "The address is a duplicate of row 2."
+    );
+    assert!(report["rows"][0].get("reason").is_none());
+}
+
+#[tokio::test]
+async fn suppressed_addresses_are_skipped() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula@gmail.com").await;
+    app.admin_request(Method::POST, "/admin/subscribers/erase")
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("email=ursula%40gmail.com")
+        .send()
+        .await
+        .unwrap();
+
+    let response = import(&app, ATTESTED, "email,name\nursula@gmail.com,Ursula\n").await;
+
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["skipped"], 1);
+    assert_eq!(report["rows"][0]["reason"], "The address is suppressed.");
+}
+
+#[tokio::test]
+async fn an_upload_that_breaks_off_reports_the_rows_imported_so_far() {
+    let app = spawn_admin_app().await;
+    let body = "email,name\nursula@gmail.com,Ursula\n";
+    let mut stream = TcpStream::connect(app.address.trim_start_matches("http://"))
+        .await
+        .unwrap();
+    // Promise more bytes than are sent, then hang up.
+    let request = format!(
+        "POST /admin/subscribers/import?mode=send_confirmation HTTP/1.1\r\n\
+         Host: localhost\r\nAuthorization: Bearer {}\r\nContent-Type: text/csv\r\n\
+         Content-Length: {}\r\n\r\n{}",
+        ADMIN_TOKEN,
+        body.len() + 100,
+        body
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 400"));
+    let report: serde_json::Value =
+        serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+    assert_eq!(report["accepted"], 1);
+    assert!(report["error"].is_string());
+}
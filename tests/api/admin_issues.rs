@@ -0,0 +1,246 @@
+use reqwest::Method;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::helpers::{spawn_admin_app, TestApp};
+
+/// Sends an issue to every confirmed subscriber and returns its id.
+async fn send_issue(app: &TestApp) -> Uuid {
+    app.admin_request(Method::PUT, "/admin/segments/everyone")
+        .json(&json!({}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let summary: serde_json::Value = app
+        .admin_request(Method::POST, "/admin/segments/everyone/issues")
+        .json(&json!({"subject": "Issue #1", "html_content": "<p>Hello!</p>"}))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json()
+        .await
+        .unwrap();
+    summary["issue_id"].as_str().unwrap().parse().unwrap()
+}
+
+fn progress_request(app: &TestApp, issue_id: Uuid) -> reqwest::RequestBuilder {
+    app.admin_request(Method::GET, &format!("/issues/{}/progress", issue_id))
+}
+
+#[tokio::test]
+async fn progress_is_streamed_until_delivery_finishes() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    let issue_id = send_issue(&app).await;
+
+    let mut response = progress_request(&app, issue_id).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["Content-Type"], "text/event-stream");
+    let first = String::from_utf8(response.chunk().await.unwrap().unwrap().to_vec()).unwrap();
+    assert!(first.contains("event: progress"));
+    assert!(first.contains(r#"data: {"sent":0,"failed":0,"remaining":1}"#));
+
+    app.dispatch_all_pending_emails().await;
+
+    let rest = response.text().await.unwrap();
+    assert!(rest.contains("event: complete"));
+    assert!(rest.contains("id: 1-0-0"));
+    assert!(rest.contains(r#"data: {"sent":1,"failed":0,"remaining":0}"#));
+}
+
+#[tokio::test]
+async fn reconnecting_resumes_after_the_last_event() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    let issue_id = send_issue(&app).await;
+    app.dispatch_all_pending_emails().await;
+
+    let missed = progress_request(&app, issue_id)
+        .header("Last-Event-ID", "0-0-1")
+        .send()
+        .await
+        .unwrap();
+    let finished = progress_request(&app, issue_id)
+        .header("Last-Event-ID", "1-0-0")
+        .send()
+        .await
+        .unwrap();
+
+    assert!(missed.text().await.unwrap().contains("event: complete"));
+    assert_eq!(finished.status().as_u16(), 204);
+}
+
+#[tokio::test]
+async fn progress_of_an_unknown_issue_is_a_404() {
+    let app = spawn_admin_app().await;
+
+    let response = progress_request(&app, Uuid::new_v4()).send().await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn progress_requires_the_admin_token() {
+    let app = spawn_admin_app().await;
+    let issue_id = send_issue(&app).await;
+
+    let response = reqwest::get(format!("{}/issues/{}/progress", app.address, issue_id))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+/// Sends an issue while the mail server rejects everything, until the
+/// worker gives up on it.
+async fn fail_issue(app: &TestApp) -> Uuid {
+    app.email_server.set_failing(true);
+    let issue_id = send_issue(app).await;
+    for _ in 0..app.issue_delivery.max_attempts {
+        sqlx::query!("UPDATE issue_deliveries SET next_attempt_at = now() - interval '1 second'")
+            .execute(&app.db_pool)
+            .await
+            .unwrap();
+        app.dispatch_all_pending_emails().await;
+    }
+    app.email_server.set_failing(false);
+    issue_id
+}
+
+async fn delivery_status(app: &TestApp, issue_id: Uuid) -> String {
+    sqlx::query!(
+        "SELECT status FROM issue_deliveries WHERE issue_id = $1",
+        issue_id
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+    .status
+}
+
+#[tokio::test]
+async fn deliveries_are_set_aside_after_the_last_attempt() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+
+    let issue_id = fail_issue(&app).await;
+
+    assert_eq!(delivery_status(&app, issue_id).await, "failed");
+    let failed: serde_json::Value = app
+        .admin_request(Method::GET, "/admin/deliveries/failed")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let failed = failed.as_array().unwrap();
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0]["issue_id"], issue_id.to_string());
+    assert_eq!(failed[0]["attempts"], 2);
+    assert!(failed[0]["last_error"].as_str().unwrap().contains("554"));
+}
+
+#[tokio::test]
+async fn failed_deliveries_are_retried_later_until_the_last_attempt() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    app.email_server.set_failing(true);
+    let issue_id = send_issue(&app).await;
+
+    app.dispatch_all_pending_emails().await;
+
+    let delivery = sqlx::query!(
+        "SELECT status, attempts, next_attempt_at FROM issue_deliveries WHERE issue_id = $1",
+        issue_id
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(delivery.status, "queued");
+    assert_eq!(delivery.attempts, 1);
+    assert!(delivery.next_attempt_at > chrono::Utc::now());
+}
+
+#[tokio::test]
+async fn requeued_deliveries_are_sent_again() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    let issue_id = fail_issue(&app).await;
+
+    let report: serde_json::Value = app
+        .admin_request(Method::POST, "/admin/deliveries/requeue")
+        .json(&json!({"issue_id": issue_id}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(report["deliveries"], 1);
+    assert_eq!(delivery_status(&app, issue_id).await, "sent");
+    assert_eq!(app.emails_to("ursula_le_guin@gmail.com").len(), 2);
+}
+
+#[tokio::test]
+async fn discarded_deliveries_are_not_sent() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    let issue_id = fail_issue(&app).await;
+
+    let response = app
+        .admin_request(Method::POST, "/admin/deliveries/discard")
+        .json(&json!({"issue_id": issue_id, "subscriber_ids": []}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(delivery_status(&app, issue_id).await, "failed");
+
+    app.admin_request(Method::POST, "/admin/deliveries/discard")
+        .json(&json!({"issue_id": issue_id}))
+        .send()
+        .await
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(delivery_status(&app, issue_id).await, "discarded");
+    let failed: serde_json::Value = app
+        .admin_request(Method::GET, "/admin/deliveries/failed")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(failed.as_array().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn failed_delivery_endpoints_require_the_admin_token() {
+    let app = spawn_admin_app().await;
+    let client = reqwest::Client::new();
+
+    let list = client
+        .get(format!("{}/admin/deliveries/failed", app.address))
+        .send()
+        .await
+        .unwrap();
+    let requeue = client
+        .post(format!("{}/admin/deliveries/requeue", app.address))
+        .json(&json!({"issue_id": Uuid::new_v4()}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(list.status().as_u16(), 401);
+    assert_eq!(requeue.status().as_u16(), 401);
+}
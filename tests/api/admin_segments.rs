@@ -0,0 +1,502 @@
+use reqwest::Method;
+use serde_json::json;
+use url::Url;
+use zero2prod::subject_tests::finish_subject_tests;
+
+use crate::helpers::{spawn_admin_app, ReceivedEmail, TestApp};
+
+async fn tag(app: &TestApp, email: &str, tags: &[&str]) -> reqwest::Response {
+    let mut body = format!("email={}", email);
+    for tag in tags {
+        body.push_str(&format!("&tag={}", tag));
+    }
+    app.admin_request(Method::POST, "/admin/subscribers/tag")
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body(body)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn save_segment(app: &TestApp, name: &str, filter: serde_json::Value) -> reqwest::Response {
+    app.admin_request(Method::PUT, &format!("/admin/segments/{}", name))
+        .json(&filter)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn preview_segment(app: &TestApp, name: &str) -> reqwest::Response {
+    app.admin_request(Method::GET, &format!("/admin/segments/{}/preview", name))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn segment_endpoints_require_the_admin_token() {
+    let app = spawn_admin_app().await;
+
+    let response = reqwest::Client::new()
+        .put(format!("{}/admin/segments/vips", app.address))
+        .json(&json!({}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn tags_are_added_and_removed() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+
+    tag(&app, "ursula_le_guin%40gmail.com", &["vip", "beta"]).await;
+    tag(&app, "ursula_le_guin%40gmail.com", &["vip"]).await;
+    app.admin_request(Method::POST, "/admin/subscribers/untag")
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("email=ursula_le_guin%40gmail.com&tag=beta")
+        .send()
+        .await
+        .unwrap();
+
+    let saved = sqlx::query!("SELECT tags FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.tags, ["vip"]);
+}
+
+#[tokio::test]
+async fn invalid_tags_are_rejected() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+
+    let response = tag(&app, "ursula_le_guin%40gmail.com", &["Not%20A%20Tag"]).await;
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn preview_counts_matching_subscribers_and_recipients() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    app.post_subscriptions("name=tolkien&email=tolkien%40gmail.com".into())
+        .await;
+    app.create_confirmed_subscriber("pratchett@gmail.com").await;
+    tag(&app, "ursula_le_guin%40gmail.com", &["vip"]).await;
+    tag(&app, "tolkien%40gmail.com", &["vip"]).await;
+
+    save_segment(&app, "vips", json!({"tags": ["vip"]})).await;
+    let response = preview_segment(&app, "vips").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let size: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(size, json!({"subscribers": 2, "recipients": 1}));
+}
+
+#[tokio::test]
+async fn segments_filter_on_lists_attributes_and_status() {
+    let app = spawn_admin_app().await;
+    app.post_subscriptions(
+        "name=le%20guin&email=ursula_le_guin%40gmail.com&lists=weekly&role=engineering".into(),
+    )
+    .await;
+    app.post_subscriptions("name=tolkien&email=tolkien%40gmail.com&role=engineering".into())
+        .await;
+    let token = app.list_confirmation_token("ursula_le_guin@gmail.com", "Weekly digest");
+    app.get_confirmation(&token).await;
+
+    // Confirming a list leaves the newsletter pending, but the address is
+    // verified, so list mail still reaches it.
+    save_segment(
+        &app,
+        "weekly-engineers",
+        json!({
+            "statuses": ["pending_confirmation"],
+            "lists": ["weekly"],
+            "attributes": {"role": "engineering"},
+        }),
+    )
+    .await;
+    let size: serde_json::Value = preview_segment(&app, "weekly-engineers")
+        .await
+        .json()
+        .await
+        .unwrap();
+
+    assert_eq!(size, json!({"subscribers": 1, "recipients": 1}));
+}
+
+#[tokio::test]
+async fn segments_with_unknown_conditions_are_rejected() {
+    let app = spawn_admin_app().await;
+
+    let response = save_segment(&app, "typo", json!({"tag": ["vip"]})).await;
+
+    assert_eq!(response.status().as_u16(), 422);
+}
+
+#[tokio::test]
+async fn previewing_an_unknown_segment_is_a_404() {
+    let app = spawn_admin_app().await;
+
+    let response = preview_segment(&app, "nobody").await;
+
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+async fn send_issue(
+    app: &TestApp,
+    segment: &str,
+    idempotency_key: Option<&str>,
+) -> reqwest::Response {
+    let mut request = app
+        .admin_request(Method::POST, &format!("/admin/segments/{}/issues", segment))
+        .json(&json!({"subject": "Issue #1", "html_content": "<p>Hello!</p>"}));
+    if let Some(key) = idempotency_key {
+        request = request.header("Idempotency-Key", key);
+    }
+    request.send().await.expect("Failed to execute request.")
+}
+
+async fn delivery_statuses(app: &TestApp) -> Vec<(String, String)> {
+    sqlx::query!(
+        r#"
+        SELECT s.email, d.status FROM issue_deliveries d
+        JOIN subscriptions s ON s.id = d.subscriber_id
+        ORDER BY s.email
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|r| (r.email, r.status))
+    .collect()
+}
+
+#[tokio::test]
+async fn issues_are_sent_to_the_segment_as_it_is_at_send_time() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    save_segment(&app, "vips", json!({"tags": ["vip"]})).await;
+    // Tagged after the segment was saved.
+    tag(&app, "ursula_le_guin%40gmail.com", &["vip"]).await;
+
+    let response = send_issue(&app, "vips", None).await;
+
+    assert_eq!(response.status().as_u16(), 202);
+    let summary: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(summary["recipients"], 1);
+    assert_eq!(summary["queued"], 1);
+}
+
+#[tokio::test]
+async fn queued_issues_are_delivered_by_the_worker() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    save_segment(&app, "everyone", json!({})).await;
+
+    send_issue(&app, "everyone", None).await;
+    assert_eq!(
+        delivery_statuses(&app).await,
+        vec![("ursula_le_guin@gmail.com".into(), "queued".into())]
+    );
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(
+        delivery_statuses(&app).await,
+        vec![("ursula_le_guin@gmail.com".into(), "sent".into())]
+    );
+}
+
+#[tokio::test]
+async fn list_only_subscribers_get_list_issues_but_not_newsletter_issues() {
+    let app = spawn_admin_app().await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&lists=weekly".into())
+        .await;
+    let token = app.list_confirmation_token("ursula_le_guin@gmail.com", "Weekly digest");
+    app.get_confirmation(&token).await;
+    save_segment(&app, "everyone", json!({})).await;
+    save_segment(&app, "weekly", json!({"lists": ["weekly"]})).await;
+
+    let newsletter: serde_json::Value = send_issue(&app, "everyone", None)
+        .await
+        .json()
+        .await
+        .unwrap();
+    let weekly: serde_json::Value = send_issue(&app, "weekly", None).await.json().await.unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(newsletter["recipients"], 0);
+    assert_eq!(weekly["recipients"], 1);
+    assert_eq!(
+        delivery_statuses(&app).await,
+        vec![("ursula_le_guin@gmail.com".into(), "sent".into())]
+    );
+}
+
+#[tokio::test]
+async fn digest_subscribers_are_not_sent_issues_immediately() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    app.create_confirmed_subscriber("tolkien@gmail.com").await;
+    sqlx::query!(
+        "UPDATE subscriptions SET delivery_frequency = 'weekly_digest' WHERE email = $1",
+        "tolkien@gmail.com",
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    save_segment(&app, "everyone", json!({})).await;
+
+    let summary: serde_json::Value = send_issue(&app, "everyone", None)
+        .await
+        .json()
+        .await
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(summary["queued"], 1);
+    assert_eq!(summary["digest"], 1);
+    assert_eq!(
+        delivery_statuses(&app).await,
+        vec![
+            ("tolkien@gmail.com".into(), "digest".into()),
+            ("ursula_le_guin@gmail.com".into(), "sent".into()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn digest_subscribers_receive_the_issue_in_their_next_digest() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("tolkien@gmail.com").await;
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET delivery_frequency = 'weekly_digest', subscribed_at = now() - interval '8 days'
+        WHERE email = $1
+        "#,
+        "tolkien@gmail.com",
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    save_segment(&app, "everyone", json!({})).await;
+    send_issue(&app, "everyone", None).await;
+    app.dispatch_all_pending_emails().await;
+
+    app.send_due_digests().await;
+
+    let digests = issue_emails(&app, &["Your weekly digest: 1 new issue"]);
+    assert_eq!(digests.len(), 1);
+    assert!(digests[0].body.contains("<p>Hello!</p>"));
+    assert_eq!(
+        delivery_statuses(&app).await,
+        vec![("tolkien@gmail.com".into(), "sent".into())]
+    );
+}
+
+#[tokio::test]
+async fn retrying_a_send_with_the_same_idempotency_key_does_not_queue_it_again() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    save_segment(&app, "everyone", json!({})).await;
+
+    let first = send_issue(&app, "everyone", Some("issue-1")).await;
+    let retry = send_issue(&app, "everyone", Some("issue-1")).await;
+
+    assert_eq!(first.status().as_u16(), 202);
+    assert_eq!(retry.status().as_u16(), 200);
+    let first: serde_json::Value = first.json().await.unwrap();
+    let retry: serde_json::Value = retry.json().await.unwrap();
+    assert_eq!(first, retry);
+    let issues = sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(issues.count, 1);
+    assert_eq!(delivery_statuses(&app).await.len(), 1);
+}
+
+async fn send_subject_test(app: &TestApp, issue: serde_json::Value) -> reqwest::Response {
+    app.admin_request(Method::POST, "/admin/segments/everyone/issues")
+        .json(&issue)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+/// Follows a link from an email to the app under test, without following
+/// its redirect.
+async fn follow(app: &TestApp, link: &Url) -> reqwest::Response {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+        .get(format!(
+            "{}{}?{}",
+            app.address,
+            link.path(),
+            link.query().unwrap_or_default()
+        ))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+fn issue_emails(app: &TestApp, subjects: &[&str]) -> Vec<ReceivedEmail> {
+    app.email_server
+        .received_emails()
+        .into_iter()
+        .filter(|email| subjects.contains(&email.subject.as_str()))
+        .collect()
+}
+
+#[tokio::test]
+async fn subject_tests_send_the_winning_variant_to_everyone_else() {
+    let app = spawn_admin_app().await;
+    for email in ["a@gmail.com", "b@gmail.com", "c@gmail.com", "d@gmail.com"] {
+        app.create_confirmed_subscriber(email).await;
+    }
+    save_segment(&app, "everyone", json!({})).await;
+
+    let summary: serde_json::Value = send_subject_test(
+        &app,
+        json!({
+            "subject": "Issue A",
+            "subject_variants": ["Issue B"],
+            "html_content": "<p><a href=\"https://example.com/post\">Read on</a></p>",
+            "winner_metric": "clicks",
+        }),
+    )
+    .await
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(summary["queued"], 2);
+    assert_eq!(summary["held"], 2);
+    app.dispatch_all_pending_emails().await;
+
+    let sample = issue_emails(&app, &["Issue A", "Issue B"]);
+    assert_eq!(sample.len(), 2);
+    let links = |subject: &str| {
+        sample
+            .iter()
+            .find(|email| email.subject == subject)
+            .unwrap()
+            .links()
+    };
+    // Variant A is opened, but variant B gets the click the test is about.
+    let open = links("Issue A")
+        .into_iter()
+        .find(|link| link.path().ends_with("/open"))
+        .unwrap();
+    let response = follow(&app, &open).await;
+    assert_eq!(response.headers()["Content-Type"], "image/gif");
+    let click = links("Issue B")
+        .into_iter()
+        .find(|link| link.path().ends_with("/click"))
+        .unwrap();
+    let response = follow(&app, &click).await;
+    assert_eq!(response.status().as_u16(), 303);
+    assert_eq!(response.headers()["Location"], "https://example.com/post");
+
+    sqlx::query!("UPDATE newsletter_issues SET test_ends_at = now() - interval '1 minute'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(finish_subject_tests(&app.db_pool).await.unwrap(), 1);
+    app.dispatch_all_pending_emails().await;
+
+    let emails = issue_emails(&app, &["Issue A", "Issue B"]);
+    assert_eq!(emails.len(), 4);
+    assert!(emails[2..].iter().all(|email| email.subject == "Issue B"));
+    let results: Vec<_> = sqlx::query!(
+        "SELECT variant, sent, opens, clicks FROM issue_subject_variants ORDER BY variant"
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|r| (r.variant, r.sent, r.opens, r.clicks))
+    .collect();
+    assert_eq!(
+        results,
+        vec![
+            (0, Some(1), Some(1), Some(0)),
+            (1, Some(1), Some(1), Some(1))
+        ]
+    );
+    let issue = sqlx::query!("SELECT subject, winning_variant FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(issue.subject, "Issue B");
+    assert_eq!(issue.winning_variant, Some(1));
+}
+
+#[tokio::test]
+async fn tracked_links_cannot_be_pointed_elsewhere() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    save_segment(&app, "everyone", json!({})).await;
+    send_subject_test(
+        &app,
+        json!({
+            "subject": "Issue A",
+            "subject_variants": ["Issue B"],
+            "html_content": "<a href=\"https://example.com/post\">Read on</a>",
+        }),
+    )
+    .await;
+    app.dispatch_all_pending_emails().await;
+    let mut click = issue_emails(&app, &["Issue A", "Issue B"])[0]
+        .links()
+        .into_iter()
+        .find(|link| link.path().ends_with("/click"))
+        .unwrap();
+    let pairs: Vec<(String, String)> = click
+        .query_pairs()
+        .map(|(key, value)| match key.as_ref() {
+            "url" => (key.into_owned(), "https://evil.example.com".to_string()),
+            _ => (key.into_owned(), value.into_owned()),
+        })
+        .collect();
+    click.query_pairs_mut().clear().extend_pairs(pairs);
+
+    let response = follow(&app, &click).await;
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn subject_variants_must_be_distinct_and_non_empty() {
+    let app = spawn_admin_app().await;
+    save_segment(&app, "everyone", json!({})).await;
+
+    for variants in [json!(["Issue A"]), json!([" "])] {
+        let response = send_subject_test(
+            &app,
+            json!({
+                "subject": "Issue A",
+                "subject_variants": variants,
+                "html_content": "<p>Hello!</p>",
+            }),
+        )
+        .await;
+
+        assert_eq!(response.status().as_u16(), 400);
+    }
+}
@@ -0,0 +1,130 @@
+use reqwest::Method;
+
+use crate::helpers::{spawn_admin_app, TestApp};
+
+async fn export(app: &TestApp, query: &str) -> reqwest::Response {
+    app.admin_request(Method::GET, &format!("/admin/subscribers?{}", query))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn export_requires_the_admin_token() {
+    let app = spawn_admin_app().await;
+
+    let response = reqwest::get(format!("{}/admin/subscribers", app.address))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn csv_exports_have_a_stable_header_and_one_row_per_subscriber() {
+    let app = spawn_admin_app().await;
+    app.post_subscriptions(
+        "name=Le%20Guin%2C%20Ursula&email=ursula_le_guin%40gmail.com&company=Acme".into(),
+    )
+    .await;
+
+    let response = export(&app, "format=csv").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        response.headers()["content-type"],
+        "text/csv; charset=utf-8"
+    );
+    let body = response.text().await.unwrap();
+    let lines: Vec<&str> = body.lines().collect();
+    assert_eq!(
+        lines[0],
+        "id,email,name,status,subscribed_at,delivery_frequency,language,topics,tags,attributes,unsubscribed_at,display_email"
+    );
+    assert_eq!(lines.len(), 2);
+    assert!(
+        lines[1].contains(",ursula_le_guin@gmail.com,\"Le Guin, Ursula\",pending_confirmation,")
+    );
+    assert!(lines[1].contains(",\"{\"\"company\"\":\"\"Acme\"\"}\","));
+}
+
+#[tokio::test]
+async fn json_lines_exports_have_one_object_per_line_oldest_first() {
+    let app = spawn_admin_app().await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    app.post_subscriptions("name=tolkien&email=tolkien%40gmail.com".into())
+        .await;
+
+    let response = export(&app, "format=jsonl").await;
+
+    assert_eq!(response.headers()["content-type"], "application/x-ndjson");
+    let body = response.text().await.unwrap();
+    let emails: Vec<String> = body
+        .lines()
+        .map(|line| {
+            let row: serde_json::Value = serde_json::from_str(line).unwrap();
+            row["email"].as_str().unwrap().to_string()
+        })
+        .collect();
+    assert_eq!(emails, ["ursula_le_guin@gmail.com", "tolkien@gmail.com"]);
+}
+
+#[tokio::test]
+async fn exports_filter_by_status_and_list() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    app.post_subscriptions("name=tolkien&email=tolkien%40gmail.com&lists=weekly".into())
+        .await;
+    let token = app.list_confirmation_token("tolkien@gmail.com", "Weekly digest");
+    app.get_confirmation(&token).await;
+    app.post_subscriptions("name=pratchett&email=pratchett%40gmail.com".into())
+        .await;
+
+    let confirmed = export(&app, "format=jsonl&status=confirmed")
+        .await
+        .text()
+        .await
+        .unwrap();
+    let weekly = export(&app, "format=jsonl&list=weekly")
+        .await
+        .text()
+        .await
+        .unwrap();
+
+    // Tolkien only confirmed the list, not the newsletter.
+    assert_eq!(confirmed.lines().count(), 1);
+    assert!(confirmed.contains("ursula_le_guin@gmail.com"));
+    assert_eq!(weekly.lines().count(), 1);
+    assert!(weekly.contains("tolkien@gmail.com"));
+}
+
+#[tokio::test]
+async fn exports_filter_by_signup_date() {
+    let app = spawn_admin_app().await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    sqlx::query!(
+        "UPDATE subscriptions SET subscribed_at = '2020-01-01T00:00:00Z' WHERE email = 'ursula_le_guin@gmail.com'"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    app.post_subscriptions("name=tolkien&email=tolkien%40gmail.com".into())
+        .await;
+
+    let recent = export(&app, "format=jsonl&subscribed_after=2021-01-01T00:00:00Z")
+        .await
+        .text()
+        .await
+        .unwrap();
+    let old = export(&app, "format=jsonl&subscribed_before=2021-01-01T00:00:00Z")
+        .await
+        .text()
+        .await
+        .unwrap();
+
+    assert!(recent.contains("tolkien@gmail.com") && !recent.contains("ursula"));
+    assert!(old.contains("ursula_le_guin@gmail.com") && !old.contains("tolkien"));
+}
@@ -0,0 +1,252 @@
+use reqwest::Method;
+use serde_json::json;
+
+use crate::helpers::{spawn_admin_app, TestApp};
+
+async fn add_suppressions(app: &TestApp, body: serde_json::Value) -> reqwest::Response {
+    app.admin_request(Method::POST, "/admin/suppressions")
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn remove_suppressions(app: &TestApp, body: serde_json::Value) -> reqwest::Response {
+    app.admin_request(Method::POST, "/admin/suppressions/remove")
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn subscriber_count(app: &TestApp) -> i64 {
+    sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count
+}
+
+#[tokio::test]
+async fn suppression_endpoints_require_the_admin_token() {
+    let app = spawn_admin_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/admin/suppressions", app.address))
+        .json(&json!({"entries": []}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn suppressed_addresses_cannot_subscribe() {
+    let app = spawn_admin_app().await;
+
+    let response = add_suppressions(
+        &app,
+        json!({
+            "entries": [
+                {"email": "ursula_le_guin@gmail.com", "reason": "hard_bounce"},
+                {"email": "tolkien@gmail.com", "reason": "complaint"},
+            ],
+            "source": "bounce-feed",
+        }),
+    )
+    .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        report,
+        json!({"added": 2, "already_suppressed": 0, "rejected": []})
+    );
+    assert_eq!(subscriber_count(&app).await, 0);
+    let saved = sqlx::query!("SELECT reason, source FROM suppressions ORDER BY reason")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved[0].reason, "complaint");
+    assert_eq!(saved[1].reason, "hard_bounce");
+    assert!(saved.iter().all(|s| s.source == "bounce-feed"));
+}
+
+#[tokio::test]
+async fn bulk_adds_report_duplicates_and_invalid_addresses() {
+    let app = spawn_admin_app().await;
+    add_suppressions(
+        &app,
+        json!({"entries": [{"email": "ursula_le_guin@gmail.com", "reason": "do_not_contact"}]}),
+    )
+    .await;
+
+    let response = add_suppressions(
+        &app,
+        json!({
+            "entries": [
+                {"email": "URSULA_LE_GUIN@gmail.com", "reason": "hard_bounce"},
+                {"email": "not-an-email", "reason": "hard_bounce"},
+            ],
+        }),
+    )
+    .await;
+
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["added"], 0);
+    assert_eq!(report["already_suppressed"], 1);
+    assert_eq!(report["rejected"][0]["email"], "not-an-email");
+}
+
+#[tokio::test]
+async fn unknown_reasons_are_rejected() {
+    let app = spawn_admin_app().await;
+
+    let response = add_suppressions(
+        &app,
+        json!({"entries": [{"email": "ursula_le_guin@gmail.com", "reason": "bored"}]}),
+    )
+    .await;
+
+    assert_eq!(response.status().as_u16(), 422);
+}
+
+/// Stores a suppression keyed the way it was before addresses were
+/// canonicalized: by the Unicode form of the domain.
+async fn add_legacy_suppression(app: &TestApp, email: &str) {
+    sqlx::query!(
+        "INSERT INTO suppressions (email_hash, reason, source, created_at) VALUES ($1, 'hard_bounce', 'test', now())",
+        app.hmac_secret.sign(&format!("suppression:{}", email)),
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn suppressions_keyed_by_a_unicode_domain_still_apply() {
+    let app = spawn_admin_app().await;
+    add_legacy_suppression(&app, "ursula@bücher.example").await;
+
+    app.post_subscriptions("name=le%20guin&email=Ursula%40B%C3%BCcher.example".into())
+        .await;
+    assert_eq!(subscriber_count(&app).await, 0);
+
+    let response =
+        remove_suppressions(&app, json!({"emails": ["ursula@xn--bcher-kva.example"]})).await;
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["removed"], 1);
+}
+
+#[tokio::test]
+async fn removed_suppressions_allow_subscribing_again() {
+    let app = spawn_admin_app().await;
+    add_suppressions(
+        &app,
+        json!({"entries": [{"email": "ursula_le_guin@gmail.com", "reason": "hard_bounce"}]}),
+    )
+    .await;
+
+    let response = remove_suppressions(
+        &app,
+        json!({"emails": ["ursula_le_guin@gmail.com", "tolkien@gmail.com"]}),
+    )
+    .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        report,
+        json!({"removed": 1, "not_found": 1, "rejected": []})
+    );
+    assert_eq!(subscriber_count(&app).await, 1);
+}
+
+#[tokio::test]
+async fn issues_are_not_sent_to_suppressed_subscribers() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    app.create_confirmed_subscriber("tolkien@gmail.com").await;
+    app.admin_request(Method::PUT, "/admin/segments/everyone")
+        .json(&json!({}))
+        .send()
+        .await
+        .unwrap();
+    add_suppressions(
+        &app,
+        json!({"entries": [{"email": "tolkien@gmail.com", "reason": "complaint"}]}),
+    )
+    .await;
+
+    let response = app
+        .admin_request(Method::POST, "/admin/segments/everyone/issues")
+        .json(&json!({"subject": "Issue #1", "html_content": "<p>Hello!</p>"}))
+        .send()
+        .await
+        .unwrap();
+
+    let summary: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(summary["queued"], 1);
+    assert_eq!(summary["suppressed"], 1);
+}
+
+#[tokio::test]
+async fn erasure_tombstones_cannot_be_removed() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    app.admin_request(Method::POST, "/admin/subscribers/erase")
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("email=ursula_le_guin%40gmail.com")
+        .send()
+        .await
+        .unwrap();
+
+    let response = remove_suppressions(&app, json!({"emails": ["ursula_le_guin@gmail.com"]})).await;
+
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["removed"], 0);
+    assert_eq!(report["not_found"], 0);
+    assert_eq!(report["rejected"][0]["email"], "ursula_le_guin@gmail.com");
+    let reasons = sqlx::query!("SELECT reason FROM suppressions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(reasons.len(), 1);
+    assert_eq!(reasons[0].reason, "erasure");
+}
+
+#[tokio::test]
+async fn erasure_turns_an_existing_suppression_into_a_tombstone() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    add_suppressions(
+        &app,
+        json!({"entries": [{"email": "ursula_le_guin@gmail.com", "reason": "hard_bounce"}]}),
+    )
+    .await;
+    app.admin_request(Method::POST, "/admin/subscribers/erase")
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("email=ursula_le_guin%40gmail.com")
+        .send()
+        .await
+        .unwrap();
+
+    let response = remove_suppressions(&app, json!({"emails": ["ursula_le_guin@gmail.com"]})).await;
+
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["removed"], 0);
+    assert_eq!(report["rejected"][0]["email"], "ursula_le_guin@gmail.com");
+    let reasons = sqlx::query!("SELECT reason FROM suppressions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(reasons.len(), 1);
+    assert_eq!(reasons[0].reason, "erasure");
+}
@@ -0,0 +1,171 @@
+use reqwest::Method;
+
+use crate::helpers::{spawn_admin_app, spawn_app, TestApp};
+
+async fn post_subscription_with_user_agent(app: &TestApp, body: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("User-Agent", "consent-test/1.0")
+        .body(body.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn subscribing_records_a_consent_event() {
+    let app = spawn_app().await;
+
+    post_subscription_with_user_agent(
+        &app,
+        "name=le%20guin&email=ursula_le_guin%40gmail.com&source=blog-footer&consent_version=v7",
+    )
+    .await;
+
+    let event = sqlx::query!(
+        "SELECT event, list_id, ip, user_agent, source, consent_version FROM consent_events"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(event.event, "subscribe");
+    assert_eq!(event.list_id, None);
+    assert_eq!(event.ip.as_deref(), Some("127.0.0.1"));
+    assert_eq!(event.user_agent.as_deref(), Some("consent-test/1.0"));
+    assert_eq!(event.source.as_deref(), Some("blog-footer"));
+    assert_eq!(event.consent_version.as_deref(), Some("v7"));
+}
+
+#[tokio::test]
+async fn the_configured_consent_version_is_the_default() {
+    let app = spawn_app().await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    let event = sqlx::query!("SELECT source, consent_version FROM consent_events")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(event.source, None);
+    assert_eq!(event.consent_version.as_deref(), Some("test-consent-v1"));
+}
+
+#[tokio::test]
+async fn unknown_consent_versions_are_rejected() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com",
+            "consent_version": "made-up",
+        }))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["fields"][0]["field"], "consent_version");
+    let events = sqlx::query!("SELECT consent_version FROM consent_events")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(events.is_empty());
+}
+
+#[tokio::test]
+async fn subscribing_to_lists_records_one_event_per_list() {
+    let app = spawn_app().await;
+
+    app.post_subscriptions(
+        "name=le%20guin&email=ursula_le_guin%40gmail.com&lists=weekly&lists=releases".into(),
+    )
+    .await;
+
+    let lists: Vec<String> = sqlx::query!(
+        r#"
+        SELECT l.slug FROM consent_events c JOIN lists l ON l.id = c.list_id
+        WHERE c.event = 'subscribe' ORDER BY l.slug
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|r| r.slug)
+    .collect();
+    assert_eq!(lists, ["releases", "weekly"]);
+}
+
+#[tokio::test]
+async fn confirming_records_an_event_with_the_signup_consent_version() {
+    let app = spawn_app().await;
+    post_subscription_with_user_agent(
+        &app,
+        "name=le%20guin&email=ursula_le_guin%40gmail.com&source=blog-footer&consent_version=v7",
+    )
+    .await;
+    let token = app.confirmation_token("ursula_le_guin@gmail.com");
+
+    app.get_confirmation(&token).await;
+
+    let event = sqlx::query!(
+        "SELECT ip, source, consent_version FROM consent_events WHERE event = 'confirm'"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(event.ip.as_deref(), Some("127.0.0.1"));
+    assert_eq!(event.source.as_deref(), Some("blog-footer"));
+    assert_eq!(event.consent_version.as_deref(), Some("v7"));
+}
+
+#[tokio::test]
+async fn consent_events_are_included_in_data_exports() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+
+    let export: serde_json::Value = app
+        .admin_request(
+            Method::GET,
+            "/admin/subscribers/export?email=ursula_le_guin%40gmail.com",
+        )
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    let events: Vec<&str> = export["consent_events"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|event| event["event"].as_str().unwrap())
+        .collect();
+    assert_eq!(events, ["subscribe", "confirm"]);
+}
+
+#[tokio::test]
+async fn erasure_keeps_consent_events_without_the_ip_and_user_agent() {
+    let app = spawn_admin_app().await;
+    post_subscription_with_user_agent(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com")
+        .await;
+
+    app.admin_request(Method::POST, "/admin/subscribers/erase")
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("email=ursula_le_guin%40gmail.com")
+        .send()
+        .await
+        .unwrap();
+
+    let event = sqlx::query!("SELECT ip, user_agent, consent_version FROM consent_events")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(event.ip, None);
+    assert_eq!(event.user_agent, None);
+    assert_eq!(event.consent_version.as_deref(), Some("test-consent-v1"));
+}
@@ -0,0 +1,142 @@
+use reqwest::Method;
+use serde_json::json;
+
+use crate::helpers::{spawn_admin_app, TestApp};
+
+async fn create_digest_subscriber(app: &TestApp, email: &str) {
+    app.create_confirmed_subscriber(email).await;
+    sqlx::query!(
+        "UPDATE subscriptions SET delivery_frequency = 'weekly_digest' WHERE email = $1",
+        email,
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+/// Moves the subscriber's digest week, started at signup or at the last
+/// digest, `days` into the past.
+async fn age_digest_week(app: &TestApp, email: &str, days: i32) {
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET subscribed_at = subscribed_at - make_interval(days => $2),
+            last_digest_at = last_digest_at - make_interval(days => $2)
+        WHERE email = $1
+        "#,
+        email,
+        days,
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+async fn send_issue(app: &TestApp, subject: &str) {
+    app.admin_request(Method::PUT, "/admin/segments/everyone")
+        .json(&json!({}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    app.admin_request(Method::POST, "/admin/segments/everyone/issues")
+        .json(&json!({"subject": subject, "html_content": format!("<p>{}</p>", subject)}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+}
+
+async fn delivery_statuses(app: &TestApp) -> Vec<String> {
+    sqlx::query_scalar!("SELECT status FROM issue_deliveries ORDER BY queued_at")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn digest_subscribers_get_the_week_of_issues_in_one_email() {
+    let app = spawn_admin_app().await;
+    create_digest_subscriber(&app, "tolkien@gmail.com").await;
+    send_issue(&app, "Issue #1").await;
+    send_issue(&app, "Issue #2").await;
+    app.dispatch_all_pending_emails().await;
+    age_digest_week(&app, "tolkien@gmail.com", 8).await;
+
+    let sent = app.send_due_digests().await;
+
+    assert_eq!(sent, 1);
+    let emails = app.emails_to("tolkien@gmail.com");
+    // The confirmation email, then the digest.
+    assert_eq!(emails.len(), 2);
+    assert_eq!(emails[1].subject, "Your weekly digest: 2 new issues");
+    assert!(emails[1].body.contains("Issue #1"));
+    assert!(emails[1].body.contains("Issue #2"));
+    assert_eq!(delivery_statuses(&app).await, vec!["sent", "sent"]);
+}
+
+#[tokio::test]
+async fn digests_are_not_sent_before_the_week_is_over() {
+    let app = spawn_admin_app().await;
+    create_digest_subscriber(&app, "tolkien@gmail.com").await;
+    send_issue(&app, "Issue #1").await;
+
+    let sent = app.send_due_digests().await;
+
+    assert_eq!(sent, 0);
+    assert_eq!(app.emails_to("tolkien@gmail.com").len(), 1);
+    assert_eq!(delivery_statuses(&app).await, vec!["digest"]);
+}
+
+#[tokio::test]
+async fn a_digest_only_has_the_issues_since_the_last_one() {
+    let app = spawn_admin_app().await;
+    create_digest_subscriber(&app, "tolkien@gmail.com").await;
+    send_issue(&app, "Issue #1").await;
+    age_digest_week(&app, "tolkien@gmail.com", 8).await;
+    app.send_due_digests().await;
+
+    send_issue(&app, "Issue #2").await;
+    let too_early = app.send_due_digests().await;
+    age_digest_week(&app, "tolkien@gmail.com", 8).await;
+    let on_time = app.send_due_digests().await;
+
+    assert_eq!((too_early, on_time), (0, 1));
+    let emails = app.emails_to("tolkien@gmail.com");
+    assert_eq!(emails.len(), 3);
+    assert_eq!(emails[2].subject, "Your weekly digest: 1 new issue");
+    assert!(emails[2].body.contains("Issue #2"));
+    assert!(!emails[2].body.contains("Issue #1"));
+}
+
+#[tokio::test]
+async fn digests_are_not_sent_to_subscribers_who_left() {
+    let app = spawn_admin_app().await;
+    create_digest_subscriber(&app, "tolkien@gmail.com").await;
+    send_issue(&app, "Issue #1").await;
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    age_digest_week(&app, "tolkien@gmail.com", 8).await;
+
+    let sent = app.send_due_digests().await;
+
+    assert_eq!(sent, 0);
+    assert_eq!(app.emails_to("tolkien@gmail.com").len(), 1);
+    assert_eq!(delivery_statuses(&app).await, vec!["skipped"]);
+}
+
+#[tokio::test]
+async fn a_failed_digest_is_sent_on_the_next_run() {
+    let app = spawn_admin_app().await;
+    create_digest_subscriber(&app, "tolkien@gmail.com").await;
+    send_issue(&app, "Issue #1").await;
+    age_digest_week(&app, "tolkien@gmail.com", 8).await;
+
+    app.email_server.set_failing(true);
+    let failed = app.send_due_digests().await;
+    app.email_server.set_failing(false);
+    let retried = app.send_due_digests().await;
+
+    assert_eq!((failed, retried), (0, 1));
+    assert_eq!(delivery_statuses(&app).await, vec!["sent"]);
+}
@@ -1,9 +1,27 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
 use once_cell::sync::Lazy;
-use secrecy::SecretString;
-use sqlx::PgPool;
+use secrecy::{ExposeSecret, SecretString};
+use sqlx::{Connection, Executor, PgConnection, PgPool};
+use url::Url;
+use uuid::Uuid;
 use zero2prod::{
     configuration::*,
+    digests::send_due_digests,
+    email_client::EmailClient,
+    hmac_secret::HmacSecret,
+    issue_delivery_worker::{try_execute_task, ExecutionOutcome},
+    routes::{store_email_revert_token, store_token, TokenPurpose},
     startup::{get_connection_pool, Application},
+    suppressions::SuppressionList,
     telemetry::{get_subscriber, init_subscriber},
 };
 
@@ -19,57 +37,586 @@ static TRACING: Lazy<()> = Lazy::new(|| {
     }
 });
 
+pub const ADMIN_TOKEN: &str = "test-admin-token";
+
 pub struct TestApp {
     pub address: String,
     pub db_pool: PgPool,
+    pub hmac_secret: HmacSecret,
+    pub email_client: EmailClient,
+    pub base_url: String,
+    pub email_server: MailServer,
+    pub issue_delivery: IssueDeliverySettings,
+}
+
+/// An SMTP server that keeps every message it is sent, so tests can follow
+/// the links in the emails the app actually sent.
+pub struct MailServer {
+    pub port: u16,
+    received: Arc<Mutex<Vec<ReceivedEmail>>>,
+    failing: Arc<AtomicBool>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReceivedEmail {
+    pub recipients: Vec<String>,
+    pub subject: String,
+    pub body: String,
+}
+
+impl MailServer {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind the mail server.");
+        let port = listener.local_addr().unwrap().port();
+        let received = Arc::new(Mutex::new(vec![]));
+        let failing = Arc::new(AtomicBool::new(false));
+        let (inbox, fail) = (received.clone(), failing.clone());
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let (inbox, fail) = (inbox.clone(), fail.clone());
+                std::thread::spawn(move || serve_smtp(stream, &inbox, &fail));
+            }
+        });
+        Self {
+            port,
+            received,
+            failing,
+        }
+    }
+
+    /// Makes the server reject every message until it is switched back.
+    pub fn set_failing(&self, failing: bool) {
+        self.failing.store(failing, Ordering::SeqCst);
+    }
+
+    pub fn received_emails(&self) -> Vec<ReceivedEmail> {
+        self.received.lock().unwrap().clone()
+    }
+}
+
+/// Just enough SMTP for lettre without TLS or authentication.
+fn serve_smtp(
+    stream: TcpStream,
+    inbox: &Mutex<Vec<ReceivedEmail>>,
+    failing: &AtomicBool,
+) -> std::io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut lines = BufReader::new(stream).lines();
+    writer.write_all(b"220 localhost ESMTP\r\n")?;
+    let mut recipients = vec![];
+    while let Some(line) = lines.next().transpose()? {
+        let command = line.to_ascii_uppercase();
+        if command.starts_with("RCPT TO:") {
+            recipients.push(line[8..].trim().trim_matches(['<', '>']).to_string());
+        } else if command.starts_with("MAIL FROM:") || command == "RSET" {
+            recipients.clear();
+        } else if command == "DATA" {
+            writer.write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n")?;
+            let mut message = vec![];
+            for line in lines.by_ref() {
+                let line = line?;
+                if line == "." {
+                    break;
+                }
+                // Undo the dot-stuffing of lines that start with a dot.
+                message.push(line.strip_prefix('.').unwrap_or(&line).to_string());
+            }
+            if failing.load(Ordering::SeqCst) {
+                recipients.clear();
+                writer.write_all(b"554 Transaction failed\r\n")?;
+                continue;
+            }
+            inbox.lock().unwrap().push(ReceivedEmail::parse(
+                std::mem::take(&mut recipients),
+                &message,
+            ));
+        } else if command == "QUIT" {
+            writer.write_all(b"221 Bye\r\n")?;
+            break;
+        }
+        writer.write_all(b"250 OK\r\n")?;
+    }
+    Ok(())
+}
+
+impl ReceivedEmail {
+    fn parse(recipients: Vec<String>, message: &[String]) -> Self {
+        let blank = message
+            .iter()
+            .position(|line| line.is_empty())
+            .unwrap_or(message.len());
+        // Unfold headers that were wrapped onto several lines.
+        let mut headers: Vec<String> = vec![];
+        for line in &message[..blank] {
+            match headers.last_mut() {
+                Some(header) if line.starts_with([' ', '\t']) => header.push_str(line),
+                _ => headers.push(line.clone()),
+            }
+        }
+        let header = |name: &str| {
+            headers.iter().find_map(|header| {
+                let (key, value) = header.split_once(':')?;
+                key.eq_ignore_ascii_case(name)
+                    .then(|| value.trim().to_string())
+            })
+        };
+        let lines = message.get(blank + 1..).unwrap_or_default();
+        let body = match header("Content-Transfer-Encoding") {
+            Some(encoding) if encoding.eq_ignore_ascii_case("quoted-printable") => {
+                decode_quoted_printable(lines)
+            }
+            _ => lines.join("\n"),
+        };
+        Self {
+            recipients,
+            subject: header("Subject").unwrap_or_default(),
+            body,
+        }
+    }
+
+    /// Every link and image source in the body.
+    pub fn links(&self) -> Vec<Url> {
+        ["href=\"", "src=\""]
+            .iter()
+            .flat_map(|attribute| self.body.split(attribute).skip(1))
+            .filter_map(|rest| rest.split('"').next())
+            .filter_map(|link| Url::parse(&link.replace("&amp;", "&")).ok())
+            .collect()
+    }
+
+    /// The value of the `param` query parameter in the first link to `path`.
+    pub fn link_param(&self, path: &str, param: &str) -> Option<String> {
+        self.links()
+            .into_iter()
+            .filter(|link| link.path() == path)
+            .find_map(|link| {
+                link.query_pairs()
+                    .find(|(key, _)| key == param)
+                    .map(|(_, value)| value.into_owned())
+            })
+    }
+}
+
+fn decode_quoted_printable(lines: &[String]) -> String {
+    let mut bytes = vec![];
+    for line in lines {
+        let (line, soft_break) = match line.strip_suffix('=') {
+            Some(line) => (line, true),
+            None => (line.as_str(), false),
+        };
+        let mut rest = line.as_bytes();
+        while let Some((&byte, tail)) = rest.split_first() {
+            match (byte, tail.get(..2)) {
+                (b'=', Some(hex)) => {
+                    let hex = std::str::from_utf8(hex).unwrap();
+                    bytes.push(u8::from_str_radix(hex, 16).unwrap());
+                    rest = &tail[2..];
+                }
+                _ => {
+                    bytes.push(byte);
+                    rest = tail;
+                }
+            }
+        }
+        if !soft_break {
+            bytes.push(b'\n');
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
 }
 
 impl TestApp {
+    /// Starts a request to an admin endpoint, authenticated with `ADMIN_TOKEN`.
+    pub fn admin_request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        reqwest::Client::new()
+            .request(method, format!("{}{}", &self.address, path))
+            .bearer_auth(ADMIN_TOKEN)
+    }
+
     pub async fn post_subscriptions(&self, body: String) -> reqwest::Response {
         reqwest::Client::new()
-            .post(&format! {"{}/subscriptions", &self.address})
+            .post(format! {"{}/subscriptions", &self.address})
             .header("Content-Type", "application/x-www-form-urlencoded")
             .body(body)
             .send()
             .await
             .expect("Failed to execute request.")
     }
+
+    pub async fn post_subscriptions_json(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format! {"{}/subscriptions", &self.address})
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_confirmation(&self, subscription_token: &str) -> reqwest::Response {
+        reqwest::get(format!(
+            "{}/subscriptions/confirm?subscription_token={}",
+            &self.address, subscription_token
+        ))
+        .await
+        .expect("Failed to execute request.")
+    }
+
+    pub async fn create_confirmed_subscriber(&self, email: &str) {
+        self.post_subscriptions(format!("name=le%20guin&email={}", email))
+            .await;
+        let token = self.confirmation_token(email);
+        self.get_confirmation(&token).await;
+    }
+
+    /// The emails sent so far to `address`, oldest first.
+    pub fn emails_to(&self, address: &str) -> Vec<ReceivedEmail> {
+        self.email_server
+            .received_emails()
+            .into_iter()
+            .filter(|email| {
+                email
+                    .recipients
+                    .iter()
+                    .any(|recipient| recipient.eq_ignore_ascii_case(address))
+            })
+            .collect()
+    }
+
+    /// The token in the latest confirmation link emailed to `address`.
+    pub fn confirmation_token(&self, address: &str) -> String {
+        self.emails_to(address)
+            .iter()
+            .rev()
+            .find_map(|email| email.link_param("/subscriptions/confirm", "subscription_token"))
+            .expect("No confirmation link was emailed.")
+    }
+
+    /// The token in the latest confirmation link for the list called
+    /// `list_name` emailed to `address`.
+    pub fn list_confirmation_token(&self, address: &str, list_name: &str) -> String {
+        let list = format!("your subscription to {}.", list_name);
+        self.emails_to(address)
+            .iter()
+            .rev()
+            .filter(|email| email.body.contains(&list))
+            .find_map(|email| email.link_param("/subscriptions/confirm", "subscription_token"))
+            .expect("No list confirmation link was emailed.")
+    }
+
+    /// Logs in to the preference center through a magic link and returns the
+    /// session cookie.
+    pub async fn log_in_to_preferences(&self, email: &str) -> String {
+        let token = self.issue_magic_link_token(email).await;
+        let response = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap()
+            .get(format!(
+                "{}/subscriptions/preferences/login?token={}",
+                &self.address, token
+            ))
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        assert_eq!(response.status().as_u16(), 303);
+        let cookie = response
+            .headers()
+            .get(reqwest::header::SET_COOKIE)
+            .expect("No session cookie was set.")
+            .to_str()
+            .unwrap();
+        cookie.split(';').next().unwrap().to_string()
+    }
+
+    /// The CSRF token the preference center renders for the session.
+    pub async fn preferences_csrf_token(&self, cookie: &str) -> String {
+        let page = reqwest::Client::new()
+            .get(format!("{}/subscriptions/preferences", &self.address))
+            .header(reqwest::header::COOKIE, cookie)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .text()
+            .await
+            .unwrap();
+        page.split(r#"name="csrf_token" value=""#)
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .expect("No CSRF token was rendered.")
+            .to_string()
+    }
+
+    pub async fn issue_subscription_token(&self, email: &str) -> String {
+        self.issue_token(email, TokenPurpose::Confirmation).await
+    }
+
+    pub async fn issue_magic_link_token(&self, email: &str) -> String {
+        self.issue_token(email, TokenPurpose::MagicLink).await
+    }
+
+    /// Only the hash of the emailed token is stored, so tests mint their own
+    /// token for a subscriber through the same code path.
+    pub async fn issue_token(&self, email: &str, purpose: TokenPurpose) -> String {
+        let subscriber = sqlx::query!("SELECT id FROM subscriptions WHERE email = $1", email)
+            .fetch_one(&self.db_pool)
+            .await
+            .expect("Failed to fetch saved subscription.");
+        let subscription_token = Uuid::new_v4().simple().to_string();
+        let mut connection = self.db_pool.acquire().await.unwrap();
+        store_token(
+            &mut connection,
+            subscriber.id,
+            &subscription_token,
+            purpose,
+            &self.hmac_secret,
+            Duration::from_secs(3600),
+        )
+        .await
+        .expect("Failed to store subscription token.");
+        subscription_token
+    }
+
+    /// Mints the revert link mailed to the old address when the change to
+    /// `new_email` was confirmed.
+    pub async fn issue_revert_token(&self, new_email: &str) -> String {
+        let change = sqlx::query!(
+            "SELECT id, subscriber_id FROM email_changes WHERE new_email = $1 AND confirmed_at IS NOT NULL",
+            new_email,
+        )
+        .fetch_one(&self.db_pool)
+        .await
+        .expect("Failed to fetch email change.");
+        let subscription_token = Uuid::new_v4().simple().to_string();
+        let mut connection = self.db_pool.acquire().await.unwrap();
+        store_email_revert_token(
+            &mut connection,
+            change.subscriber_id,
+            change.id,
+            &subscription_token,
+            &self.hmac_secret,
+            Duration::from_secs(3600),
+        )
+        .await
+        .expect("Failed to store subscription token.");
+        subscription_token
+    }
+
+    /// Runs the issue delivery worker until the queue is drained.
+    pub async fn dispatch_all_pending_emails(&self) {
+        loop {
+            if let ExecutionOutcome::EmptyQueue = try_execute_task(
+                &self.db_pool,
+                &self.email_client,
+                &self.base_url,
+                &self.hmac_secret,
+                &self.issue_delivery,
+            )
+            .await
+            .unwrap()
+            {
+                break;
+            }
+        }
+    }
+
+    /// Runs the digest job once and returns how many digests were sent.
+    pub async fn send_due_digests(&self) -> u64 {
+        send_due_digests(
+            &self.db_pool,
+            &self.email_client,
+            &self.base_url,
+            &self.hmac_secret,
+        )
+        .await
+        .unwrap()
+    }
+
+    pub async fn list_memberships(&self) -> Vec<(String, String)> {
+        sqlx::query!(
+            r#"
+            SELECT l.slug, m.status FROM list_memberships m
+            JOIN lists l ON l.id = m.list_id
+            ORDER BY l.slug
+            "#
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .expect("Failed to fetch list memberships.")
+        .into_iter()
+        .map(|r| (r.slug, r.status))
+        .collect()
+    }
 }
 
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+
+/// Spawns the app with admin endpoints open to `ADMIN_TOKEN`.
+pub async fn spawn_admin_app() -> TestApp {
+    spawn_app_with(|c| c.application.admin_token = Some(SecretString::from(ADMIN_TOKEN))).await
+}
+
+/// Spawns the app after letting the test adjust the configuration.
+pub async fn spawn_app_with(customise: impl FnOnce(&mut Settings)) -> TestApp {
     Lazy::force(&TRACING);
 
-    let configuration = Settings {
+    let email_server = MailServer::start();
+    let mut configuration = Settings {
         database: DatabaseSettings {
             host: "postgres".to_string(),
             port: 5432,
             username: "postgres".to_string(),
             password: SecretString::from("password"),
-            database_name: "newsletter".to_string(),
+            database_name: Uuid::new_v4().to_string(),
         },
         application: ApplicationSettings {
             port: 0,
             host: "0.0.0.0".to_string(),
             base_url: "http://127.0.0.1:8000".to_string(),
+            hmac_secret: SecretString::from("long-and-very-secret-random-key"),
+            admin_token: None,
         },
         email_client: EmailClientSettings {
             sender_email: "cndoit18@outlook.com".to_string(),
             email_service: EmailService::Smtp(SmtpSettings {
-                host: "mailtutan".to_string(),
-                port: 1025,
+                host: "127.0.0.1".to_string(),
+                port: email_server.port.into(),
                 username: None,
                 password: None,
             }),
             timeout_milliseconds: 10000,
         },
+        subscription_tokens: SubscriptionTokenSettings {
+            expiry_minutes: 60,
+            cleanup_interval_seconds: 60,
+            resend_cooldown_seconds: 300,
+        },
+        preferences: PreferenceSettings {
+            magic_link_expiry_minutes: 15,
+            session_expiry_minutes: 60,
+            topics: vec!["rust".to_string(), "databases".to_string()],
+            languages: vec!["en".to_string(), "zh".to_string()],
+        },
+        lists: vec![
+            ListSettings {
+                slug: "weekly".to_string(),
+                name: "Weekly digest".to_string(),
+                sender_email: "weekly@example.com".to_string(),
+                welcome_subject: "Welcome to the weekly digest!".to_string(),
+                welcome_body: "Thanks for signing up.".to_string(),
+            },
+            ListSettings {
+                slug: "releases".to_string(),
+                name: "Release notes".to_string(),
+                sender_email: "releases@example.com".to_string(),
+                welcome_subject: "Welcome to our release notes!".to_string(),
+                welcome_body: "Thanks for signing up.".to_string(),
+            },
+        ],
+        attributes: vec![
+            AttributeSettings {
+                name: "company".to_string(),
+                kind: AttributeType::Text,
+                required: false,
+                max_length: Some(20),
+                min: None,
+                max: None,
+                choices: vec![],
+            },
+            AttributeSettings {
+                name: "employees".to_string(),
+                kind: AttributeType::Number,
+                required: false,
+                max_length: None,
+                min: Some(1.0),
+                max: None,
+                choices: vec![],
+            },
+            AttributeSettings {
+                name: "role".to_string(),
+                kind: AttributeType::Choice,
+                required: false,
+                max_length: None,
+                min: None,
+                max: None,
+                choices: vec!["engineering".to_string(), "marketing".to_string()],
+            },
+        ],
+        bot_protection: BotProtectionSettings {
+            honeypot_field: Some("website".to_string()),
+            min_submit_seconds: 0,
+            max_form_age_minutes: 120,
+            proof_of_work_difficulty: 0,
+        },
+        rate_limits: RateLimitSettings {
+            store: RateLimitStoreKind::InMemory,
+            trusted_proxies: vec![],
+            per_ip: TokenBucketSettings {
+                capacity: 1000,
+                refill_interval_seconds: 1,
+            },
+            per_email: TokenBucketSettings {
+                capacity: 1000,
+                refill_interval_seconds: 1,
+            },
+        },
+        retention: RetentionSettings {
+            pending_max_age_days: 30,
+            mode: RetentionMode::Delete,
+            dry_run: false,
+            interval_seconds: 86400,
+        },
+        consent: ConsentSettings {
+            text_version: "test-consent-v1".to_string(),
+            accepted_versions: vec!["v7".to_string()],
+        },
+        subject_tests: SubjectTestSettings {
+            sample_percent: 50,
+            wait_minutes: 60,
+        },
+        issue_delivery: IssueDeliverySettings {
+            max_attempts: 2,
+            retry_base_seconds: 60,
+            retry_max_seconds: 3600,
+        },
     };
+    customise(&mut configuration);
+    configure_database(&configuration.database).await;
+
     let application = Application::build(configuration.clone())
         .await
         .expect("Failed to build application.");
     let address = format!("http://127.0.0.1:{}", application.port());
     tokio::spawn(application.run_until_stopped());
+    let db_pool = get_connection_pool(&configuration.database);
+    let hmac_secret = HmacSecret::new(configuration.application.hmac_secret);
+    let email_client = configuration
+        .email_client
+        .client()
+        .with_suppressions(SuppressionList::new(db_pool.clone(), hmac_secret.clone()));
     TestApp {
         address,
-        db_pool: get_connection_pool(&configuration.database),
+        db_pool,
+        hmac_secret,
+        email_client,
+        base_url: configuration.application.base_url,
+        email_server,
+        issue_delivery: configuration.issue_delivery,
     }
 }
+
+async fn configure_database(configuration: &DatabaseSettings) {
+    let maintenance_settings = DatabaseSettings {
+        database_name: "postgres".to_string(),
+        ..configuration.clone()
+    };
+    let mut connection =
+        PgConnection::connect(maintenance_settings.connection_string().expose_secret())
+            .await
+            .expect("Failed to connect to Postgres.");
+    connection
+        .execute(format!(r#"CREATE DATABASE "{}";"#, configuration.database_name).as_str())
+        .await
+        .expect("Failed to create database.");
+}
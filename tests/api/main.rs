@@ -1,4 +1,22 @@
+mod admin_erasure;
+mod admin_import;
+mod admin_issues;
+mod admin_segments;
+mod admin_subscribers;
+mod admin_suppressions;
+mod consent;
+mod digests;
 mod health_check;
 mod helpers;
+mod retention;
 mod subscriptions;
+mod subscriptions_attributes;
+mod subscriptions_bot_protection;
 mod subscriptions_confirm;
+mod subscriptions_email_change;
+mod subscriptions_export;
+mod subscriptions_lists;
+mod subscriptions_preferences;
+mod subscriptions_rate_limit;
+mod subscriptions_resend;
+mod subscriptions_unsubscribe;
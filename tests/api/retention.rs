@@ -0,0 +1,148 @@
+use zero2prod::{
+    configuration::{RetentionMode, RetentionSettings},
+    retention_worker::{purge_stale_subscribers, PurgeSummary},
+};
+
+use crate::helpers::{spawn_app, TestApp};
+
+fn settings(mode: RetentionMode, dry_run: bool) -> RetentionSettings {
+    RetentionSettings {
+        pending_max_age_days: 30,
+        mode,
+        dry_run,
+        interval_seconds: 86400,
+    }
+}
+
+/// One stale pending subscriber, one recent pending subscriber and one stale
+/// confirmed subscriber.
+async fn seed(app: &TestApp) {
+    app.post_subscriptions("name=stale&email=stale%40gmail.com".into())
+        .await;
+    app.post_subscriptions("name=recent&email=recent%40gmail.com".into())
+        .await;
+    app.create_confirmed_subscriber("confirmed@gmail.com").await;
+    sqlx::query!(
+        "UPDATE subscriptions SET subscribed_at = now() - interval '31 days' WHERE email <> 'recent@gmail.com'"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    sqlx::query!("UPDATE subscription_tokens SET created_at = now() - interval '31 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+}
+
+async fn emails(app: &TestApp) -> Vec<String> {
+    sqlx::query!("SELECT email FROM subscriptions ORDER BY email")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| r.email)
+        .collect()
+}
+
+#[tokio::test]
+async fn stale_pending_subscribers_are_deleted_with_their_tokens() {
+    let app = spawn_app().await;
+    seed(&app).await;
+
+    let summary = purge_stale_subscribers(&app.db_pool, &settings(RetentionMode::Delete, false))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        summary,
+        PurgeSummary {
+            subscribers: 1,
+            tokens: 1
+        }
+    );
+    assert_eq!(
+        emails(&app).await,
+        ["confirmed@gmail.com", "recent@gmail.com"]
+    );
+}
+
+#[tokio::test]
+async fn subscribers_with_a_recent_confirmation_email_are_kept() {
+    let app = spawn_app().await;
+    seed(&app).await;
+    // A resend issues a fresh token without touching the signup date.
+    app.issue_subscription_token("stale@gmail.com").await;
+
+    let summary = purge_stale_subscribers(&app.db_pool, &settings(RetentionMode::Delete, false))
+        .await
+        .unwrap();
+
+    assert_eq!(summary.subscribers, 0);
+    assert_eq!(
+        emails(&app).await,
+        ["confirmed@gmail.com", "recent@gmail.com", "stale@gmail.com"]
+    );
+}
+
+#[tokio::test]
+async fn subscribers_who_confirmed_a_list_are_kept() {
+    let app = spawn_app().await;
+    app.post_subscriptions("name=listed&email=listed%40gmail.com&lists=weekly".into())
+        .await;
+    let token = app.list_confirmation_token("listed@gmail.com", "Weekly digest");
+    app.get_confirmation(&token).await;
+    sqlx::query!("UPDATE subscriptions SET subscribed_at = now() - interval '31 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let summary = purge_stale_subscribers(&app.db_pool, &settings(RetentionMode::Delete, false))
+        .await
+        .unwrap();
+
+    assert_eq!(summary.subscribers, 0);
+    assert_eq!(emails(&app).await, ["listed@gmail.com"]);
+}
+
+#[tokio::test]
+async fn a_dry_run_changes_nothing() {
+    let app = spawn_app().await;
+    seed(&app).await;
+
+    let summary = purge_stale_subscribers(&app.db_pool, &settings(RetentionMode::Delete, true))
+        .await
+        .unwrap();
+
+    assert_eq!(summary.subscribers, 1);
+    assert_eq!(emails(&app).await.len(), 3);
+}
+
+#[tokio::test]
+async fn stale_pending_subscribers_can_be_anonymized_instead() {
+    let app = spawn_app().await;
+    seed(&app).await;
+
+    purge_stale_subscribers(&app.db_pool, &settings(RetentionMode::Anonymize, false))
+        .await
+        .unwrap();
+
+    let anonymized = sqlx::query!(
+        "SELECT id, email, name, status FROM subscriptions WHERE status = 'anonymized'"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(
+        anonymized.email,
+        format!("{}@anonymized.invalid", anonymized.id)
+    );
+    assert_eq!(anonymized.name, "anonymized");
+    let tokens = sqlx::query!(
+        "SELECT subscriber_id FROM subscription_tokens WHERE subscriber_id = $1",
+        anonymized.id
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap();
+    assert!(tokens.is_empty());
+}
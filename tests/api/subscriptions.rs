@@ -1,3 +1,5 @@
+use zero2prod::email_backfill::backfill_punycode_domains;
+
 use crate::helpers::spawn_app;
 
 #[tokio::test]
@@ -17,6 +19,120 @@ async fn subscribe_returns_a_200_for_valid_from_data() {
     assert_eq!(saved.name, "le guin");
 }
 
+#[tokio::test]
+async fn subscribe_persists_the_requested_delivery_frequency() {
+    let test_app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com&delivery_frequency=weekly_digest";
+
+    let response = test_app.post_subscriptions(body.to_string()).await;
+    assert_eq!(200, response.status().as_u16());
+
+    let saved = sqlx::query!("SELECT delivery_frequency FROM subscriptions")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+
+    assert_eq!(saved.delivery_frequency, "weekly_digest");
+}
+
+#[tokio::test]
+async fn subscribe_stores_a_canonical_address_and_keeps_the_original() {
+    let test_app = spawn_app().await;
+    let body = "name=le%20guin&email=%20Ursula_Le_Guin%40B%C3%BCcher.Example%20";
+
+    let response = test_app.post_subscriptions(body.to_string()).await;
+    assert_eq!(200, response.status().as_u16());
+
+    let saved = sqlx::query!("SELECT email, display_email FROM subscriptions")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.email, "Ursula_Le_Guin@xn--bcher-kva.example");
+    assert_eq!(saved.display_email, "Ursula_Le_Guin@Bücher.Example");
+}
+
+#[tokio::test]
+async fn stored_internationalized_domains_are_converted_to_punycode() {
+    let test_app = spawn_app().await;
+    test_app
+        .post_subscriptions("name=le%20guin&email=ursula%40gmail.com".into())
+        .await;
+    // As the case-insensitive migration left addresses stored before it.
+    sqlx::query!("UPDATE subscriptions SET email = 'Ursula@bücher.example'")
+        .execute(&test_app.db_pool)
+        .await
+        .unwrap();
+
+    let summary = backfill_punycode_domains(&test_app.db_pool).await.unwrap();
+
+    assert_eq!(summary.converted, 1);
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.email, "Ursula@xn--bcher-kva.example");
+}
+
+#[tokio::test]
+async fn stored_addresses_colliding_in_punycode_are_merged() {
+    let test_app = spawn_app().await;
+    test_app
+        .create_confirmed_subscriber("ursula@gmail.com")
+        .await;
+    test_app
+        .post_subscriptions("name=le%20guin&email=ursula%40xn--bcher-kva.example".into())
+        .await;
+    // The confirmed row was stored in Unicode form before canonicalization.
+    sqlx::query!(
+        "UPDATE subscriptions SET email = 'ursula@bücher.example' WHERE email = 'ursula@gmail.com'"
+    )
+    .execute(&test_app.db_pool)
+    .await
+    .unwrap();
+
+    let summary = backfill_punycode_domains(&test_app.db_pool).await.unwrap();
+
+    assert_eq!(summary.converted, 0);
+    assert_eq!(summary.merged, 1);
+    let saved = sqlx::query!("SELECT id, email, status FROM subscriptions ORDER BY status")
+        .fetch_all(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved[0].status, "confirmed");
+    assert_eq!(saved[0].email, "ursula@xn--bcher-kva.example");
+    assert_eq!(saved[1].status, "merged");
+    assert_eq!(
+        saved[1].email,
+        format!("{}@anonymized.invalid", saved[1].id)
+    );
+    let merged =
+        sqlx::query!("SELECT subscriber_id FROM audit_log WHERE action = 'subscriber_merged'")
+            .fetch_one(&test_app.db_pool)
+            .await
+            .unwrap();
+    assert_eq!(merged.subscriber_id, Some(saved[1].id));
+}
+
+#[tokio::test]
+async fn addresses_differing_only_in_case_are_one_subscriber() {
+    let test_app = spawn_app().await;
+
+    test_app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    let response = test_app
+        .post_subscriptions("name=le%20guin&email=Ursula_Le_Guin%40GMAIL.com".into())
+        .await;
+    assert_eq!(200, response.status().as_u16());
+
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_all(&test_app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].email, "ursula_le_guin@gmail.com");
+}
+
 #[tokio::test]
 async fn subscribe_returns_a_400_when_fields_are_present_but_invalid() {
     let test_app = spawn_app().await;
@@ -25,6 +141,10 @@ async fn subscribe_returns_a_400_when_fields_are_present_but_invalid() {
         ("name=&email=ursula_le_guin%40gmail.com", "empty name"),
         ("name=Ursula&email=", "empty email"),
         ("name=Ursula&email=definitely-not-an-email", "ivaild email"),
+        (
+            "name=Ursula&email=ursula_le_guin%40gmail.com&delivery_frequency=daily",
+            "invalid delivery frequency",
+        ),
     ];
 
     for (body, description) in test_cases {
@@ -58,3 +178,184 @@ async fn subscribe_returns_a_422_when_data_is_missing() {
         );
     }
 }
+
+#[tokio::test]
+async fn subscribing_again_while_pending_rotates_the_token() {
+    let test_app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    let response = test_app.post_subscriptions(body.to_string()).await;
+    assert_eq!(200, response.status().as_u16());
+    let first = sqlx::query!("SELECT subscription_token_hash FROM subscription_tokens")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .expect("Failed to fetch subscription token.");
+
+    let response = test_app.post_subscriptions(body.to_string()).await;
+    assert_eq!(200, response.status().as_u16());
+    let tokens = sqlx::query!("SELECT subscription_token_hash FROM subscription_tokens")
+        .fetch_all(&test_app.db_pool)
+        .await
+        .expect("Failed to fetch subscription tokens.");
+
+    assert_eq!(tokens.len(), 1);
+    assert_ne!(
+        tokens[0].subscription_token_hash,
+        first.subscription_token_hash
+    );
+}
+
+#[tokio::test]
+async fn subscribing_again_once_confirmed_succeeds_silently() {
+    let test_app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    test_app.post_subscriptions(body.to_string()).await;
+    let token = test_app.confirmation_token("ursula_le_guin@gmail.com");
+    let response = test_app.get_confirmation(&token).await;
+    assert_eq!(200, response.status().as_u16());
+    let tokens_before = sqlx::query!("SELECT subscription_token_hash FROM subscription_tokens")
+        .fetch_all(&test_app.db_pool)
+        .await
+        .expect("Failed to fetch subscription tokens.");
+
+    let response = test_app.post_subscriptions(body.to_string()).await;
+    assert_eq!(200, response.status().as_u16());
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "confirmed");
+    let tokens_after = sqlx::query!("SELECT subscription_token_hash FROM subscription_tokens")
+        .fetch_all(&test_app.db_pool)
+        .await
+        .expect("Failed to fetch subscription tokens.");
+    assert_eq!(tokens_after.len(), tokens_before.len());
+}
+
+#[tokio::test]
+async fn subscribe_accepts_json_and_answers_with_json() {
+    let test_app = spawn_app().await;
+
+    let response = test_app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com",
+            "lists": ["weekly"],
+        }))
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(body["message"].is_string());
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.email, "ursula_le_guin@gmail.com");
+}
+
+#[tokio::test]
+async fn json_subscriptions_report_every_invalid_field() {
+    let test_app = spawn_app().await;
+
+    let response = test_app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "",
+            "email": "definitely-not-an-email",
+            "delivery_frequency": "daily",
+        }))
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "invalid_fields");
+    let fields: Vec<_> = body["fields"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| f["field"].as_str().unwrap())
+        .collect();
+    assert_eq!(fields, ["name", "email", "delivery_frequency"]);
+}
+
+#[tokio::test]
+async fn json_subscriptions_with_unknown_lists_are_rejected_with_a_400() {
+    let test_app = spawn_app().await;
+
+    let response = test_app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com",
+            "lists": ["weekly", "nope"],
+        }))
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["fields"][0]["field"], "lists");
+}
+
+#[tokio::test]
+async fn json_subscriptions_with_missing_fields_are_rejected_with_a_422() {
+    let test_app = spawn_app().await;
+
+    let response = test_app
+        .post_subscriptions_json(serde_json::json!({ "name": "le guin" }))
+        .await;
+
+    assert_eq!(422, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "malformed_request");
+}
+
+#[tokio::test]
+async fn form_clients_accepting_json_get_json_errors() {
+    let test_app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", test_app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("Accept", "application/json")
+        .body("name=Ursula&email=definitely-not-an-email")
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(400, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["fields"][0]["field"], "email");
+}
+
+#[tokio::test]
+async fn form_clients_get_an_empty_body() {
+    let test_app = spawn_app().await;
+
+    let response = test_app
+        .post_subscriptions("name=Ursula&email=definitely-not-an-email".to_string())
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+    assert!(response.text().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn concurrent_first_subscribes_for_one_address_both_succeed() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    let responses =
+        futures_util::future::join_all((0..3).map(|_| app.post_subscriptions(body.to_string())))
+            .await;
+
+    for response in responses {
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].status, "pending_confirmation");
+}
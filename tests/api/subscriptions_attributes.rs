@@ -0,0 +1,137 @@
+use serde_json::json;
+
+use crate::helpers::{spawn_app, spawn_app_with};
+
+#[tokio::test]
+async fn subscribe_stores_custom_attributes_from_a_form() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&company=Acme&employees=12&role=engineering"
+                .into(),
+        )
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT attributes FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(
+        saved.attributes,
+        json!({"company": "Acme", "employees": 12, "role": "engineering"})
+    );
+}
+
+#[tokio::test]
+async fn subscribe_stores_custom_attributes_from_json() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions_json(json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com",
+            "employees": 12,
+            "role": "marketing",
+        }))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT attributes FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(
+        saved.attributes,
+        json!({"employees": 12, "role": "marketing"})
+    );
+}
+
+#[tokio::test]
+async fn fields_outside_the_attribute_schema_are_not_stored() {
+    let app = spawn_app().await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&favourite=tea".into())
+        .await;
+
+    let saved = sqlx::query!("SELECT attributes FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.attributes, json!({}));
+}
+
+#[tokio::test]
+async fn invalid_attributes_are_reported_per_field() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions_json(json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com",
+            "company": "a".repeat(21),
+            "employees": "many",
+            "role": "sales",
+        }))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let fields: Vec<&str> = body["fields"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|field| field["field"].as_str().unwrap())
+        .collect();
+    assert_eq!(fields, ["company", "employees", "role"]);
+    let saved = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_optional(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(saved.is_none());
+}
+
+#[tokio::test]
+async fn attribute_errors_are_reported_next_to_core_field_errors() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions_json(json!({
+            "name": "le guin",
+            "email": "not-an-email",
+            "employees": 0,
+        }))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let fields: Vec<&str> = body["fields"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|field| field["field"].as_str().unwrap())
+        .collect();
+    assert_eq!(fields, ["email", "employees"]);
+}
+
+#[tokio::test]
+#[should_panic(expected = "Invalid custom attribute.")]
+async fn attributes_named_after_a_form_field_are_rejected_at_startup() {
+    spawn_app_with(|c| c.attributes[0].name = "form_token".to_string()).await;
+}
+
+#[tokio::test]
+#[should_panic(expected = "Invalid custom attribute.")]
+async fn attributes_named_after_the_honeypot_field_are_rejected_at_startup() {
+    spawn_app_with(|c| {
+        c.attributes[0].name = c.bot_protection.honeypot_field.clone().unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+#[should_panic(expected = "Invalid custom attribute.")]
+async fn choice_attributes_without_choices_are_rejected_at_startup() {
+    spawn_app_with(|c| c.attributes[2].choices.clear()).await;
+}
@@ -0,0 +1,162 @@
+use sqlx::types::chrono::Utc;
+use zero2prod::routes::{form_token, is_valid_proof_of_work};
+
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+
+async fn saved_subscriptions(app: &TestApp) -> usize {
+    sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.")
+        .len()
+}
+
+#[tokio::test]
+async fn the_challenge_describes_the_checks() {
+    let app = spawn_app_with(|c| c.bot_protection.proof_of_work_difficulty = 4).await;
+
+    let response = reqwest::get(format!("{}/subscriptions/challenge", app.address))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(body["form_token"].is_string());
+    assert_eq!(body["honeypot_field"], "website");
+    assert_eq!(body["proof_of_work_difficulty"], 4);
+}
+
+#[tokio::test]
+async fn a_filled_honeypot_gets_a_generic_answer_and_no_subscription() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&website=http%3A%2F%2Fspam".into(),
+        )
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(saved_subscriptions(&app).await, 0);
+}
+
+#[tokio::test]
+async fn an_empty_honeypot_is_accepted() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&website=".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(saved_subscriptions(&app).await, 1);
+}
+
+#[tokio::test]
+async fn forms_submitted_too_fast_or_without_a_token_are_dropped() {
+    let app = spawn_app_with(|c| c.bot_protection.min_submit_seconds = 3).await;
+    let fresh = form_token(&app.hmac_secret, Utc::now().timestamp());
+
+    for body in [
+        "name=le%20guin&email=ursula_le_guin%40gmail.com".to_string(),
+        format!(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&form_token={}",
+            fresh
+        ),
+    ] {
+        let response = app.post_subscriptions(body).await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    assert_eq!(saved_subscriptions(&app).await, 0);
+}
+
+#[tokio::test]
+async fn forms_submitted_after_the_minimum_time_are_accepted() {
+    let app = spawn_app_with(|c| c.bot_protection.min_submit_seconds = 3).await;
+    let token = form_token(&app.hmac_secret, Utc::now().timestamp() - 10);
+
+    let response = app
+        .post_subscriptions(format!(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&form_token={}",
+            token
+        ))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(saved_subscriptions(&app).await, 1);
+}
+
+#[tokio::test]
+async fn a_proof_of_work_is_required_when_configured() {
+    let app = spawn_app_with(|c| c.bot_protection.proof_of_work_difficulty = 8).await;
+    let token = form_token(&app.hmac_secret, Utc::now().timestamp());
+    let nonce = (0u64..)
+        .map(|n| n.to_string())
+        .find(|n| is_valid_proof_of_work(&token, n, 8))
+        .unwrap();
+    let wrong_nonce = (0u64..)
+        .map(|n| n.to_string())
+        .find(|n| !is_valid_proof_of_work(&token, n, 8))
+        .unwrap();
+
+    app.post_subscriptions_json(serde_json::json!({
+        "name": "le guin",
+        "email": "ursula_le_guin@gmail.com",
+        "form_token": token,
+        "proof_of_work": wrong_nonce,
+    }))
+    .await;
+    assert_eq!(saved_subscriptions(&app).await, 0);
+
+    let response = app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com",
+            "form_token": token,
+            "proof_of_work": nonce,
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(saved_subscriptions(&app).await, 1);
+}
+
+#[tokio::test]
+async fn a_form_token_cannot_be_used_twice() {
+    let app = spawn_app_with(|c| c.bot_protection.min_submit_seconds = 3).await;
+    let token = form_token(&app.hmac_secret, Utc::now().timestamp() - 10);
+
+    for email in ["ursula_le_guin%40gmail.com", "tolkien%40gmail.com"] {
+        let response = app
+            .post_subscriptions(format!(
+                "name=le%20guin&email={}&form_token={}",
+                email, token
+            ))
+            .await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+
+    assert_eq!(saved_subscriptions(&app).await, 1);
+}
+
+#[tokio::test]
+async fn a_form_rejected_as_invalid_can_be_sent_again_with_the_same_token() {
+    let app = spawn_app_with(|c| c.bot_protection.min_submit_seconds = 3).await;
+    let token = form_token(&app.hmac_secret, Utc::now().timestamp() - 10);
+
+    let response = app
+        .post_subscriptions(format!(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&lists=unknown&form_token={}",
+            token
+        ))
+        .await;
+    assert_eq!(response.status().as_u16(), 400);
+    let response = app
+        .post_subscriptions(format!(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&form_token={}",
+            token
+        ))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(saved_subscriptions(&app).await, 1);
+}
@@ -1,3 +1,5 @@
+use zero2prod::token_cleanup_worker::delete_expired_tokens;
+
 use crate::helpers::spawn_app;
 
 #[tokio::test]
@@ -8,3 +10,107 @@ async fn confirmations_without_token_are_rejected_with_a_400() {
         .unwrap();
     assert_eq!(response.status().as_u16(), 400);
 }
+
+#[tokio::test]
+async fn confirmations_with_an_unknown_token_are_rejected_with_a_401() {
+    let app = spawn_app().await;
+    let response = app.get_confirmation("not-a-real-token").await;
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn a_confirmation_token_can_only_be_used_once() {
+    let app = spawn_app().await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    let token = app.confirmation_token("ursula_le_guin@gmail.com");
+
+    let response = app.get_confirmation(&token).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let response = app.get_confirmation(&token).await;
+    assert_eq!(response.status().as_u16(), 409);
+}
+
+#[tokio::test]
+async fn expired_confirmation_tokens_are_rejected_with_a_410() {
+    let app = spawn_app().await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    let token = app.confirmation_token("ursula_le_guin@gmail.com");
+    sqlx::query!("UPDATE subscription_tokens SET expires_at = now() - interval '1 minute'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = app.get_confirmation(&token).await;
+    assert_eq!(response.status().as_u16(), 410);
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn tokens_are_not_stored_in_plaintext() {
+    let app = spawn_app().await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    let token = app.confirmation_token("ursula_le_guin@gmail.com");
+
+    let stored =
+        sqlx::query!("SELECT subscription_token, subscription_token_hash FROM subscription_tokens")
+            .fetch_all(&app.db_pool)
+            .await
+            .unwrap();
+
+    assert!(stored.iter().all(|r| r.subscription_token.is_none()));
+    assert!(stored
+        .iter()
+        .any(|r| r.subscription_token_hash == Some(app.hmac_secret.sign(&token))));
+}
+
+#[tokio::test]
+async fn plaintext_tokens_issued_before_hashing_still_confirm() {
+    let app = spawn_app().await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    sqlx::query!(
+        "INSERT INTO subscription_tokens (subscription_token, subscriber_id, expires_at)
+        SELECT 'legacy-plaintext-token', id, now() + interval '1 hour' FROM subscriptions"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    let response = app.get_confirmation("legacy-plaintext-token").await;
+    assert_eq!(response.status().as_u16(), 200);
+    let response = app.get_confirmation("legacy-plaintext-token").await;
+    assert_eq!(response.status().as_u16(), 409);
+}
+
+#[tokio::test]
+async fn expired_tokens_are_garbage_collected() {
+    let app = spawn_app().await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    app.post_subscriptions("name=ursula&email=ursula%40example.com".into())
+        .await;
+    sqlx::query!(
+        "UPDATE subscription_tokens SET expires_at = now() - interval '1 minute'
+        WHERE subscriber_id = (SELECT id FROM subscriptions WHERE email = 'ursula@example.com')"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    let deleted = delete_expired_tokens(&app.db_pool).await.unwrap();
+
+    assert_eq!(deleted, 1);
+    let remaining = sqlx::query!("SELECT subscription_token FROM subscription_tokens")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining.len(), 1);
+}
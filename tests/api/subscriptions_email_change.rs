@@ -0,0 +1,280 @@
+use reqwest::header;
+use zero2prod::routes::TokenPurpose;
+
+use crate::helpers::{spawn_app, TestApp};
+
+async fn post_email_change(app: &TestApp, cookie: Option<&str>, email: &str) -> reqwest::Response {
+    let mut body = format!("email={}", email);
+    let mut request = reqwest::Client::new()
+        .post(format!("{}/subscriptions/preferences/email", app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded");
+    if let Some(cookie) = cookie {
+        body.push_str(&format!(
+            "&csrf_token={}",
+            app.preferences_csrf_token(cookie).await
+        ));
+        request = request.header(header::COOKIE, cookie);
+    }
+    request
+        .body(body)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn post_link(app: &TestApp, path: &str, token: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}{}?token={}", app.address, path, token))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn change_and_confirm(app: &TestApp, cookie: &str, from: &str, to: &str) {
+    post_email_change(app, Some(cookie), &to.replace('@', "%40")).await;
+    let token = app.issue_token(from, TokenPurpose::EmailChange).await;
+    let response = post_link(app, "/subscriptions/email/confirm", &token).await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+async fn saved_email(app: &TestApp) -> String {
+    sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.")
+        .email
+}
+
+#[tokio::test]
+async fn changing_the_email_address_requires_a_session() {
+    let app = spawn_app().await;
+
+    let response = post_email_change(&app, None, "new%40example.com").await;
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn an_invalid_new_address_is_rejected_with_a_400() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("old@example.com").await;
+    let cookie = app.log_in_to_preferences("old@example.com").await;
+
+    let response = post_email_change(&app, Some(&cookie), "not-an-email").await;
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn an_address_already_on_the_list_gets_a_notice_instead_of_a_link() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("old@example.com").await;
+    app.create_confirmed_subscriber("taken@example.com").await;
+    let cookie = app.log_in_to_preferences("old@example.com").await;
+    let before = app.emails_to("taken@example.com").len();
+
+    let taken = post_email_change(&app, Some(&cookie), "taken%40example.com").await;
+    let free = post_email_change(&app, Some(&cookie), "free%40example.com").await;
+
+    assert_eq!(taken.status().as_u16(), 200);
+    assert_eq!(taken.text().await.unwrap(), free.text().await.unwrap());
+    let notices = app.emails_to("taken@example.com");
+    assert_eq!(notices.len(), before + 1);
+    assert!(notices[before].body.contains("already subscribed"));
+    let changes = sqlx::query!("SELECT new_email FROM email_changes")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(changes.iter().all(|c| c.new_email != "taken@example.com"));
+}
+
+#[tokio::test]
+async fn opening_a_link_shows_a_form_without_using_it() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("old@example.com").await;
+    let cookie = app.log_in_to_preferences("old@example.com").await;
+    post_email_change(&app, Some(&cookie), "new%40example.com").await;
+    let token = app
+        .issue_token("old@example.com", TokenPurpose::EmailChange)
+        .await;
+
+    for _ in 0..2 {
+        let response = reqwest::get(format!(
+            "{}/subscriptions/email/confirm?token={}",
+            app.address, token
+        ))
+        .await
+        .unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        let html = response.text().await.unwrap();
+        assert!(html.contains(&format!(
+            r#"action="/subscriptions/email/confirm?token={}" method="post""#,
+            token
+        )));
+    }
+    assert_eq!(saved_email(&app).await, "old@example.com");
+
+    let response = post_link(&app, "/subscriptions/email/confirm", &token).await;
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(saved_email(&app).await, "new@example.com");
+    let response = reqwest::get(format!(
+        "{}/subscriptions/email/confirm?token={}",
+        app.address, token
+    ))
+    .await
+    .unwrap();
+    assert_eq!(response.status().as_u16(), 409);
+}
+
+#[tokio::test]
+async fn opening_a_revert_link_does_not_revert() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("old@example.com").await;
+    let cookie = app.log_in_to_preferences("old@example.com").await;
+    change_and_confirm(&app, &cookie, "old@example.com", "new@example.com").await;
+    let revert_token = app.issue_revert_token("new@example.com").await;
+
+    let response = reqwest::get(format!(
+        "{}/subscriptions/email/revert?token={}",
+        app.address, revert_token
+    ))
+    .await
+    .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains(r#"action="/subscriptions/email/revert?token="#));
+    assert_eq!(saved_email(&app).await, "new@example.com");
+}
+
+#[tokio::test]
+async fn the_address_only_changes_after_confirmation() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("old@example.com").await;
+    let cookie = app.log_in_to_preferences("old@example.com").await;
+
+    let response = post_email_change(&app, Some(&cookie), "new%40example.com").await;
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(saved_email(&app).await, "old@example.com");
+
+    let token = app
+        .issue_token("old@example.com", TokenPurpose::EmailChange)
+        .await;
+    let response = post_link(&app, "/subscriptions/email/confirm", &token).await;
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(saved_email(&app).await, "new@example.com");
+
+    let revert_tokens = sqlx::query!(
+        "SELECT subscriber_id FROM subscription_tokens WHERE purpose = 'email_revert'"
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(revert_tokens.len(), 1);
+}
+
+#[tokio::test]
+async fn a_newer_request_invalidates_older_confirmation_links() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("old@example.com").await;
+    let cookie = app.log_in_to_preferences("old@example.com").await;
+    post_email_change(&app, Some(&cookie), "first%40example.com").await;
+    let first_token = app
+        .issue_token("old@example.com", TokenPurpose::EmailChange)
+        .await;
+
+    post_email_change(&app, Some(&cookie), "second%40example.com").await;
+
+    let response = post_link(&app, "/subscriptions/email/confirm", &first_token).await;
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(saved_email(&app).await, "old@example.com");
+}
+
+#[tokio::test]
+async fn confirming_fails_with_a_409_if_the_address_was_taken_meanwhile() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("old@example.com").await;
+    let cookie = app.log_in_to_preferences("old@example.com").await;
+    post_email_change(&app, Some(&cookie), "new%40example.com").await;
+    let token = app
+        .issue_token("old@example.com", TokenPurpose::EmailChange)
+        .await;
+    app.post_subscriptions("name=someone&email=new%40example.com".into())
+        .await;
+
+    let response = post_link(&app, "/subscriptions/email/confirm", &token).await;
+
+    assert_eq!(response.status().as_u16(), 409);
+    let saved = sqlx::query!("SELECT email FROM subscriptions WHERE email = 'old@example.com'")
+        .fetch_optional(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(saved.is_some());
+}
+
+#[tokio::test]
+async fn the_old_address_can_revert_the_change() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("old@example.com").await;
+    let cookie = app.log_in_to_preferences("old@example.com").await;
+    post_email_change(&app, Some(&cookie), "new%40example.com").await;
+    let token = app
+        .issue_token("old@example.com", TokenPurpose::EmailChange)
+        .await;
+    post_link(&app, "/subscriptions/email/confirm", &token).await;
+    let revert_token = app.issue_revert_token("new@example.com").await;
+
+    let response = post_link(&app, "/subscriptions/email/revert", &revert_token).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(saved_email(&app).await, "old@example.com");
+    let response = reqwest::Client::new()
+        .get(format!("{}/subscriptions/preferences", app.address))
+        .header(header::COOKIE, &cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn a_revert_link_restores_the_address_it_was_sent_to() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("a@example.com").await;
+    let cookie = app.log_in_to_preferences("a@example.com").await;
+    change_and_confirm(&app, &cookie, "a@example.com", "b@example.com").await;
+    change_and_confirm(&app, &cookie, "b@example.com", "c@example.com").await;
+    let revert_to_a = app.issue_revert_token("b@example.com").await;
+    let revert_to_b = app.issue_revert_token("c@example.com").await;
+
+    let response = post_link(&app, "/subscriptions/email/revert", &revert_to_a).await;
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(saved_email(&app).await, "a@example.com");
+
+    // The later change is undone as well, so its link is spent.
+    let response = post_link(&app, "/subscriptions/email/revert", &revert_to_b).await;
+    assert_eq!(response.status().as_u16(), 410);
+    assert_eq!(saved_email(&app).await, "a@example.com");
+}
+
+#[tokio::test]
+async fn reverting_cancels_pending_changes() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("old@example.com").await;
+    let cookie = app.log_in_to_preferences("old@example.com").await;
+    change_and_confirm(&app, &cookie, "old@example.com", "new@example.com").await;
+    post_email_change(&app, Some(&cookie), "attacker%40example.com").await;
+    let pending_token = app
+        .issue_token("new@example.com", TokenPurpose::EmailChange)
+        .await;
+    let revert_token = app.issue_revert_token("new@example.com").await;
+
+    post_link(&app, "/subscriptions/email/revert", &revert_token).await;
+    let response = post_link(&app, "/subscriptions/email/confirm", &pending_token).await;
+
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(saved_email(&app).await, "old@example.com");
+}
@@ -0,0 +1,190 @@
+use reqwest::Method;
+use zero2prod::{configuration::TokenBucketSettings, routes::TokenPurpose};
+
+use crate::helpers::{spawn_admin_app, spawn_app, spawn_app_with, TestApp, ADMIN_TOKEN};
+
+async fn admin_export(app: &TestApp, email: &str, token: Option<&str>) -> reqwest::Response {
+    let mut request = reqwest::Client::new().get(format!(
+        "{}/admin/subscribers/export?email={}",
+        app.address, email
+    ));
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    request.send().await.expect("Failed to execute request.")
+}
+
+async fn request_export(app: &TestApp, email: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/subscriptions/export", app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body(format!("email={}", email))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn download_export(app: &TestApp, token: &str) -> reqwest::Response {
+    reqwest::get(format!(
+        "{}/subscriptions/export?token={}",
+        app.address, token
+    ))
+    .await
+    .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn admin_exports_require_the_admin_token() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+
+    for token in [None, Some("wrong-token")] {
+        let response = admin_export(&app, "ursula_le_guin%40gmail.com", token).await;
+        assert_eq!(response.status().as_u16(), 401);
+    }
+}
+
+#[tokio::test]
+async fn admin_endpoints_stay_closed_without_a_configured_token() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+
+    let response = admin_export(&app, "ursula_le_guin%40gmail.com", Some(ADMIN_TOKEN)).await;
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn admin_exports_of_unknown_addresses_are_a_404() {
+    let app = spawn_admin_app().await;
+
+    let response = admin_export(&app, "nobody%40gmail.com", Some(ADMIN_TOKEN)).await;
+
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn admin_exports_contain_the_full_record() {
+    let app = spawn_admin_app().await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&lists=weekly".into())
+        .await;
+
+    let response = admin_export(&app, "ursula_le_guin%40gmail.com", Some(ADMIN_TOKEN)).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response
+        .headers()
+        .get("Content-Disposition")
+        .is_some_and(|v| v.to_str().unwrap().starts_with("attachment")));
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["subscriber"]["email"], "ursula_le_guin@gmail.com");
+    assert_eq!(body["subscriber"]["status"], "pending_confirmation");
+    assert_eq!(body["list_memberships"][0]["list"], "weekly");
+    assert_eq!(body["tokens"][0]["purpose"], "confirmation");
+    assert_eq!(body["tokens"][0]["list"], "weekly");
+    assert!(body["tokens"][0].get("subscription_token_hash").is_none());
+}
+
+#[tokio::test]
+async fn admin_exports_include_the_delivery_history() {
+    let app = spawn_admin_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    app.admin_request(Method::PUT, "/admin/segments/everyone")
+        .json(&serde_json::json!({}))
+        .send()
+        .await
+        .unwrap();
+    app.admin_request(Method::POST, "/admin/segments/everyone/issues")
+        .json(&serde_json::json!({"subject": "Issue #1", "html_content": "<p>Hello!</p>"}))
+        .send()
+        .await
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    let response = admin_export(&app, "ursula_le_guin%40gmail.com", Some(ADMIN_TOKEN)).await;
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["deliveries"][0]["subject"], "Issue #1");
+    assert_eq!(body["deliveries"][0]["status"], "sent");
+    assert!(body["deliveries"][0]["delivered_at"].is_string());
+}
+
+#[tokio::test]
+async fn requesting_an_export_for_an_unknown_address_gets_the_same_answer() {
+    let app = spawn_app().await;
+
+    let response = request_export(&app, "nobody%40gmail.com").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn export_links_are_not_resent_during_the_cooldown() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+
+    for _ in 0..2 {
+        let response = request_export(&app, "ursula_le_guin%40gmail.com").await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+
+    let tokens = sqlx::query!(
+        "SELECT subscription_token_hash FROM subscription_tokens WHERE purpose = 'data_export'"
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(tokens.len(), 1);
+}
+
+#[tokio::test]
+async fn too_many_export_requests_for_one_address_get_a_429() {
+    let app = spawn_app_with(|c| {
+        c.rate_limits.per_email = TokenBucketSettings {
+            capacity: 1,
+            refill_interval_seconds: 3600,
+        }
+    })
+    .await;
+
+    let response = request_export(&app, "nobody%40gmail.com").await;
+    assert_eq!(response.status().as_u16(), 200);
+    let response = request_export(&app, "nobody%40gmail.com").await;
+
+    assert_eq!(response.status().as_u16(), 429);
+    assert!(response.headers().contains_key("Retry-After"));
+}
+
+#[tokio::test]
+async fn subscribers_can_download_their_export_once_through_the_emailed_link() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    let token = app
+        .issue_token("ursula_le_guin@gmail.com", TokenPurpose::DataExport)
+        .await;
+
+    let response = download_export(&app, &token).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["subscriber"]["status"], "confirmed");
+
+    let response = download_export(&app, &token).await;
+    assert_eq!(response.status().as_u16(), 409);
+}
+
+#[tokio::test]
+async fn other_tokens_cannot_download_an_export() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    let token = app.issue_magic_link_token("ursula_le_guin@gmail.com").await;
+
+    let response = download_export(&app, &token).await;
+
+    assert_eq!(response.status().as_u16(), 401);
+}
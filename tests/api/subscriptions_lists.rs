@@ -0,0 +1,244 @@
+use reqwest::header;
+
+use crate::helpers::{spawn_app, TestApp};
+
+fn pending(slug: &str) -> (String, String) {
+    (slug.to_string(), "pending_confirmation".to_string())
+}
+
+fn confirmed(slug: &str) -> (String, String) {
+    (slug.to_string(), "confirmed".to_string())
+}
+
+#[tokio::test]
+async fn subscribing_to_an_unknown_list_is_rejected_with_a_400() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&lists=nope".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    assert!(app.list_memberships().await.is_empty());
+}
+
+#[tokio::test]
+async fn subscribing_to_several_lists_adds_a_pending_membership_for_each() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&lists=weekly&lists=releases".into(),
+        )
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        app.list_memberships().await,
+        vec![pending("releases"), pending("weekly")]
+    );
+    let tokens = sqlx::query!("SELECT list_id FROM subscription_tokens WHERE list_id IS NOT NULL")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(tokens.len(), 2);
+}
+
+#[tokio::test]
+async fn each_list_is_confirmed_separately() {
+    let app = spawn_app().await;
+    app.post_subscriptions(
+        "name=le%20guin&email=ursula_le_guin%40gmail.com&lists=weekly&lists=releases".into(),
+    )
+    .await;
+    let token = app.list_confirmation_token("ursula_le_guin@gmail.com", "Weekly digest");
+
+    let response = app.get_confirmation(&token).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        app.list_memberships().await,
+        vec![pending("releases"), confirmed("weekly")]
+    );
+}
+
+#[tokio::test]
+async fn confirming_a_list_does_not_confirm_the_newsletter() {
+    let app = spawn_app().await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&lists=weekly".into())
+        .await;
+    let token = app.list_confirmation_token("ursula_le_guin@gmail.com", "Weekly digest");
+
+    app.get_confirmation(&token).await;
+
+    let saved = sqlx::query!("SELECT status, email_verified_at FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "pending_confirmation");
+    assert!(saved.email_verified_at.is_some());
+    assert_eq!(app.list_memberships().await, vec![confirmed("weekly")]);
+}
+
+#[tokio::test]
+async fn joining_another_list_keeps_confirmed_memberships() {
+    let app = spawn_app().await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&lists=weekly".into())
+        .await;
+    let token = app.list_confirmation_token("ursula_le_guin@gmail.com", "Weekly digest");
+    app.get_confirmation(&token).await;
+
+    let response = app
+        .post_subscriptions(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&lists=weekly&lists=releases".into(),
+        )
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        app.list_memberships().await,
+        vec![pending("releases"), confirmed("weekly")]
+    );
+}
+
+#[tokio::test]
+async fn unsubscribed_addresses_are_not_added_to_lists() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&lists=weekly".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(app.list_memberships().await.is_empty());
+}
+
+async fn leave_list(app: &TestApp, cookie: &str, slug: &str) -> reqwest::Response {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+        .post(format!(
+            "{}/subscriptions/preferences/lists/leave",
+            app.address
+        ))
+        .header(header::COOKIE, cookie)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body(format!(
+            "list={}&csrf_token={}",
+            slug,
+            app.preferences_csrf_token(cookie).await
+        ))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn subscribers_can_leave_a_single_list() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    app.post_subscriptions(
+        "name=le%20guin&email=ursula_le_guin%40gmail.com&lists=weekly&lists=releases".into(),
+    )
+    .await;
+    let token = app.list_confirmation_token("ursula_le_guin@gmail.com", "Weekly digest");
+    app.get_confirmation(&token).await;
+    let cookie = app.log_in_to_preferences("ursula_le_guin@gmail.com").await;
+
+    let response = leave_list(&app, &cookie, "weekly").await;
+
+    assert_eq!(response.status().as_u16(), 303);
+    assert_eq!(
+        app.list_memberships().await,
+        vec![
+            pending("releases"),
+            ("weekly".into(), "unsubscribed".into())
+        ]
+    );
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+    let page = reqwest::Client::new()
+        .get(format!("{}/subscriptions/preferences", app.address))
+        .header(header::COOKIE, &cookie)
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(page.contains(r#"name="list" value="releases""#));
+    assert!(!page.contains(r#"name="list" value="weekly""#));
+}
+
+#[tokio::test]
+async fn leaving_a_list_invalidates_its_pending_confirmation_link() {
+    let app = spawn_app().await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&lists=weekly".into())
+        .await;
+    let token = app.list_confirmation_token("ursula_le_guin@gmail.com", "Weekly digest");
+    let cookie = app.log_in_to_preferences("ursula_le_guin@gmail.com").await;
+
+    leave_list(&app, &cookie, "weekly").await;
+    let response = app.get_confirmation(&token).await;
+
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(
+        app.list_memberships().await,
+        vec![("weekly".into(), "unsubscribed".into())]
+    );
+}
+
+#[tokio::test]
+async fn rejoining_a_list_needs_a_new_confirmation() {
+    let app = spawn_app().await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&lists=weekly".into())
+        .await;
+    let token = app.list_confirmation_token("ursula_le_guin@gmail.com", "Weekly digest");
+    app.get_confirmation(&token).await;
+    let cookie = app.log_in_to_preferences("ursula_le_guin@gmail.com").await;
+    leave_list(&app, &cookie, "weekly").await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&lists=weekly".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(app.list_memberships().await, vec![pending("weekly")]);
+    let token = app.list_confirmation_token("ursula_le_guin@gmail.com", "Weekly digest");
+    app.get_confirmation(&token).await;
+    assert_eq!(app.list_memberships().await, vec![confirmed("weekly")]);
+}
+
+#[tokio::test]
+async fn leaving_requires_a_valid_csrf_token() {
+    let app = spawn_app().await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&lists=weekly".into())
+        .await;
+    let cookie = app.log_in_to_preferences("ursula_le_guin@gmail.com").await;
+
+    let response = reqwest::Client::new()
+        .post(format!(
+            "{}/subscriptions/preferences/lists/leave",
+            app.address
+        ))
+        .header(header::COOKIE, &cookie)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("list=weekly&csrf_token=forged")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 403);
+    assert_eq!(app.list_memberships().await, vec![pending("weekly")]);
+}
@@ -0,0 +1,300 @@
+use reqwest::{header, redirect::Policy};
+use zero2prod::routes::unsubscribe_token;
+
+use crate::helpers::{spawn_app, TestApp};
+
+async fn get_login(app: &TestApp, token: &str) -> reqwest::Response {
+    reqwest::Client::builder()
+        .redirect(Policy::none())
+        .build()
+        .unwrap()
+        .get(format!(
+            "{}/subscriptions/preferences/login?token={}",
+            app.address, token
+        ))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+/// Posts the form as the preference center renders it, with the session's
+/// CSRF token.
+async fn post_preferences(app: &TestApp, cookie: Option<&str>, body: &str) -> reqwest::Response {
+    let body = match cookie {
+        Some(cookie) => format!(
+            "{}&csrf_token={}",
+            body,
+            app.preferences_csrf_token(cookie).await
+        ),
+        None => body.to_string(),
+    };
+    post_preferences_as_is(app, cookie, &body).await
+}
+
+async fn post_preferences_as_is(
+    app: &TestApp,
+    cookie: Option<&str>,
+    body: &str,
+) -> reqwest::Response {
+    let mut request = reqwest::Client::builder()
+        .redirect(Policy::none())
+        .build()
+        .unwrap()
+        .post(format!("{}/subscriptions/preferences", app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body(body.to_string());
+    if let Some(cookie) = cookie {
+        request = request.header(header::COOKIE, cookie);
+    }
+    request.send().await.expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn magic_links_are_only_issued_to_verified_subscribers() {
+    let app = spawn_app().await;
+    app.post_subscriptions("name=le%20guin&email=pending%40example.com".into())
+        .await;
+    app.create_confirmed_subscriber("confirmed@example.com")
+        .await;
+    app.post_subscriptions("name=le%20guin&email=listed%40example.com&lists=weekly".into())
+        .await;
+    let token = app.list_confirmation_token("listed@example.com", "Weekly digest");
+    app.get_confirmation(&token).await;
+
+    for email in [
+        "unknown%40example.com",
+        "pending%40example.com",
+        "confirmed%40example.com",
+        "listed%40example.com",
+    ] {
+        let response = reqwest::Client::new()
+            .post(format!(
+                "{}/subscriptions/preferences/magic-link",
+                app.address
+            ))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(format!("email={}", email))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+    }
+
+    let tokens = sqlx::query!(
+        "SELECT s.email FROM subscription_tokens t
+        JOIN subscriptions s ON s.id = t.subscriber_id
+        WHERE t.purpose = 'magic_link'
+        ORDER BY s.email"
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap();
+    let emails: Vec<_> = tokens.into_iter().map(|t| t.email).collect();
+    assert_eq!(emails, ["confirmed@example.com", "listed@example.com"]);
+}
+
+#[tokio::test]
+async fn a_magic_link_can_only_be_used_once() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula@example.com").await;
+    let token = app.issue_magic_link_token("ursula@example.com").await;
+
+    let response = get_login(&app, &token).await;
+    assert_eq!(response.status().as_u16(), 303);
+    let response = get_login(&app, &token).await;
+    assert_eq!(response.status().as_u16(), 409);
+}
+
+#[tokio::test]
+async fn expired_magic_links_are_rejected_with_a_410() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula@example.com").await;
+    let token = app.issue_magic_link_token("ursula@example.com").await;
+    sqlx::query!("UPDATE subscription_tokens SET expires_at = now() - interval '1 minute'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = get_login(&app, &token).await;
+    assert_eq!(response.status().as_u16(), 410);
+}
+
+#[tokio::test]
+async fn confirmation_tokens_do_not_work_as_magic_links() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula@example.com").await;
+    let token = app.confirmation_token("ursula@example.com");
+
+    let response = get_login(&app, &token).await;
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn the_preference_center_requires_a_session() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(format!("{}/subscriptions/preferences", app.address))
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+
+    let body = "name=ursula&delivery_frequency=immediate&language=en";
+    let response = post_preferences(&app, None, body).await;
+    assert_eq!(response.status().as_u16(), 401);
+    let response = post_preferences_as_is(&app, Some("preferences_session=forged"), body).await;
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn subscribers_can_update_their_preferences() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula@example.com").await;
+    app.create_confirmed_subscriber("someone.else@example.com")
+        .await;
+    let cookie = app.log_in_to_preferences("ursula@example.com").await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/subscriptions/preferences", app.address))
+        .header(header::COOKIE, &cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("ursula@example.com"));
+
+    let response = post_preferences(
+        &app,
+        Some(&cookie),
+        "name=Ursula%20K.&topics=rust&topics=databases&delivery_frequency=weekly_digest&language=zh",
+    )
+    .await;
+    assert_eq!(response.status().as_u16(), 303);
+
+    let saved = sqlx::query!(
+        "SELECT name, topics, delivery_frequency, language FROM subscriptions WHERE email = $1",
+        "ursula@example.com"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(saved.name, "Ursula K.");
+    assert_eq!(saved.topics, vec!["rust", "databases"]);
+    assert_eq!(saved.delivery_frequency, "weekly_digest");
+    assert_eq!(saved.language, "zh");
+
+    let untouched = sqlx::query!(
+        "SELECT name, language FROM subscriptions WHERE email = $1",
+        "someone.else@example.com"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(untouched.name, "le guin");
+    assert_eq!(untouched.language, "en");
+}
+
+#[tokio::test]
+async fn invalid_preferences_are_rejected_with_a_400() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula@example.com").await;
+    let cookie = app.log_in_to_preferences("ursula@example.com").await;
+
+    let test_cases = [
+        (
+            "name=&delivery_frequency=immediate&language=en",
+            "empty name",
+        ),
+        (
+            "name=ursula&topics=knitting&delivery_frequency=immediate&language=en",
+            "unknown topic",
+        ),
+        (
+            "name=ursula&delivery_frequency=daily&language=en",
+            "unknown frequency",
+        ),
+        (
+            "name=ursula&delivery_frequency=immediate&language=klingon",
+            "unsupported language",
+        ),
+    ];
+    for (body, description) in test_cases {
+        let response = post_preferences(&app, Some(&cookie), body).await;
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "The API did not return a 400 Bad Request when the payload had an {}",
+            description
+        );
+    }
+}
+
+#[tokio::test]
+async fn preference_updates_without_the_csrf_token_are_rejected_with_a_403() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula@example.com").await;
+    let cookie = app.log_in_to_preferences("ursula@example.com").await;
+    app.create_confirmed_subscriber("tolkien@example.com").await;
+    let other_cookie = app.log_in_to_preferences("tolkien@example.com").await;
+    let body = "name=Mallory&delivery_frequency=immediate&language=en";
+
+    let missing = post_preferences_as_is(&app, Some(&cookie), body).await;
+    let forged =
+        post_preferences_as_is(&app, Some(&cookie), &format!("{}&csrf_token=00ff", body)).await;
+    let other_session = post_preferences_as_is(
+        &app,
+        Some(&cookie),
+        &format!(
+            "{}&csrf_token={}",
+            body,
+            app.preferences_csrf_token(&other_cookie).await
+        ),
+    )
+    .await;
+
+    assert_eq!(missing.status().as_u16(), 403);
+    assert_eq!(forged.status().as_u16(), 403);
+    assert_eq!(other_session.status().as_u16(), 403);
+    let saved = sqlx::query!(
+        "SELECT name FROM subscriptions WHERE email = $1",
+        "ursula@example.com"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(saved.name, "le guin");
+}
+
+#[tokio::test]
+async fn unsubscribing_ends_preference_sessions() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula@example.com").await;
+    let cookie = app.log_in_to_preferences("ursula@example.com").await;
+    let subscriber_id = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .id;
+    reqwest::Client::new()
+        .post(format!(
+            "{}/subscriptions/unsubscribe?token={}",
+            app.address,
+            unsubscribe_token(&app.hmac_secret, subscriber_id)
+        ))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .send()
+        .await
+        .unwrap();
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/subscriptions/preferences", app.address))
+        .header(header::COOKIE, &cookie)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+}
@@ -0,0 +1,120 @@
+use zero2prod::configuration::{RateLimitStoreKind, TokenBucketSettings};
+
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+
+fn one_per_hour() -> TokenBucketSettings {
+    TokenBucketSettings {
+        capacity: 1,
+        refill_interval_seconds: 3600,
+    }
+}
+
+async fn subscribe(app: &TestApp, email: &str, forwarded_for: Option<&str>) -> reqwest::Response {
+    let mut request = reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body(format!("name=le%20guin&email={}", email));
+    if let Some(forwarded_for) = forwarded_for {
+        request = request.header("X-Forwarded-For", forwarded_for);
+    }
+    request.send().await.expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn generous_limits_do_not_get_in_the_way() {
+    let app = spawn_app().await;
+
+    for _ in 0..3 {
+        let response = subscribe(&app, "ursula%40gmail.com", None).await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+}
+
+#[tokio::test]
+async fn too_many_subscriptions_for_one_address_get_a_429() {
+    let app = spawn_app_with(|c| c.rate_limits.per_email = one_per_hour()).await;
+
+    let response = subscribe(&app, "ursula%40gmail.com", None).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let response = subscribe(&app, "URSULA%40gmail.com", None).await;
+
+    assert_eq!(response.status().as_u16(), 429);
+    let retry_after: u64 = response.headers()["Retry-After"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 3500 && retry_after <= 3600);
+}
+
+#[tokio::test]
+async fn too_many_subscriptions_from_one_client_get_a_429() {
+    let app = spawn_app_with(|c| c.rate_limits.per_ip = one_per_hour()).await;
+
+    let response = subscribe(&app, "ursula%40gmail.com", None).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let response = subscribe(&app, "le_guin%40gmail.com", None).await;
+
+    assert_eq!(response.status().as_u16(), 429);
+    assert!(response.headers().contains_key("Retry-After"));
+}
+
+#[tokio::test]
+async fn json_clients_get_a_json_429() {
+    let app = spawn_app_with(|c| c.rate_limits.per_ip = one_per_hour()).await;
+    let body = serde_json::json!({ "name": "le guin", "email": "ursula@gmail.com" });
+
+    app.post_subscriptions_json(body.clone()).await;
+    let response = app.post_subscriptions_json(body).await;
+
+    assert_eq!(response.status().as_u16(), 429);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "rate_limited");
+}
+
+#[tokio::test]
+async fn forwarded_addresses_are_ignored_unless_the_proxy_is_trusted() {
+    let app = spawn_app_with(|c| c.rate_limits.per_ip = one_per_hour()).await;
+
+    subscribe(&app, "ursula%40gmail.com", Some("203.0.113.1")).await;
+    let response = subscribe(&app, "le_guin%40gmail.com", Some("203.0.113.2")).await;
+
+    assert_eq!(response.status().as_u16(), 429);
+}
+
+#[tokio::test]
+async fn trusted_proxies_forward_the_client_address() {
+    let app = spawn_app_with(|c| {
+        c.rate_limits.per_ip = one_per_hour();
+        c.rate_limits.trusted_proxies = vec!["127.0.0.1".parse().unwrap()];
+    })
+    .await;
+
+    let response = subscribe(&app, "ursula%40gmail.com", Some("203.0.113.1")).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let response = subscribe(&app, "le_guin%40gmail.com", Some("203.0.113.2, 127.0.0.1")).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let response = subscribe(&app, "ursula_k%40gmail.com", Some("203.0.113.1")).await;
+    assert_eq!(response.status().as_u16(), 429);
+}
+
+#[tokio::test]
+async fn limits_can_be_kept_in_postgres() {
+    let app = spawn_app_with(|c| {
+        c.rate_limits.store = RateLimitStoreKind::Postgres;
+        c.rate_limits.per_email = one_per_hour();
+    })
+    .await;
+
+    let response = subscribe(&app, "ursula%40gmail.com", None).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let response = subscribe(&app, "ursula%40gmail.com", None).await;
+    assert_eq!(response.status().as_u16(), 429);
+
+    let buckets = sqlx::query!("SELECT key FROM rate_limit_buckets")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(buckets.len(), 2);
+    assert!(buckets.iter().all(|b| !b.key.contains("ursula")));
+}
@@ -0,0 +1,169 @@
+use crate::helpers::{spawn_app, TestApp};
+
+async fn post_resend(app: &TestApp, body: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/subscriptions/resend-confirmation", app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body(body.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn token_hashes(app: &TestApp) -> Vec<Option<String>> {
+    sqlx::query!(
+        "SELECT subscription_token_hash FROM subscription_tokens WHERE purpose = 'confirmation'"
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .expect("Failed to fetch subscription tokens.")
+    .into_iter()
+    .map(|r| r.subscription_token_hash)
+    .collect()
+}
+
+#[tokio::test]
+async fn an_invalid_address_is_rejected_with_a_400() {
+    let app = spawn_app().await;
+
+    let response = post_resend(&app, "email=not-an-email").await;
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn unknown_addresses_get_the_same_answer() {
+    let app = spawn_app().await;
+
+    let response = post_resend(&app, "email=nobody%40gmail.com").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(token_hashes(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn a_pending_address_gets_a_fresh_token_after_the_cooldown() {
+    let app = spawn_app().await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    let before = token_hashes(&app).await;
+    sqlx::query!("UPDATE subscription_tokens SET created_at = now() - interval '1 hour'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = post_resend(&app, "email=ursula_le_guin%40gmail.com").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let after = token_hashes(&app).await;
+    assert_eq!(after.len(), 1);
+    assert_ne!(after, before);
+}
+
+#[tokio::test]
+async fn a_failed_send_gets_the_same_answer_as_an_unknown_address() {
+    let app = spawn_app().await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    let before = token_hashes(&app).await;
+    sqlx::query!("UPDATE subscription_tokens SET created_at = now() - interval '1 hour'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.email_server.set_failing(true);
+
+    let response = post_resend(&app, "email=ursula_le_guin%40gmail.com").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let after = token_hashes(&app).await;
+    assert_eq!(after.len(), 1);
+    assert_ne!(after, before);
+}
+
+#[tokio::test]
+async fn resending_within_the_cooldown_does_nothing() {
+    let app = spawn_app().await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    let before = token_hashes(&app).await;
+
+    let response = post_resend(&app, "email=ursula_le_guin%40gmail.com").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(token_hashes(&app).await, before);
+}
+
+#[tokio::test]
+async fn confirmed_addresses_get_no_new_token() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    sqlx::query!("DELETE FROM subscription_tokens")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = post_resend(&app, "email=ursula_le_guin%40gmail.com").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(token_hashes(&app).await.is_empty());
+}
+
+async fn list_token_slugs(app: &TestApp) -> Vec<Option<String>> {
+    sqlx::query!(
+        r#"
+        SELECT l.slug AS "slug?" FROM subscription_tokens t
+        LEFT JOIN lists l ON l.id = t.list_id
+        WHERE t.purpose = 'confirmation' ORDER BY l.slug
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .expect("Failed to fetch subscription tokens.")
+    .into_iter()
+    .map(|r| r.slug)
+    .collect()
+}
+
+#[tokio::test]
+async fn pending_list_memberships_get_fresh_list_tokens() {
+    let app = spawn_app().await;
+    app.post_subscriptions(
+        "name=le%20guin&email=ursula_le_guin%40gmail.com&lists=weekly&lists=releases".into(),
+    )
+    .await;
+    let before = token_hashes(&app).await;
+    sqlx::query!("UPDATE subscription_tokens SET created_at = now() - interval '1 hour'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    post_resend(&app, "email=ursula_le_guin%40gmail.com").await;
+
+    // No newsletter-wide token: the subscriber only asked for the lists.
+    assert_eq!(
+        list_token_slugs(&app).await,
+        [Some("releases".to_string()), Some("weekly".to_string())]
+    );
+    assert!(token_hashes(&app)
+        .await
+        .iter()
+        .all(|hash| !before.contains(hash)));
+}
+
+#[tokio::test]
+async fn confirmed_subscribers_get_tokens_for_their_pending_lists() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&lists=weekly".into())
+        .await;
+    sqlx::query!("DELETE FROM subscription_tokens")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    post_resend(&app, "email=ursula_le_guin%40gmail.com").await;
+
+    assert_eq!(list_token_slugs(&app).await, [Some("weekly".to_string())]);
+}
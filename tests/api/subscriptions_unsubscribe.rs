@@ -0,0 +1,230 @@
+use uuid::Uuid;
+use zero2prod::routes::unsubscribe_token;
+
+use crate::helpers::{spawn_app, TestApp};
+
+async fn confirmed_subscriber(app: &TestApp) -> Uuid {
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    let token = app.confirmation_token("ursula_le_guin@gmail.com");
+    app.get_confirmation(&token).await;
+    sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.")
+        .id
+}
+
+async fn post_unsubscribe(app: &TestApp, token: &str, body: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!(
+            "{}/subscriptions/unsubscribe?token={}",
+            app.address, token
+        ))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body(body.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn unsubscribe_links_with_a_forged_signature_are_rejected_with_a_401() {
+    let app = spawn_app().await;
+    let subscriber_id = confirmed_subscriber(&app).await;
+    let token = format!("{}.{}", subscriber_id, "00".repeat(32));
+
+    let response = reqwest::get(format!(
+        "{}/subscriptions/unsubscribe?token={}",
+        app.address, token
+    ))
+    .await
+    .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+
+    let response = post_unsubscribe(&app, &token, "").await;
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn opening_the_unsubscribe_link_does_not_unsubscribe() {
+    let app = spawn_app().await;
+    let subscriber_id = confirmed_subscriber(&app).await;
+    let token = unsubscribe_token(&app.hmac_secret, subscriber_id);
+
+    let response = reqwest::get(format!(
+        "{}/subscriptions/unsubscribe?token={}",
+        app.address, token
+    ))
+    .await
+    .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn unsubscribing_records_when_and_why() {
+    let app = spawn_app().await;
+    let subscriber_id = confirmed_subscriber(&app).await;
+    let token = unsubscribe_token(&app.hmac_secret, subscriber_id);
+
+    let response = post_unsubscribe(&app, &token, "reason=too%20many%20emails").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.text().await.unwrap().contains("/undo?token="));
+    let saved =
+        sqlx::query!("SELECT status, unsubscribed_at, unsubscribe_reason FROM subscriptions")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap();
+    assert_eq!(saved.status, "unsubscribed");
+    assert!(saved.unsubscribed_at.is_some());
+    assert_eq!(saved.unsubscribe_reason.as_deref(), Some("too many emails"));
+}
+
+#[tokio::test]
+async fn one_click_unsubscribe_works_without_a_reason() {
+    let app = spawn_app().await;
+    let subscriber_id = confirmed_subscriber(&app).await;
+    let token = unsubscribe_token(&app.hmac_secret, subscriber_id);
+
+    let response = post_unsubscribe(&app, &token, "List-Unsubscribe=One-Click").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status, unsubscribe_reason FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "unsubscribed");
+    assert!(saved.unsubscribe_reason.is_none());
+}
+
+#[tokio::test]
+async fn undo_restores_the_subscription() {
+    let app = spawn_app().await;
+    let subscriber_id = confirmed_subscriber(&app).await;
+    let token = unsubscribe_token(&app.hmac_secret, subscriber_id);
+    post_unsubscribe(&app, &token, "").await;
+
+    let response = post_undo(&app, &token).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status, unsubscribed_at FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+    assert!(saved.unsubscribed_at.is_none());
+}
+
+async fn post_undo(app: &TestApp, token: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!(
+            "{}/subscriptions/unsubscribe/undo?token={}",
+            app.address, token
+        ))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn undo_does_not_confirm_a_pending_subscriber() {
+    let app = spawn_app().await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    let subscriber_id = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .id;
+    let token = unsubscribe_token(&app.hmac_secret, subscriber_id);
+    post_unsubscribe(&app, &token, "").await;
+
+    let response = post_undo(&app, &token).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn unsubscribes_without_a_recorded_status_cannot_be_undone() {
+    let app = spawn_app().await;
+    let subscriber_id = confirmed_subscriber(&app).await;
+    let token = unsubscribe_token(&app.hmac_secret, subscriber_id);
+    post_unsubscribe(&app, &token, "").await;
+    sqlx::query!("UPDATE subscriptions SET status_before_unsubscribe = NULL")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = post_undo(&app, &token).await;
+
+    assert_eq!(response.status().as_u16(), 410);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "unsubscribed");
+}
+
+#[tokio::test]
+async fn unsubscribed_addresses_are_not_mailed_when_subscribing_again() {
+    let app = spawn_app().await;
+    let subscriber_id = confirmed_subscriber(&app).await;
+    let token = unsubscribe_token(&app.hmac_secret, subscriber_id);
+    post_unsubscribe(&app, &token, "").await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "unsubscribed");
+    let tokens = sqlx::query!("SELECT subscriber_id FROM subscription_tokens")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(tokens.is_empty());
+}
+
+#[tokio::test]
+async fn pending_confirmation_links_stop_working_after_unsubscribing() {
+    let app = spawn_app().await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    let confirmation_token = app.confirmation_token("ursula_le_guin@gmail.com");
+    let subscriber_id = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .id;
+    post_unsubscribe(
+        &app,
+        &unsubscribe_token(&app.hmac_secret, subscriber_id),
+        "",
+    )
+    .await;
+
+    let response = app.get_confirmation(&confirmation_token).await;
+
+    assert_eq!(response.status().as_u16(), 401);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "unsubscribed");
+}